@@ -1,7 +1,12 @@
+use std::{collections::HashMap, ops::Range};
+
 use wgpu::util::DeviceExt;
 use winit::{event::WindowEvent, window::Window};
 
 use crate::{
+    atlas::Atlas,
+    bvh,
+    environment::Environment,
     gui,
     pipeline::{compute::ComputePipeline, render::RenderPipeline},
     scene,
@@ -38,6 +43,13 @@ pub struct Parameters {
     pub ambient_lighting_color: glam::Vec3,
     pub ambient_lighting_strength: f32,
     pub focal_blur_strength: f32,
+    pub reconstruction_filter: u32,
+    pub filter_radius: f32,
+    pub environment_intensity: f32,
+    pub environment_rotation: f32,
+    pub target_samples: Option<u32>,
+    pub exposure: f32,
+    pub tone_mapping: u32,
 }
 
 pub struct Timing {
@@ -54,10 +66,20 @@ pub struct State {
     config: wgpu::SurfaceConfiguration,
     size: winit::dpi::PhysicalSize<u32>,
     globals: storage::Globals,
+    renderer: Renderer,
+    gui_layer: GuiLayer,
+
+    // The globals the accumulated image was traced with
+    accumulated_globals: storage::Globals,
+}
+
+/// The GPU resources needed to trace a scene and blit the result to a target
+/// texture, independent of where that texture ends up being presented.
+pub struct Renderer {
     pipelines: Pipelines,
     compute_data: ComputeData,
     render_data: RenderData,
-    gui_layer: GuiLayer,
+    environment: EnvironmentData,
 }
 
 pub struct Pipelines {
@@ -70,14 +92,26 @@ pub struct RenderData {
     index_buffer: wgpu::Buffer,
 }
 
+/// The environment map and its sampling distributions. Scenes without one get
+/// single texel placeholders so the bind group layout stays the same.
+pub struct EnvironmentData {
+    texture: wgpu::TextureView,
+    conditional_cdf: wgpu::TextureView,
+    marginal_cdf: wgpu::TextureView,
+}
+
 pub struct ComputeData {
     globals_buffer: wgpu::Buffer,
     materials_buffer: wgpu::Buffer,
     spheres_buffer: wgpu::Buffer,
     vertex_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-    mesh_buffer: wgpu::Buffer,
+    triangle_buffer: wgpu::Buffer,
+    bvh_buffer: wgpu::Buffer,
+    instance_buffer: wgpu::Buffer,
+    light_buffer: wgpu::Buffer,
+    accumulation_buffer: wgpu::Buffer,
     render_texture: wgpu::TextureView,
+    texture_atlas: wgpu::TextureView,
 }
 
 pub struct GuiLayer {
@@ -89,57 +123,14 @@ pub struct GuiLayer {
 }
 
 impl State {
-    pub async fn new(window: &Window, scene: &scene::Scene, parameters: &Parameters) -> Self {
+    pub async fn new(
+        window: &Window,
+        scene: &scene::Scene,
+        environment: Option<&Environment>,
+        parameters: &Parameters,
+    ) -> Result<Self, String> {
         let size = window.inner_size();
-
-        let globals = {
-            storage::Globals {
-                camera: match scene.camera.lens {
-                    scene::Lens::Perspective {
-                        fov,
-                        focal_distance,
-                    } => {
-                        let fov = fov.to_radians();
-                        let aspect_ratio = (size.width as f32) / (size.height as f32);
-                        let plane_height = 2.0 * (fov / 2.0).tan() * focal_distance;
-                        let plane_width = plane_height * aspect_ratio;
-
-                        let rotation = scene.camera.transform.rotation;
-                        let position = scene.camera.transform.position;
-
-                        storage::Camera {
-                            focal_view: glam::f32::vec3(plane_width, plane_height, focal_distance),
-                            world_space_position: position,
-                            local_to_world_matrix: glam::f32::Mat4::from_euler(
-                                glam::EulerRot::XYZ,
-                                rotation.x.to_radians(),
-                                rotation.y.to_radians(),
-                                rotation.z.to_radians(),
-                            ),
-                            near_clip: scene.camera.clipping.near,
-                            far_clip: scene.camera.clipping.far,
-                        }
-                    }
-                },
-                frame: parameters.frame,
-                random_seed: parameters.random_seed,
-                skybox_color: parameters.skybox_color,
-                ambient_lighting_color: parameters.ambient_lighting_color,
-                ambient_lighting_strength: parameters.ambient_lighting_strength,
-                max_ray_bounces: parameters.max_ray_bounces,
-                max_samples_per_pixel: parameters.max_samples_per_pixel,
-                focal_blur_strength: parameters.focal_blur_strength,
-            }
-        };
-
-        let scene_buffers = {
-            let mut builder = SceneBufferBuilder::new();
-            for obj in &scene.objects {
-                builder.push(obj);
-            }
-
-            builder
-        };
+        let globals = create_globals(scene, environment, parameters, size.width, size.height);
 
         // Backends::all => Vulkan + Metal + DX12 + Browser WebGPU
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -151,26 +142,8 @@ impl State {
         // State owns the window so this should be safe.
         let surface = unsafe { instance.create_surface(&window) }.unwrap();
 
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
-            })
-            .await
-            .unwrap();
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
-                    limits: wgpu::Limits::default(),
-                    label: None,
-                },
-                None,
-            )
-            .await
-            .unwrap();
+        let adapter = request_adapter(&instance, Some(&surface)).await.unwrap();
+        let (device, queue) = request_device(&adapter).await.unwrap();
 
         let surface_caps = surface.get_capabilities(&adapter);
 
@@ -194,92 +167,16 @@ impl State {
 
         surface.configure(&device, &config);
 
-        let pipelines = Pipelines {
-            compute: ComputePipeline::new(&device),
-            render: RenderPipeline::new(&device, surface_format),
-        };
-
-        let render_data = RenderData {
-            vertex_buffer: {
-                let bytes = bytemuck::cast_slice(QUAD_VERTICIES);
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Vertex Buffer"),
-                    contents: &bytes,
-                    usage: wgpu::BufferUsages::VERTEX,
-                })
-            },
-            index_buffer: {
-                let bytes = bytemuck::cast_slice(QUAD_INDICES);
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Index Buffer"),
-                    contents: &bytes,
-                    usage: wgpu::BufferUsages::INDEX,
-                })
-            },
-        };
-
-        let compute_data = ComputeData {
-            globals_buffer: {
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Globals buffer"),
-                    contents: &storage::Uniform(&globals).into_bytes(),
-                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-                })
-            },
-            materials_buffer: {
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Materials buffer"),
-                    contents: &storage::Buffer(&scene_buffers.materials).into_bytes(),
-                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
-                })
-            },
-            spheres_buffer: {
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Spheres buffer"),
-                    contents: &storage::Buffer(&scene_buffers.spheres).into_bytes(),
-                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
-                })
-            },
-            vertex_buffer: {
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Vertex buffer"),
-                    contents: &storage::Buffer(&scene_buffers.vertices).into_bytes(),
-                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
-                })
-            },
-            index_buffer: {
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Index buffer"),
-                    contents: &storage::Buffer(&scene_buffers.indices).into_bytes(),
-                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
-                })
-            },
-            mesh_buffer: {
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Mesh buffer"),
-                    contents: &storage::Buffer(&scene_buffers.meshes).into_bytes(),
-                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
-                })
-            },
-            render_texture: {
-                let texture = device.create_texture(&wgpu::TextureDescriptor {
-                    label: Some("Output texture"),
-                    size: wgpu::Extent3d {
-                        width: size.width,
-                        height: size.height,
-                        depth_or_array_layers: 1,
-                    },
-                    mip_level_count: 1,
-                    sample_count: 1,
-                    dimension: wgpu::TextureDimension::D2,
-                    format: wgpu::TextureFormat::Rgba32Float,
-                    usage: wgpu::TextureUsages::STORAGE_BINDING,
-                    view_formats: &[wgpu::TextureFormat::Rgba32Float],
-                });
-
-                texture.create_view(&wgpu::TextureViewDescriptor::default())
-            },
-        };
+        let environment = EnvironmentData::new(&device, &queue, environment)?;
+        let renderer = Renderer::new(
+            &device,
+            &queue,
+            scene,
+            environment,
+            &globals,
+            (size.width, size.height),
+            surface_format,
+        )?;
 
         let gui_layer = {
             let ctx = egui::Context::default();
@@ -301,19 +198,18 @@ impl State {
             frames_since_last_checkpoint: 0,
         };
 
-        Self {
+        Ok(Self {
             timing,
             surface,
             device,
             queue,
             config,
             size,
+            accumulated_globals: globals.clone(),
             globals,
-            pipelines,
-            render_data,
-            compute_data,
+            renderer,
             gui_layer,
-        }
+        })
     }
 
     pub fn size(&self) -> winit::dpi::PhysicalSize<u32> {
@@ -330,9 +226,23 @@ impl State {
             self.config.width = new_size.width;
             self.config.height = new_size.height;
             self.surface.configure(&self.device, &self.config);
+
+            // Keep the vertical field of view, widening or narrowing the horizontal one
+            let focal_view = &mut self.globals.camera.focal_view;
+            focal_view.x = focal_view.y * new_size.width as f32 / new_size.height as f32;
+
+            self.renderer
+                .resize(&self.device, new_size.width, new_size.height);
+
+            self.globals.frame = 0;
         }
     }
 
+    /// Whether the target sample count has been reached, leaving nothing to trace
+    pub fn is_converged(&self) -> bool {
+        is_converged(&self.globals)
+    }
+
     pub fn input(&mut self, event: &WindowEvent) -> bool {
         let mut handled = self
             .gui_layer
@@ -340,34 +250,27 @@ impl State {
             .on_event(&self.gui_layer.ctx, event)
             .consumed;
 
-        if handled {
-            // Reset the frame counter so we re-render everything
-            self.globals.frame = 0;
-        }
-
-        match event {
-            WindowEvent::KeyboardInput {
-                input:
-                    winit::event::KeyboardInput {
-                        virtual_keycode: Some(winit::event::VirtualKeyCode::Space),
-                        state,
-                        ..
-                    },
-                ..
-            } => {
-                if *state == winit::event::ElementState::Released {
-                    self.gui_layer.enabled = !self.gui_layer.enabled;
-                    handled = true;
-                }
-            }
-            _ => {}
+        if let WindowEvent::KeyboardInput {
+            input:
+                winit::event::KeyboardInput {
+                    virtual_keycode: Some(winit::event::VirtualKeyCode::Space),
+                    state: winit::event::ElementState::Released,
+                    ..
+                },
+            ..
+        } = event
+        {
+            self.gui_layer.enabled = !self.gui_layer.enabled;
+            handled = true;
         }
 
         handled
     }
 
     pub fn update(&mut self) {
-        self.globals.frame += 1;
+        if !self.is_converged() {
+            self.globals.frame += 1;
+        }
     }
 
     pub fn render(&mut self, window: &Window) -> Result<(), wgpu::SurfaceError> {
@@ -395,125 +298,30 @@ impl State {
 
         let mut cmd_buffer = Vec::new();
 
-        // Copy frame data to GPU
-        {
-            let globals_data = storage::Uniform(&self.globals).into_bytes();
-            let globals_buffer =
-                self.device
-                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                        label: None,
-                        contents: &globals_data,
-                        usage: wgpu::BufferUsages::COPY_SRC,
-                    });
-
-            encoder.copy_buffer_to_buffer(
-                &globals_buffer,
-                0,
-                &self.compute_data.globals_buffer,
-                0,
-                globals_data.len() as wgpu::BufferAddress,
-            );
+        // Start over if anything that affects the traced image changed since the last frame
+        if invalidates_accumulation(&self.accumulated_globals, &self.globals) {
+            self.globals.frame = 0;
         }
 
-        // Compute pass
-        {
-            let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("Compute bind group"),
-                layout: &self.pipelines.compute.bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: self.compute_data.globals_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::TextureView(
-                            &self.compute_data.render_texture,
-                        ),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: self.compute_data.materials_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: self.compute_data.spheres_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 4,
-                        resource: self.compute_data.vertex_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 5,
-                        resource: self.compute_data.index_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 6,
-                        resource: self.compute_data.mesh_buffer.as_entire_binding(),
-                    },
-                ],
-            });
-
-            {
-                let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                    label: Some("Compute pass"),
-                });
+        self.accumulated_globals = self.globals.clone();
 
-                compute_pass.set_pipeline(&self.pipelines.compute.pipeline);
-                compute_pass.set_bind_group(0, &bind_group, &[]);
-                compute_pass.dispatch_workgroups(self.size.width, self.size.height, 1)
-            }
+        if !self.is_converged() {
+            self.renderer.encode_compute_pass(
+                &self.device,
+                &mut encoder,
+                &self.globals,
+                self.size.width,
+                self.size.height,
+            );
         }
 
-        // Render pass
-        {
-            let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("Render bind group"),
-                layout: &self.pipelines.render.bind_group_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&self.compute_data.render_texture),
-                }],
-            });
-
-            {
-                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: Some("Render Pass"),
-                    color_attachments: &[
-                        // This is what @location(0) in the fragment shader targets
-                        Some(wgpu::RenderPassColorAttachment {
-                            view: &view,
-                            resolve_target: None,
-                            ops: wgpu::Operations {
-                                load: wgpu::LoadOp::Clear(wgpu::Color {
-                                    r: 1.0,
-                                    g: 0.0,
-                                    b: 0.5,
-                                    a: 1.0,
-                                }),
-                                store: true,
-                            },
-                        }),
-                    ],
-                    depth_stencil_attachment: None,
-                });
-
-                render_pass.set_pipeline(&self.pipelines.render.pipeline);
-                render_pass.set_bind_group(0, &bind_group, &[]);
-                render_pass.set_vertex_buffer(0, self.render_data.vertex_buffer.slice(..));
-                render_pass.set_index_buffer(
-                    self.render_data.index_buffer.slice(..),
-                    wgpu::IndexFormat::Uint32,
-                );
-
-                render_pass.draw_indexed(0..6, 0, 0..1);
-            }
-        }
+        self.renderer
+            .encode_render_pass(&self.device, &mut encoder, &self.globals, &view);
 
         // GUI Pass
         if self.gui_layer.enabled {
             let screen_descriptor = egui_wgpu::renderer::ScreenDescriptor {
-                size_in_pixels: [self.size.width as u32, self.size.height as u32],
+                size_in_pixels: [self.size.width, self.size.height],
                 pixels_per_point: self.gui_layer.state.pixels_per_point(),
             };
 
@@ -571,7 +379,7 @@ impl State {
                 .renderer
                 .render(&mut render_pass, &paint_jobs, &screen_descriptor);
 
-            cmd_buffer.extend(gui_commands.into_iter());
+            cmd_buffer.extend(gui_commands);
         }
 
         // submit will accept anything that implements IntoIter
@@ -586,79 +394,1477 @@ impl State {
     }
 }
 
-struct SceneBufferBuilder {
-    indices: Vec<u32>,
-    vertices: Vec<glam::f32::Vec3>,
-    meshes: Vec<storage::Mesh>,
-    spheres: Vec<storage::Sphere>,
-    materials: Vec<storage::Material>,
-}
+impl Renderer {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &scene::Scene,
+        environment: EnvironmentData,
+        globals: &storage::Globals,
+        (width, height): (u32, u32),
+        target_format: wgpu::TextureFormat,
+    ) -> Result<Self, String> {
+        let scene_buffers = {
+            let mut builder = SceneBufferBuilder::new();
+            for (i, image) in scene.images.iter().enumerate() {
+                builder
+                    .push_image(i, image)
+                    .map_err(|e| format!("Invalid image at index {}:\n  {}", i, e))?;
+            }
 
-impl SceneBufferBuilder {
-    fn new() -> Self {
-        Self {
-            indices: Vec::new(),
-            vertices: Vec::new(),
-            meshes: Vec::new(),
-            spheres: Vec::new(),
-            materials: Vec::new(),
-        }
-    }
+            for (name, mesh) in &scene.meshes {
+                builder.push_mesh(name, mesh, scene.bvhs.get(name))?;
+            }
 
-    fn push(&mut self, obj: &scene::Object) -> &mut Self {
-        let mat_index = self.materials.len();
-        self.materials.push(storage::Material {
-            color: obj.material.color,
-            luminosity: obj.material.luminosity,
-            smoothness: obj.material.smoothness,
-        });
+            for (name, material) in &scene.materials {
+                builder.push_material(name, material)?;
+            }
 
-        match &obj.surface {
-            scene::Surface::Sphere { radius } => {
-                self.spheres.push(storage::Sphere {
-                    position: obj.transform.position,
-                    radius: (*radius) * f32::max(obj.transform.scale.x, obj.transform.scale.y),
-                    material_id: mat_index as u32,
-                });
+            for (i, obj) in scene.objects.iter().enumerate() {
+                builder
+                    .push(obj)
+                    .map_err(|e| format!("Invalid object at index {}:\n  {}", i, e))?;
             }
-            scene::Surface::MeshData { vertices, indices } => {
-                let index_offset = self.vertices.len() as u32;
-                let affine_transform = glam::f32::Affine3A::from_scale_rotation_translation(
-                    obj.transform.scale,
-                    glam::Quat::from_euler(
-                        glam::EulerRot::XYZ,
-                        obj.transform.rotation.x.to_radians(),
-                        obj.transform.rotation.y.to_radians(),
-                        obj.transform.rotation.z.to_radians(),
-                    ),
-                    obj.transform.position,
-                );
 
-                self.vertices.extend(
-                    vertices
-                        .into_iter()
-                        .map(|v| affine_transform.transform_point3(*v)),
-                );
+            for light in &scene.lights {
+                builder.push_light(light);
+            }
 
-                self.indices
-                    .extend(indices.iter().map(|i| *i + index_offset).fold(
-                        Vec::with_capacity(indices.len() * 3),
-                        |mut acc, i| {
-                            acc.push(i.x);
-                            acc.push(i.y);
-                            acc.push(i.z);
-                            acc
-                        },
-                    ));
+            builder.build(device.limits().max_texture_dimension_2d)?
+        };
 
-                self.meshes.push(storage::Mesh {
-                    index_offset,
-                    triangle_count: indices.len() as u32,
-                    material_id: mat_index as u32,
-                });
-            }
-        }
+        let pipelines = Pipelines {
+            compute: ComputePipeline::new(device),
+            render: RenderPipeline::new(device, target_format),
+        };
 
-        self
+        let render_data = RenderData {
+            vertex_buffer: {
+                let bytes = bytemuck::cast_slice(QUAD_VERTICIES);
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Vertex Buffer"),
+                    contents: bytes,
+                    usage: wgpu::BufferUsages::VERTEX,
+                })
+            },
+            index_buffer: {
+                let bytes = bytemuck::cast_slice(QUAD_INDICES);
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Index Buffer"),
+                    contents: bytes,
+                    usage: wgpu::BufferUsages::INDEX,
+                })
+            },
+        };
+
+        let compute_data = ComputeData {
+            globals_buffer: {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Globals buffer"),
+                    contents: &storage::Uniform(globals).to_bytes(),
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                })
+            },
+            materials_buffer: {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Materials buffer"),
+                    contents: &storage::Buffer(&scene_buffers.materials).to_bytes(),
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                })
+            },
+            spheres_buffer: {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Spheres buffer"),
+                    contents: &storage::Buffer(&scene_buffers.spheres).to_bytes(),
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                })
+            },
+            vertex_buffer: {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Vertex buffer"),
+                    contents: &storage::Buffer(&scene_buffers.vertices).to_bytes(),
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                })
+            },
+            triangle_buffer: {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Triangle buffer"),
+                    contents: &storage::Buffer(&scene_buffers.triangles).to_bytes(),
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                })
+            },
+            bvh_buffer: {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("BVH buffer"),
+                    contents: &storage::Buffer(&scene_buffers.bvh_nodes).to_bytes(),
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                })
+            },
+            instance_buffer: {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Instance buffer"),
+                    contents: &storage::Buffer(&scene_buffers.instances).to_bytes(),
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                })
+            },
+            light_buffer: {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Light buffer"),
+                    contents: &storage::Buffer(&scene_buffers.lights).to_bytes(),
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                })
+            },
+            accumulation_buffer: create_accumulation_buffer(device, width, height),
+            render_texture: create_render_texture(device, width, height),
+            texture_atlas: {
+                let atlas = &scene_buffers.atlas;
+                let texture = device.create_texture_with_data(
+                    queue,
+                    &wgpu::TextureDescriptor {
+                        label: Some("Texture atlas"),
+                        size: wgpu::Extent3d {
+                            width: atlas.width,
+                            height: atlas.height,
+                            depth_or_array_layers: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: wgpu::TextureDimension::D2,
+                        // Color textures are sRGB but normal maps aren't, so the shader decodes them
+                        format: wgpu::TextureFormat::Rgba8Unorm,
+                        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                        view_formats: &[],
+                    },
+                    &atlas.pixels,
+                );
+
+                texture.create_view(&wgpu::TextureViewDescriptor::default())
+            },
+        };
+
+        Ok(Self {
+            pipelines,
+            compute_data,
+            render_data,
+            environment,
+        })
+    }
+
+    /// Recreates the accumulated image at a new size. Its contents are
+    /// undefined until the next frame with a frame number of zero.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.compute_data.accumulation_buffer = create_accumulation_buffer(device, width, height);
+        self.compute_data.render_texture = create_render_texture(device, width, height);
+    }
+
+    /// Uploads the globals and traces one frame into the accumulation texture.
+    pub fn encode_compute_pass(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        globals: &storage::Globals,
+        width: u32,
+        height: u32,
+    ) {
+        // Copy frame data to GPU
+        {
+            let globals_data = storage::Uniform(globals).to_bytes();
+            let globals_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: &globals_data,
+                usage: wgpu::BufferUsages::COPY_SRC,
+            });
+
+            encoder.copy_buffer_to_buffer(
+                &globals_buffer,
+                0,
+                &self.compute_data.globals_buffer,
+                0,
+                globals_data.len() as wgpu::BufferAddress,
+            );
+        }
+
+        // Compute pass
+        {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Compute bind group"),
+                layout: &self.pipelines.compute.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.compute_data.globals_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(
+                            &self.compute_data.render_texture,
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: self.compute_data.materials_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: self.compute_data.spheres_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: self.compute_data.vertex_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: self.compute_data.triangle_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: self.compute_data.bvh_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: self.compute_data.accumulation_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 8,
+                        resource: self.compute_data.instance_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 9,
+                        resource: self.compute_data.light_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 10,
+                        resource: wgpu::BindingResource::TextureView(&self.environment.texture),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 11,
+                        resource: wgpu::BindingResource::TextureView(
+                            &self.environment.conditional_cdf,
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 12,
+                        resource: wgpu::BindingResource::TextureView(
+                            &self.environment.marginal_cdf,
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 13,
+                        resource: wgpu::BindingResource::TextureView(
+                            &self.compute_data.texture_atlas,
+                        ),
+                    },
+                ],
+            });
+
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Compute pass"),
+                });
+
+                compute_pass.set_pipeline(&self.pipelines.compute.pipeline);
+                compute_pass.set_bind_group(0, &bind_group, &[]);
+                compute_pass.dispatch_workgroups(width, height, 1)
+            }
+        }
+    }
+
+    /// Blits the accumulation texture onto the given target view.
+    pub fn encode_render_pass(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        globals: &storage::Globals,
+        view: &wgpu::TextureView,
+    ) {
+        let display = storage::Display {
+            exposure: globals.exposure,
+            tone_mapping: globals.tone_mapping,
+        };
+
+        let display_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Display buffer"),
+            contents: &storage::Uniform(&display).to_bytes(),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Render bind group"),
+            layout: &self.pipelines.render.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&self.compute_data.render_texture),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: display_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            color_attachments: &[
+                // This is what @location(0) in the fragment shader targets
+                Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: 1.0,
+                            g: 0.0,
+                            b: 0.5,
+                            a: 1.0,
+                        }),
+                        store: true,
+                    },
+                }),
+            ],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&self.pipelines.render.pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.render_data.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(
+            self.render_data.index_buffer.slice(..),
+            wgpu::IndexFormat::Uint32,
+        );
+
+        render_pass.draw_indexed(0..6, 0, 0..1);
+    }
+}
+
+fn create_accumulation_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Buffer {
+    let pixel_size = <storage::Accumulation as encase::ShaderSize>::SHADER_SIZE.get();
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Accumulation buffer"),
+        size: (width * height) as wgpu::BufferAddress * pixel_size,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_render_texture(device: &wgpu::Device, width: u32, height: u32) -> wgpu::TextureView {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Output texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::STORAGE_BINDING,
+        view_formats: &[wgpu::TextureFormat::Rgba32Float],
+    });
+
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+/// The number of samples the shader traces per pixel each frame
+pub fn samples_per_frame(globals: &storage::Globals) -> u32 {
+    // Must match the cap in the shader
+    globals.max_samples_per_pixel.min(100)
+}
+
+/// The number of samples accumulated in every pixel so far
+pub fn accumulated_samples(globals: &storage::Globals) -> u32 {
+    let samples = globals.frame * samples_per_frame(globals);
+    match globals.target_samples {
+        0 => samples,
+        target => samples.min(target),
+    }
+}
+
+pub fn is_converged(globals: &storage::Globals) -> bool {
+    globals.target_samples > 0 && accumulated_samples(globals) >= globals.target_samples
+}
+
+/// Whether switching from one set of globals to another changes the traced
+/// image. Display settings and the sample target only change how much of it
+/// is traced or how it's shown, so they keep the accumulated samples.
+fn invalidates_accumulation(previous: &storage::Globals, current: &storage::Globals) -> bool {
+    let traced = |globals: &storage::Globals| storage::Globals {
+        frame: 0,
+        target_samples: 0,
+        exposure: 0.0,
+        tone_mapping: 0,
+        ..globals.clone()
+    };
+
+    traced(previous) != traced(current)
+}
+
+/// Requests an adapter, falling back to a software adapter when no hardware
+/// adapter is available (e.g. on GPU-less CI machines).
+pub async fn request_adapter(
+    instance: &wgpu::Instance,
+    compatible_surface: Option<&wgpu::Surface>,
+) -> Option<wgpu::Adapter> {
+    let options = wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        compatible_surface,
+        force_fallback_adapter: false,
+    };
+
+    match instance.request_adapter(&options).await {
+        Some(adapter) => Some(adapter),
+        None => {
+            instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    force_fallback_adapter: true,
+                    ..options
+                })
+                .await
+        }
+    }
+}
+
+pub async fn request_device(
+    adapter: &wgpu::Adapter,
+) -> Result<(wgpu::Device, wgpu::Queue), wgpu::RequestDeviceError> {
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                features: wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+                limits: wgpu::Limits::default(),
+                label: None,
+            },
+            None,
+        )
+        .await
+}
+
+pub fn create_globals(
+    scene: &scene::Scene,
+    environment: Option<&Environment>,
+    parameters: &Parameters,
+    width: u32,
+    height: u32,
+) -> storage::Globals {
+    storage::Globals {
+        camera: match scene.camera.lens {
+            scene::Lens::Perspective {
+                fov,
+                focal_distance,
+            } => {
+                let fov = fov.to_radians();
+                let aspect_ratio = (width as f32) / (height as f32);
+                let plane_height = 2.0 * (fov / 2.0).tan() * focal_distance;
+                let plane_width = plane_height * aspect_ratio;
+
+                let rotation = scene.camera.transform.rotation;
+                let position = scene.camera.transform.position;
+
+                storage::Camera {
+                    focal_view: glam::f32::vec3(plane_width, plane_height, focal_distance),
+                    world_space_position: position,
+                    local_to_world_matrix: glam::f32::Mat4::from_euler(
+                        glam::EulerRot::XYZ,
+                        rotation.x.to_radians(),
+                        rotation.y.to_radians(),
+                        rotation.z.to_radians(),
+                    ),
+                    near_clip: scene.camera.clipping.near,
+                    far_clip: scene.camera.clipping.far,
+                }
+            }
+        },
+        frame: parameters.frame,
+        random_seed: parameters.random_seed,
+        skybox_color: parameters.skybox_color,
+        ambient_lighting_color: parameters.ambient_lighting_color,
+        ambient_lighting_strength: parameters.ambient_lighting_strength,
+        max_ray_bounces: parameters.max_ray_bounces,
+        max_samples_per_pixel: parameters.max_samples_per_pixel,
+        focal_blur_strength: parameters.focal_blur_strength,
+        reconstruction_filter: parameters.reconstruction_filter,
+        filter_radius: parameters.filter_radius,
+        has_environment: environment.is_some() as u32,
+        environment_intensity: parameters.environment_intensity,
+        environment_rotation: parameters.environment_rotation.to_radians(),
+        target_samples: parameters.target_samples.unwrap_or(0),
+        exposure: parameters.exposure,
+        tone_mapping: parameters.tone_mapping,
+    }
+}
+
+impl EnvironmentData {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        environment: Option<&Environment>,
+    ) -> Result<Self, String> {
+        let Some(environment) = environment else {
+            return Ok(Self {
+                texture: create_data_texture(
+                    device,
+                    queue,
+                    wgpu::TextureFormat::Rgba32Float,
+                    (1, 1),
+                    &[0.0; 4],
+                ),
+                conditional_cdf: create_data_texture(
+                    device,
+                    queue,
+                    wgpu::TextureFormat::R32Float,
+                    (1, 1),
+                    &[0.0],
+                ),
+                marginal_cdf: create_data_texture(
+                    device,
+                    queue,
+                    wgpu::TextureFormat::R32Float,
+                    (1, 1),
+                    &[0.0],
+                ),
+            });
+        };
+
+        let (width, height) = (environment.width, environment.height);
+        let max_size = device.limits().max_texture_dimension_2d;
+        if width + 1 > max_size || height + 1 > max_size {
+            return Err(format!(
+                "Environment is too large: {}x{} (maximum size: {}x{})",
+                width,
+                height,
+                max_size - 1,
+                max_size - 1
+            ));
+        }
+
+        Ok(Self {
+            texture: create_data_texture(
+                device,
+                queue,
+                wgpu::TextureFormat::Rgba32Float,
+                (width, height),
+                bytemuck::cast_slice(&environment.pixels),
+            ),
+            conditional_cdf: create_data_texture(
+                device,
+                queue,
+                wgpu::TextureFormat::R32Float,
+                (width + 1, height),
+                &environment.conditional_cdf,
+            ),
+            marginal_cdf: create_data_texture(
+                device,
+                queue,
+                wgpu::TextureFormat::R32Float,
+                (height + 1, 1),
+                &environment.marginal_cdf,
+            ),
+        })
+    }
+}
+
+/// Creates a texture of 32-bit floats read with `textureLoad` in the shader
+fn create_data_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    format: wgpu::TextureFormat,
+    (width, height): (u32, u32),
+    data: &[f32],
+) -> wgpu::TextureView {
+    let size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Environment texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    let bytes_per_texel = format.block_size(None).unwrap_or(4);
+    queue.write_texture(
+        texture.as_image_copy(),
+        bytemuck::cast_slice(data),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(width * bytes_per_texel),
+            rows_per_image: Some(height),
+        },
+        size,
+    );
+
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+/// Marks an instance as a sphere, with the remaining bits of its root indexing
+/// the sphere buffer. Must match `SPHERE_INSTANCE_FLAG` in the shader.
+const SPHERE_INSTANCE_FLAG: u32 = 1 << 31;
+
+// Must match the LIGHT_* constants in the shader
+const LIGHT_SPHERE: u32 = 0;
+const LIGHT_TRIANGLE: u32 = 1;
+const LIGHT_POINT: u32 = 2;
+const LIGHT_SPOT: u32 = 3;
+const LIGHT_DIRECTIONAL: u32 = 4;
+
+// Fields a kind of light doesn't use
+const NO_LIGHT: storage::Light = storage::Light {
+    position: glam::f32::Vec3::ZERO,
+    kind: LIGHT_SPHERE,
+    edge_ab: glam::f32::Vec3::ZERO,
+    radius: 0.0,
+    edge_ac: glam::f32::Vec3::ZERO,
+    range: 0.0,
+    emission: glam::f32::Vec3::ZERO,
+    cos_outer_cone: -1.0,
+    direction: glam::f32::Vec3::ZERO,
+    cos_inner_cone: -1.0,
+};
+
+/// Collects scene objects into GPU buffers. Each mesh gets its own bottom-level
+/// BVH in object space, built once no matter how many objects reference it, and
+/// objects become instances in a top-level BVH over their world space bounds.
+/// Objects with identical materials share one entry in the material buffer.
+struct SceneBufferBuilder {
+    vertices: Vec<storage::MeshVertex>,
+    triangles: Vec<storage::Triangle>,
+    spheres: Vec<storage::Sphere>,
+    materials: Vec<storage::Material>,
+    blas_nodes: Vec<bvh::Node>,
+    meshes: HashMap<String, Option<Blas>>,
+    material_names: HashMap<String, u32>,
+
+    // The index in `materials` of each scene material, so identical ones are only uploaded once
+    material_ids: HashMap<scene::MaterialKey, u32>,
+    instances: Vec<(storage::Instance, bvh::Aabb)>,
+    lights: Vec<storage::Light>,
+    textures: Vec<image::RgbaImage>,
+    texture_ids: HashMap<scene::Texture, usize>,
+
+    // Materials to point at their textures once the atlas is packed
+    material_textures: Vec<(usize, TextureSlot, usize)>,
+}
+
+/// The vertex attributes of a mesh, whichever surface they came from
+struct MeshAttributes<'a> {
+    vertices: &'a [glam::f32::Vec3],
+    indices: &'a [glam::u32::UVec3],
+    uvs: &'a [glam::f32::Vec2],
+    normals: &'a [glam::f32::Vec3],
+    tangents: &'a [glam::f32::Vec4],
+    colors: &'a [glam::f32::Vec4],
+}
+
+/// A mesh's bottom-level BVH and the range of the triangle buffer it covers
+#[derive(Clone)]
+struct Blas {
+    root: u32,
+    triangles: Range<usize>,
+}
+
+/// Which of a material's textures an image is used as
+#[derive(Clone, Copy)]
+enum TextureSlot {
+    BaseColor,
+    Normal,
+}
+
+struct SceneBuffers {
+    vertices: Vec<storage::MeshVertex>,
+    triangles: Vec<storage::Triangle>,
+    spheres: Vec<storage::Sphere>,
+    materials: Vec<storage::Material>,
+    bvh_nodes: Vec<storage::BvhNode>,
+    instances: Vec<storage::Instance>,
+    lights: Vec<storage::Light>,
+    atlas: Atlas,
+}
+
+impl SceneBufferBuilder {
+    fn new() -> Self {
+        Self {
+            vertices: Vec::new(),
+            triangles: Vec::new(),
+            spheres: Vec::new(),
+            materials: Vec::new(),
+            blas_nodes: Vec::new(),
+            meshes: HashMap::new(),
+            material_names: HashMap::new(),
+            material_ids: HashMap::new(),
+            instances: Vec::new(),
+            lights: Vec::new(),
+            textures: Vec::new(),
+            texture_ids: HashMap::new(),
+            material_textures: Vec::new(),
+        }
+    }
+
+    fn push_image(&mut self, index: usize, image: &scene::Image) -> Result<&mut Self, String> {
+        let image = image::RgbaImage::from_raw(image.width, image.height, image.pixels.clone())
+            .ok_or_else(|| {
+                format!(
+                    "Expected {} bytes of pixels for a {}x{} image but found {}",
+                    image.width as usize * image.height as usize * 4,
+                    image.width,
+                    image.height,
+                    image.pixels.len()
+                )
+            })?;
+
+        self.texture_ids
+            .insert(scene::Texture::Image(index), self.textures.len());
+        self.textures.push(image);
+        Ok(self)
+    }
+
+    /// Finds the texture's index in the atlas, loading it the first time it's used
+    fn texture_id(&mut self, texture: &scene::Texture) -> Result<usize, String> {
+        if let Some(id) = self.texture_ids.get(texture) {
+            return Ok(*id);
+        }
+
+        let image = match texture {
+            scene::Texture::File(path) => image::open(path)
+                .map_err(|e| format!("Unable to load texture: {}\n  {}", path.display(), e))?
+                .into_rgba8(),
+            scene::Texture::Image(index) => return Err(format!("Unknown image: {}", index)),
+        };
+
+        let id = self.textures.len();
+        self.texture_ids.insert(texture.clone(), id);
+        self.textures.push(image);
+        Ok(id)
+    }
+
+    /// Adds a named mesh for objects to reference, using its bottom-level BVH
+    /// if one was already built
+    fn push_mesh(
+        &mut self,
+        name: &str,
+        mesh: &scene::Mesh,
+        bvh: Option<&bvh::Bvh>,
+    ) -> Result<&mut Self, String> {
+        let blas = self
+            .push_blas(
+                MeshAttributes {
+                    vertices: &mesh.vertices,
+                    indices: &mesh.indices,
+                    uvs: &mesh.uvs,
+                    normals: &mesh.normals,
+                    tangents: &mesh.tangents,
+                    colors: &mesh.colors,
+                },
+                bvh,
+            )
+            .map_err(|e| format!("{} (in mesh '{}')", e, name))?;
+
+        self.meshes.insert(name.to_string(), blas);
+        Ok(self)
+    }
+
+    /// Adds a named material for objects to reference
+    fn push_material(
+        &mut self,
+        name: &str,
+        material: &scene::Material,
+    ) -> Result<&mut Self, String> {
+        let material_id = self
+            .material_id(material)
+            .map_err(|e| format!("{} (in material '{}')", e, name))?;
+
+        self.material_names.insert(name.to_string(), material_id);
+        Ok(self)
+    }
+
+    /// Finds the material's index in the material buffer, adding it unless an
+    /// identical one is already there
+    fn material_id(&mut self, material: &scene::Material) -> Result<u32, String> {
+        let key = scene::MaterialKey::from(material);
+        if let Some(id) = self.material_ids.get(&key) {
+            return Ok(*id);
+        }
+
+        let material_id = self.materials.len() as u32;
+        let textures = [
+            (TextureSlot::BaseColor, &material.texture),
+            (TextureSlot::Normal, &material.normal_texture),
+        ];
+
+        for (slot, texture) in textures {
+            if let Some(texture) = texture {
+                let texture_id = self.texture_id(texture)?;
+                self.material_textures
+                    .push((material_id as usize, slot, texture_id));
+            }
+        }
+
+        self.materials.push(storage::Material {
+            color: material.color,
+            emission: material.emission(),
+            metallic: material.metallic,
+            roughness: material.roughness,
+            transmission: material.transmission,
+            ior: material.ior,
+            // Beer's law coefficient that leaves the absorption color after one unit
+            absorption: material.absorption.map_or(glam::f32::Vec3::ZERO, |color| {
+                glam::f32::Vec3::from_array(color.to_array().map(|c| -c.max(1E-6).ln()))
+            }),
+            texture_offset: glam::u32::UVec2::ZERO,
+            texture_size: glam::u32::UVec2::ZERO,
+            normal_map_offset: glam::u32::UVec2::ZERO,
+            normal_map_size: glam::u32::UVec2::ZERO,
+            normal_scale: material.normal_scale,
+        });
+
+        self.material_ids.insert(key, material_id);
+        Ok(material_id)
+    }
+
+    fn push(&mut self, obj: &scene::Object) -> Result<&mut Self, String> {
+        let material_id = match &obj.material {
+            scene::MaterialRef::Inline(material) => self.material_id(material)?,
+            scene::MaterialRef::Named(name) => *self
+                .material_names
+                .get(name)
+                .ok_or_else(|| format!("Unknown material: '{}'", name))?,
+        };
+
+        let emission = self.materials[material_id as usize].emission;
+        let emissive = emission.max_element() > 0.0;
+
+        let blas = match &obj.surface {
+            scene::Surface::Sphere { radius } => {
+                let sphere_index = self.spheres.len() as u32;
+                let sphere = storage::Sphere {
+                    position: obj.transform.position,
+                    radius: (*radius) * f32::max(obj.transform.scale.x, obj.transform.scale.y),
+                    material_id,
+                };
+
+                // Spheres are intersected in world space, so their instances don't need a transform
+                let bounds = bvh::Aabb {
+                    min: sphere.position - sphere.radius,
+                    max: sphere.position + sphere.radius,
+                };
+
+                if emissive {
+                    self.lights.push(storage::Light {
+                        position: sphere.position,
+                        kind: LIGHT_SPHERE,
+                        edge_ab: glam::f32::Vec3::ZERO,
+                        radius: sphere.radius,
+                        edge_ac: glam::f32::Vec3::ZERO,
+                        emission,
+                        ..NO_LIGHT
+                    });
+                }
+
+                self.spheres.push(sphere);
+                self.instances.push((
+                    storage::Instance {
+                        world_to_object: glam::f32::Mat4::IDENTITY,
+                        blas_root: sphere_index | SPHERE_INSTANCE_FLAG,
+                        material_id,
+                    },
+                    bounds,
+                ));
+
+                return Ok(self);
+            }
+            scene::Surface::MeshData {
+                vertices,
+                indices,
+                uvs,
+                normals,
+                tangents,
+                colors,
+            } => self.push_blas(
+                MeshAttributes {
+                    vertices,
+                    indices,
+                    uvs,
+                    normals,
+                    tangents,
+                    colors,
+                },
+                None,
+            )?,
+            scene::Surface::Mesh { name } => self
+                .meshes
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("Unknown mesh: '{}'", name))?,
+            scene::Surface::MeshFile { path, .. } => {
+                return Err(format!("Mesh file wasn't loaded: {}", path.display()))
+            }
+        };
+
+        // Meshes without any triangles have nothing to intersect
+        let Some(blas) = blas else {
+            return Ok(self);
+        };
+
+        let object_to_world = glam::f32::Affine3A::from(&obj.transform);
+        if emissive {
+            self.push_triangle_lights(&blas, &object_to_world, emission);
+        }
+
+        let local_bounds = self.blas_nodes[blas.root as usize].bounds;
+        let bounds = (0..8).fold(bvh::Aabb::EMPTY, |aabb, corner| {
+            let point = glam::f32::vec3(
+                if corner & 1 == 0 {
+                    local_bounds.min.x
+                } else {
+                    local_bounds.max.x
+                },
+                if corner & 2 == 0 {
+                    local_bounds.min.y
+                } else {
+                    local_bounds.max.y
+                },
+                if corner & 4 == 0 {
+                    local_bounds.min.z
+                } else {
+                    local_bounds.max.z
+                },
+            );
+
+            aabb.grow(object_to_world.transform_point3(point))
+        });
+
+        self.instances.push((
+            storage::Instance {
+                world_to_object: glam::f32::Mat4::from(object_to_world.inverse()),
+                blas_root: blas.root,
+                material_id,
+            },
+            bounds,
+        ));
+
+        Ok(self)
+    }
+
+    fn push_light(&mut self, light: &scene::Light) -> &mut Self {
+        let transform = glam::f32::Affine3A::from(&light.transform);
+        let (kind, cos_inner_cone, cos_outer_cone) = match light.kind {
+            scene::LightKind::Point => (LIGHT_POINT, -1.0, -1.0),
+            scene::LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => (
+                LIGHT_SPOT,
+                inner_cone_angle.min(outer_cone_angle).to_radians().cos(),
+                outer_cone_angle.to_radians().cos(),
+            ),
+            scene::LightKind::Directional => (LIGHT_DIRECTIONAL, -1.0, -1.0),
+        };
+
+        self.lights.push(storage::Light {
+            position: light.transform.position,
+            kind,
+            range: light.range.unwrap_or(0.0),
+            emission: light.color * light.intensity,
+            cos_outer_cone,
+            direction: transform
+                .transform_vector3(glam::f32::Vec3::Z)
+                .normalize_or_zero(),
+            cos_inner_cone,
+            ..NO_LIGHT
+        });
+
+        self
+    }
+
+    /// Adds every triangle of an emissive mesh instance as a light in world space
+    fn push_triangle_lights(
+        &mut self,
+        blas: &Blas,
+        object_to_world: &glam::f32::Affine3A,
+        emission: glam::f32::Vec3,
+    ) {
+        let lights = self.triangles[blas.triangles.clone()].iter().map(|t| {
+            let [a, b, c] = t
+                .indices
+                .to_array()
+                .map(|i| object_to_world.transform_point3(self.vertices[i as usize].position));
+
+            storage::Light {
+                position: a,
+                kind: LIGHT_TRIANGLE,
+                edge_ab: b - a,
+                radius: 0.0,
+                edge_ac: c - a,
+                emission,
+                ..NO_LIGHT
+            }
+        });
+
+        self.lights.extend(lights);
+    }
+
+    /// Builds a bottom-level BVH over a mesh's triangles, unless one is given
+    fn push_blas(
+        &mut self,
+        mesh: MeshAttributes,
+        prebuilt: Option<&bvh::Bvh>,
+    ) -> Result<Option<Blas>, String> {
+        let MeshAttributes {
+            vertices,
+            indices,
+            uvs,
+            normals,
+            tangents,
+            colors,
+        } = mesh;
+
+        let attributes = [
+            ("UVs", uvs.len()),
+            ("normals", normals.len()),
+            ("tangents", tangents.len()),
+            ("colors", colors.len()),
+        ];
+
+        // Optional attributes are either missing or given for every vertex
+        for (name, count) in attributes {
+            if count != 0 && count != vertices.len() {
+                return Err(format!(
+                    "Mesh has {} {} but {} vertices",
+                    count,
+                    name,
+                    vertices.len()
+                ));
+            }
+        }
+
+        let vertex_count = vertices.len() as u32;
+        if let Some(i) = indices.iter().find(|i| i.max_element() >= vertex_count) {
+            return Err(format!(
+                "Triangle {:?} references a vertex out of range (vertex count: {})",
+                i, vertex_count
+            ));
+        }
+
+        let built;
+        let bvh = match prebuilt {
+            Some(bvh) => {
+                bvh.validate(indices.len())
+                    .map_err(|e| format!("Invalid prebuilt BVH:\n  {}", e))?;
+                bvh
+            }
+            None => {
+                built = bvh::Bvh::build_triangles(vertices, indices);
+                &built
+            }
+        };
+
+        if bvh.nodes.is_empty() {
+            return Ok(None);
+        }
+
+        let vertex_offset = self.vertices.len() as u32;
+        let triangle_offset = self.triangles.len() as u32;
+        let node_offset = self.blas_nodes.len() as u32;
+
+        self.vertices
+            .extend(
+                vertices
+                    .iter()
+                    .enumerate()
+                    .map(|(i, position)| storage::MeshVertex {
+                        position: *position,
+                        normal: normals.get(i).copied().unwrap_or_default(),
+                        tangent: tangents.get(i).copied().unwrap_or_default(),
+                        uv: uvs.get(i).copied().unwrap_or_default(),
+                        color: colors.get(i).copied().unwrap_or(glam::f32::Vec4::ONE),
+                    }),
+            );
+
+        // Store triangles in tree order so leaves can reference them directly
+        self.triangles
+            .extend(bvh.indices.iter().map(|i| storage::Triangle {
+                indices: indices[*i as usize] + vertex_offset,
+            }));
+
+        self.blas_nodes
+            .extend(bvh.nodes.iter().map(|node| bvh::Node {
+                first: match node.count {
+                    0 => node.first + node_offset,
+                    _ => node.first + triangle_offset,
+                },
+                ..*node
+            }));
+
+        Ok(Some(Blas {
+            root: node_offset,
+            triangles: triangle_offset as usize..self.triangles.len(),
+        }))
+    }
+
+    /// Builds the top-level BVH over every instance. Its nodes come first in the
+    /// node buffer, followed by the nodes of every mesh's bottom-level BVH.
+    fn build(mut self, max_texture_size: u32) -> Result<SceneBuffers, String> {
+        let atlas = Atlas::pack(&self.textures, max_texture_size)?;
+        for (material_id, slot, texture_id) in &self.material_textures {
+            let material = &mut self.materials[*material_id];
+            let offset = atlas.offsets[*texture_id];
+            let size = {
+                let texture = &self.textures[*texture_id];
+                glam::u32::uvec2(texture.width(), texture.height())
+            };
+
+            match slot {
+                TextureSlot::BaseColor => {
+                    material.texture_offset = offset;
+                    material.texture_size = size;
+                }
+                TextureSlot::Normal => {
+                    material.normal_map_offset = offset;
+                    material.normal_map_size = size;
+                }
+            }
+        }
+
+        let bounds: Vec<bvh::Aabb> = self.instances.iter().map(|(_, b)| *b).collect();
+        let tlas = bvh::Bvh::build(&bounds);
+        let tlas_size = tlas.nodes.len() as u32;
+
+        let tlas_nodes = tlas.nodes.iter().map(|node| to_storage_node(node, 0));
+        let blas_nodes = self.blas_nodes.iter().map(|node| match node.count {
+            0 => to_storage_node(node, tlas_size),
+            _ => to_storage_node(node, 0),
+        });
+
+        // Store instances in tree order so leaves can reference them directly
+        let instances = tlas
+            .indices
+            .iter()
+            .map(|i| {
+                let (instance, _) = &self.instances[*i as usize];
+                storage::Instance {
+                    world_to_object: instance.world_to_object,
+                    blas_root: match instance.blas_root & SPHERE_INSTANCE_FLAG {
+                        0 => instance.blas_root + tlas_size,
+                        _ => instance.blas_root,
+                    },
+                    material_id: instance.material_id,
+                }
+            })
+            .collect();
+
+        Ok(SceneBuffers {
+            vertices: self.vertices,
+            triangles: self.triangles,
+            spheres: self.spheres,
+            materials: self.materials,
+            bvh_nodes: tlas_nodes.chain(blas_nodes).collect(),
+            instances,
+            lights: self.lights,
+            atlas,
+        })
+    }
+}
+
+fn to_storage_node(node: &bvh::Node, child_offset: u32) -> storage::BvhNode {
+    storage::BvhNode {
+        aabb_min: node.bounds.min,
+        first: node.first + child_offset,
+        aabb_max: node.bounds.max,
+        count: node.count,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAX_TEXTURE_SIZE: u32 = 2048;
+
+    fn material() -> scene::Material {
+        scene::Material {
+            color: glam::f32::Vec4::ONE,
+            luminosity: 0.0,
+            emission_color: None,
+            metallic: 0.0,
+            roughness: 1.0,
+            transmission: 0.0,
+            ior: 1.5,
+            absorption: None,
+            texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+        }
+    }
+
+    fn object(surface: scene::Surface, position: glam::f32::Vec3) -> scene::Object {
+        scene::Object {
+            surface,
+            transform: scene::Transform {
+                position,
+                rotation: glam::f32::Vec3::ZERO,
+                scale: glam::f32::Vec3::ONE,
+            },
+            material: material().into(),
+        }
+    }
+
+    #[test]
+    fn test_mesh_instances_share_geometry() {
+        let mesh = scene::Mesh {
+            vertices: vec![
+                glam::f32::vec3(0.0, 0.0, 0.0),
+                glam::f32::vec3(1.0, 0.0, 0.0),
+                glam::f32::vec3(0.0, 1.0, 0.0),
+            ],
+            indices: vec![glam::u32::uvec3(0, 1, 2)],
+            uvs: Vec::new(),
+            normals: Vec::new(),
+            tangents: Vec::new(),
+            colors: Vec::new(),
+        };
+
+        let mut builder = SceneBufferBuilder::new();
+        builder.push_mesh("triangle", &mesh, None).unwrap();
+        for x in 0..3 {
+            let surface = scene::Surface::Mesh {
+                name: "triangle".to_string(),
+            };
+
+            builder
+                .push(&object(surface, glam::f32::vec3(x as f32, 0.0, 0.0)))
+                .unwrap();
+        }
+
+        let buffers = builder.build(MAX_TEXTURE_SIZE).unwrap();
+        assert_eq!(buffers.vertices.len(), 3);
+        assert_eq!(buffers.triangles.len(), 1);
+        assert_eq!(buffers.instances.len(), 3);
+
+        // Every instance points past the top-level nodes at the same mesh root
+        let roots: Vec<u32> = buffers.instances.iter().map(|i| i.blas_root).collect();
+        assert!(roots.iter().all(|root| *root == roots[0]));
+        assert_eq!(roots[0] as usize, buffers.bvh_nodes.len() - 1);
+    }
+
+    #[test]
+    fn test_unknown_mesh() {
+        let surface = scene::Surface::Mesh {
+            name: "missing".to_string(),
+        };
+
+        let mut builder = SceneBufferBuilder::new();
+        assert!(builder
+            .push(&object(surface, glam::f32::Vec3::ZERO))
+            .is_err());
+    }
+
+    #[test]
+    fn test_emissive_objects_become_lights() {
+        let triangle = scene::Surface::MeshData {
+            vertices: vec![
+                glam::f32::vec3(0.0, 0.0, 0.0),
+                glam::f32::vec3(1.0, 0.0, 0.0),
+                glam::f32::vec3(0.0, 1.0, 0.0),
+            ],
+            indices: vec![glam::u32::uvec3(0, 1, 2)],
+            uvs: Vec::new(),
+            normals: Vec::new(),
+            tangents: Vec::new(),
+            colors: Vec::new(),
+        };
+
+        let mut emissive = object(triangle, glam::f32::vec3(0.0, 0.0, 5.0));
+        emissive.material = scene::Material {
+            luminosity: 2.0,
+            ..material()
+        }
+        .into();
+
+        let sphere = scene::Surface::Sphere { radius: 1.0 };
+        let mut builder = SceneBufferBuilder::new();
+        builder
+            .push(&emissive)
+            .unwrap()
+            .push(&object(sphere, glam::f32::Vec3::ZERO))
+            .unwrap();
+
+        // Only the emissive triangle is a light, placed in world space
+        let buffers = builder.build(MAX_TEXTURE_SIZE).unwrap();
+        assert_eq!(buffers.lights.len(), 1);
+        assert_eq!(buffers.lights[0].kind, LIGHT_TRIANGLE);
+        assert_eq!(buffers.lights[0].position, glam::f32::vec3(0.0, 0.0, 5.0));
+        assert_eq!(buffers.lights[0].emission, glam::f32::Vec3::splat(2.0));
+    }
+
+    #[test]
+    fn test_spot_lights() {
+        let spot = scene::Light {
+            kind: scene::LightKind::Spot {
+                inner_cone_angle: 60.0,
+                outer_cone_angle: 90.0,
+            },
+            transform: scene::Transform {
+                position: glam::f32::vec3(1.0, 2.0, 3.0),
+                rotation: glam::f32::vec3(90.0, 0.0, 0.0),
+                scale: glam::f32::Vec3::ONE,
+            },
+            color: glam::f32::vec3(1.0, 0.5, 0.25),
+            intensity: 4.0,
+            range: None,
+        };
+
+        let mut builder = SceneBufferBuilder::new();
+        builder.push_light(&spot);
+
+        // Pitched down from shining along +Z
+        let buffers = builder.build(MAX_TEXTURE_SIZE).unwrap();
+        let light = &buffers.lights[0];
+        assert_eq!(light.kind, LIGHT_SPOT);
+        assert_eq!(light.position, glam::f32::vec3(1.0, 2.0, 3.0));
+        assert!(light
+            .direction
+            .abs_diff_eq(glam::f32::vec3(0.0, -1.0, 0.0), 1E-6));
+        assert_eq!(light.emission, glam::f32::vec3(4.0, 2.0, 1.0));
+        assert!((light.cos_inner_cone - 0.5).abs() < 1E-6);
+        assert!(light.cos_outer_cone.abs() < 1E-6);
+    }
+
+    #[test]
+    fn test_textures_are_shared() {
+        let image = scene::Image {
+            width: 2,
+            height: 2,
+            pixels: vec![255; 16],
+        };
+
+        let mut textured = object(
+            scene::Surface::Sphere { radius: 1.0 },
+            glam::f32::Vec3::ZERO,
+        );
+        textured.material = scene::Material {
+            texture: Some(scene::Texture::Image(0)),
+            ..material()
+        }
+        .into();
+
+        let mut builder = SceneBufferBuilder::new();
+        builder
+            .push_image(0, &image)
+            .unwrap()
+            .push(&textured)
+            .unwrap()
+            .push(&textured)
+            .unwrap()
+            .push(&object(
+                scene::Surface::Sphere { radius: 1.0 },
+                glam::f32::Vec3::ZERO,
+            ))
+            .unwrap();
+
+        let buffers = builder.build(MAX_TEXTURE_SIZE).unwrap();
+        assert_eq!((buffers.atlas.width, buffers.atlas.height), (2, 2));
+
+        // The two textured objects share a material too
+        let sizes: Vec<_> = buffers.materials.iter().map(|m| m.texture_size).collect();
+        assert_eq!(sizes, [glam::u32::uvec2(2, 2), glam::u32::UVec2::ZERO]);
+    }
+
+    #[test]
+    fn test_named_materials() {
+        let sphere = || scene::Surface::Sphere { radius: 1.0 };
+        let mut named = object(sphere(), glam::f32::Vec3::ZERO);
+        named.material = scene::MaterialRef::Named("white".to_string());
+
+        let mut builder = SceneBufferBuilder::new();
+        builder
+            .push_material("white", &material())
+            .unwrap()
+            .push(&named)
+            .unwrap()
+            .push(&object(sphere(), glam::f32::Vec3::ONE))
+            .unwrap();
+
+        // The inline material is the same as the named one, so only one is uploaded
+        let buffers = builder.build(MAX_TEXTURE_SIZE).unwrap();
+        assert_eq!(buffers.materials.len(), 1);
+        assert!(buffers.spheres.iter().all(|s| s.material_id == 0));
+
+        named.material = scene::MaterialRef::Named("black".to_string());
+        let mut builder = SceneBufferBuilder::new();
+        assert!(builder.push(&named).is_err());
+    }
+
+    #[test]
+    fn test_unknown_image() {
+        let mut textured = object(
+            scene::Surface::Sphere { radius: 1.0 },
+            glam::f32::Vec3::ZERO,
+        );
+        textured.material = scene::Material {
+            texture: Some(scene::Texture::Image(3)),
+            ..material()
+        }
+        .into();
+
+        let mut builder = SceneBufferBuilder::new();
+        assert!(builder.push(&textured).is_err());
+    }
+
+    fn globals() -> storage::Globals {
+        let scene: scene::Scene =
+            ron::from_str(include_str!("../examples/01-spheres.ron")).unwrap();
+
+        let parameters = Parameters {
+            frame: 0,
+            random_seed: 0,
+            max_ray_bounces: 4,
+            max_samples_per_pixel: 4,
+            skybox_color: glam::Vec3::ZERO,
+            ambient_lighting_color: glam::Vec3::ONE,
+            ambient_lighting_strength: 0.1,
+            focal_blur_strength: 0.0,
+            reconstruction_filter: 0,
+            filter_radius: 0.5,
+            environment_intensity: 1.0,
+            environment_rotation: 0.0,
+            target_samples: Some(10),
+            exposure: 0.0,
+            tone_mapping: 0,
+        };
+
+        create_globals(&scene, None, &parameters, 64, 32)
+    }
+
+    #[test]
+    fn test_accumulation_stops_at_target() {
+        let mut globals = globals();
+        globals.frame = 2;
+        assert_eq!(accumulated_samples(&globals), 8);
+        assert!(!is_converged(&globals));
+
+        globals.frame = 3;
+        assert_eq!(accumulated_samples(&globals), 10);
+        assert!(is_converged(&globals));
+    }
+
+    #[test]
+    fn test_display_changes_keep_accumulation() {
+        let previous = globals();
+
+        let mut current = previous.clone();
+        current.frame = 7;
+        current.exposure = 1.5;
+        current.tone_mapping = 2;
+        current.target_samples = 100;
+        assert!(!invalidates_accumulation(&previous, &current));
+
+        current.camera.world_space_position.x += 1.0;
+        assert!(invalidates_accumulation(&previous, &current));
     }
 }
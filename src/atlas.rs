@@ -0,0 +1,112 @@
+/// Every texture in the scene packed into a single image, since a shader can
+/// only bind a fixed number of textures. Pixels are 8-bit RGBA.
+pub struct Atlas {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+
+    /// The top left corner of each packed image, in the order they were given
+    pub offsets: Vec<glam::u32::UVec2>,
+}
+
+impl Atlas {
+    /// Packs the images in rows of decreasing height, in an atlas no larger
+    /// than `max_size` along either side
+    pub fn pack(images: &[image::RgbaImage], max_size: u32) -> Result<Self, String> {
+        let area: u64 = images
+            .iter()
+            .map(|image| image.width() as u64 * image.height() as u64)
+            .sum();
+
+        let widest = images.iter().map(|image| image.width()).max().unwrap_or(1);
+        let width = ((area as f64).sqrt().ceil() as u32)
+            .next_power_of_two()
+            .max(widest)
+            .min(max_size);
+
+        let mut order: Vec<usize> = (0..images.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(images[*i].height()));
+
+        let mut offsets = vec![glam::u32::UVec2::ZERO; images.len()];
+        let mut cursor = glam::u32::UVec2::ZERO;
+        let mut row_height = 0;
+        for i in order {
+            let image = &images[i];
+            if image.width() > width {
+                return Err(format!(
+                    "Texture is too large: {}x{} (maximum size: {}x{})",
+                    image.width(),
+                    image.height(),
+                    max_size,
+                    max_size
+                ));
+            }
+
+            if cursor.x + image.width() > width {
+                cursor = glam::u32::uvec2(0, cursor.y + row_height);
+                row_height = 0;
+            }
+
+            offsets[i] = cursor;
+            cursor.x += image.width();
+            row_height = row_height.max(image.height());
+        }
+
+        let height = (cursor.y + row_height).max(1);
+        if height > max_size {
+            return Err(format!(
+                "Textures don't fit in a {}x{} texture atlas",
+                max_size, max_size
+            ));
+        }
+
+        let mut pixels = vec![0; width as usize * height as usize * 4];
+        for (image, offset) in images.iter().zip(&offsets) {
+            let row_bytes = image.width() as usize * 4;
+            for (y, row) in image.as_raw().chunks_exact(row_bytes).enumerate() {
+                let start = ((offset.y as usize + y) * width as usize + offset.x as usize) * 4;
+                pixels[start..start + row_bytes].copy_from_slice(row);
+            }
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+            offsets,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_images_dont_overlap() {
+        let images: Vec<_> = [(4, 4), (2, 3), (3, 1), (4, 2)]
+            .iter()
+            .enumerate()
+            .map(|(i, (w, h))| image::RgbaImage::from_pixel(*w, *h, image::Rgba([i as u8; 4])))
+            .collect();
+
+        let atlas = Atlas::pack(&images, 16).unwrap();
+        for (i, (image, offset)) in images.iter().zip(&atlas.offsets).enumerate() {
+            assert!(offset.x + image.width() <= atlas.width);
+            assert!(offset.y + image.height() <= atlas.height);
+
+            for y in offset.y..offset.y + image.height() {
+                for x in offset.x..offset.x + image.width() {
+                    let start = ((y * atlas.width + x) * 4) as usize;
+                    assert_eq!(atlas.pixels[start..start + 4], [i as u8; 4]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_too_many_textures() {
+        let images = vec![image::RgbaImage::new(4, 4); 5];
+        assert!(Atlas::pack(&images, 8).is_err());
+    }
+}
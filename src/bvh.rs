@@ -0,0 +1,388 @@
+use glam::{f32::Vec3, u32::UVec3};
+
+/// The deepest a tree is allowed to grow. The shader sizes its traversal stack
+/// to match, so this must stay in sync with `BVH_STACK_SIZE` in the shader.
+pub const MAX_DEPTH: usize = 64;
+
+const BIN_COUNT: usize = 12;
+
+// Cost of visiting a node relative to intersecting a primitive
+const TRAVERSAL_COST: f32 = 1.0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::splat(f32::INFINITY),
+        max: Vec3::splat(f32::NEG_INFINITY),
+    };
+
+    pub fn from_points(points: &[Vec3]) -> Self {
+        points.iter().fold(Self::EMPTY, |aabb, p| aabb.grow(*p))
+    }
+
+    pub fn grow(&self, point: Vec3) -> Self {
+        Self {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let extent = self.max - self.min;
+        if extent.cmplt(Vec3::ZERO).any() {
+            return 0.0;
+        }
+
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+}
+
+/// A node in a flattened tree. Interior nodes have a `count` of zero and store
+/// the index of their left child in `first`, with the right child directly after
+/// it. Leaves store a range into `Bvh::indices`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Node {
+    pub bounds: Aabb,
+    pub first: u32,
+    pub count: u32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Bvh {
+    pub nodes: Vec<Node>,
+
+    /// Primitive indices ordered so that every leaf references a contiguous range
+    pub indices: Vec<u32>,
+}
+
+impl Bvh {
+    /// Builds a tree over the given primitive bounds using the surface area heuristic.
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut builder = Builder {
+            bounds,
+            centroids: bounds.iter().map(|b| b.centroid()).collect(),
+            indices: (0..bounds.len() as u32).collect(),
+            nodes: Vec::with_capacity(bounds.len() * 2),
+        };
+
+        if !bounds.is_empty() {
+            let root = builder.leaf(0, bounds.len());
+            builder.nodes.push(root);
+            builder.subdivide(0, 0);
+        }
+
+        Self {
+            nodes: builder.nodes,
+            indices: builder.indices,
+        }
+    }
+
+    /// Builds a tree over a mesh's triangles, which must only reference vertices in range
+    pub fn build_triangles(vertices: &[Vec3], indices: &[UVec3]) -> Self {
+        let bounds: Vec<Aabb> = indices
+            .iter()
+            .map(|i| {
+                Aabb::from_points(&[
+                    vertices[i.x as usize],
+                    vertices[i.y as usize],
+                    vertices[i.z as usize],
+                ])
+            })
+            .collect();
+
+        Self::build(&bounds)
+    }
+
+    /// Checks that a tree that wasn't built here, like one read from a file,
+    /// orders all `count` primitives and only points within itself, no deeper
+    /// than the shader can traverse
+    pub fn validate(&self, count: usize) -> Result<(), String> {
+        if self.indices.len() != count {
+            return Err(format!(
+                "Expected {} primitives but found {}",
+                count,
+                self.indices.len()
+            ));
+        }
+
+        let mut seen = vec![false; count];
+        for i in &self.indices {
+            match seen.get_mut(*i as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return Err(format!("Primitive {} is out of range or repeated", i)),
+            }
+        }
+
+        if self.nodes.is_empty() {
+            return match count {
+                0 => Ok(()),
+                _ => Err("Missing nodes".to_string()),
+            };
+        }
+
+        // Children always come after their parent, so no path can loop, and
+        // each node has one parent, so nothing is visited twice
+        let mut visited = vec![false; self.nodes.len()];
+        let mut stack = vec![(0, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = self.nodes[index];
+            if std::mem::replace(&mut visited[index], true) {
+                return Err(format!("Node {} has more than one parent", index));
+            }
+
+            if depth >= MAX_DEPTH {
+                return Err(format!("Tree is deeper than {} levels", MAX_DEPTH));
+            }
+
+            let (first, count) = (node.first as usize, node.count as usize);
+            if count == 0 {
+                if first <= index || first + 1 >= self.nodes.len() {
+                    return Err(format!("Node {} has children out of range", index));
+                }
+
+                stack.push((first, depth + 1));
+                stack.push((first + 1, depth + 1));
+            } else if first + count > self.indices.len() {
+                return Err(format!("Node {} has primitives out of range", index));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Builder<'a> {
+    bounds: &'a [Aabb],
+    centroids: Vec<Vec3>,
+    indices: Vec<u32>,
+    nodes: Vec<Node>,
+}
+
+struct Split {
+    axis: usize,
+    bin: usize,
+    cost: f32,
+}
+
+impl Builder<'_> {
+    fn leaf(&self, first: usize, count: usize) -> Node {
+        let bounds = self.indices[first..first + count]
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, i| aabb.union(&self.bounds[*i as usize]));
+
+        Node {
+            bounds,
+            first: first as u32,
+            count: count as u32,
+        }
+    }
+
+    fn subdivide(&mut self, node_index: usize, depth: usize) {
+        let node = self.nodes[node_index];
+        let (first, count) = (node.first as usize, node.count as usize);
+        if count <= 1 || depth + 1 >= MAX_DEPTH {
+            return;
+        }
+
+        let centroid_bounds = self.indices[first..first + count]
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, i| {
+                aabb.grow(self.centroids[*i as usize])
+            });
+
+        let node_area = node.bounds.surface_area();
+        let split = match self.find_split(first, count, &centroid_bounds, node_area) {
+            Some(split) => split,
+            None => return,
+        };
+
+        let leaf_cost = count as f32 * node_area;
+        if split.cost >= leaf_cost {
+            return;
+        }
+
+        // Partition the primitives so the ones left of the split come first
+        let mut left_count = 0;
+        for i in first..first + count {
+            let centroid = self.centroids[self.indices[i] as usize];
+            if bin_index(centroid, &centroid_bounds, split.axis) < split.bin {
+                self.indices.swap(first + left_count, i);
+                left_count += 1;
+            }
+        }
+
+        if left_count == 0 || left_count == count {
+            return;
+        }
+
+        let left_index = self.nodes.len();
+        let left = self.leaf(first, left_count);
+        let right = self.leaf(first + left_count, count - left_count);
+        self.nodes.push(left);
+        self.nodes.push(right);
+
+        self.nodes[node_index].first = left_index as u32;
+        self.nodes[node_index].count = 0;
+
+        self.subdivide(left_index, depth + 1);
+        self.subdivide(left_index + 1, depth + 1);
+    }
+
+    fn find_split(
+        &self,
+        first: usize,
+        count: usize,
+        centroid_bounds: &Aabb,
+        node_area: f32,
+    ) -> Option<Split> {
+        let mut best: Option<Split> = None;
+        for axis in 0..3 {
+            if centroid_bounds.max[axis] <= centroid_bounds.min[axis] {
+                continue;
+            }
+
+            let mut bins = [(Aabb::EMPTY, 0usize); BIN_COUNT];
+            for i in &self.indices[first..first + count] {
+                let bin = bin_index(self.centroids[*i as usize], centroid_bounds, axis);
+                bins[bin].0 = bins[bin].0.union(&self.bounds[*i as usize]);
+                bins[bin].1 += 1;
+            }
+
+            // Sweep from the right so each split's right-hand cost is known
+            let mut right_costs = [0.0; BIN_COUNT];
+            let (mut right_bounds, mut right_count) = (Aabb::EMPTY, 0);
+            for bin in (1..BIN_COUNT).rev() {
+                right_bounds = right_bounds.union(&bins[bin].0);
+                right_count += bins[bin].1;
+                right_costs[bin] = right_count as f32 * right_bounds.surface_area();
+            }
+
+            let (mut left_bounds, mut left_count) = (Aabb::EMPTY, 0);
+            for bin in 1..BIN_COUNT {
+                left_bounds = left_bounds.union(&bins[bin - 1].0);
+                left_count += bins[bin - 1].1;
+
+                let cost = TRAVERSAL_COST * node_area
+                    + left_count as f32 * left_bounds.surface_area()
+                    + right_costs[bin];
+
+                if best.as_ref().is_none_or(|b| cost < b.cost) {
+                    best = Some(Split { axis, bin, cost });
+                }
+            }
+        }
+
+        best
+    }
+}
+
+fn bin_index(centroid: Vec3, centroid_bounds: &Aabb, axis: usize) -> usize {
+    let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+    let t = (centroid[axis] - centroid_bounds.min[axis]) / extent;
+    ((t * BIN_COUNT as f32) as usize).min(BIN_COUNT - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn contains(outer: &Aabb, inner: &Aabb) -> bool {
+        outer.min.cmple(inner.min).all() && outer.max.cmpge(inner.max).all()
+    }
+
+    fn grid(size: usize) -> Vec<Aabb> {
+        (0..size * size * size)
+            .map(|i| {
+                let p = Vec3::new(
+                    (i % size) as f32,
+                    ((i / size) % size) as f32,
+                    (i / (size * size)) as f32,
+                );
+
+                Aabb::from_points(&[p, p + Vec3::splat(0.5)])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_references_every_primitive_once() {
+        let bounds = grid(8);
+        let bvh = Bvh::build(&bounds);
+
+        let mut seen = vec![0; bounds.len()];
+        for node in bvh.nodes.iter().filter(|n| n.count > 0) {
+            for i in node.first..node.first + node.count {
+                seen[bvh.indices[i as usize] as usize] += 1;
+            }
+        }
+
+        assert!(seen.iter().all(|count| *count == 1));
+    }
+
+    #[test]
+    fn test_build_nodes_contain_their_contents() {
+        let bounds = grid(6);
+        let bvh = Bvh::build(&bounds);
+        assert!(bvh.nodes.len() > 1);
+
+        for node in &bvh.nodes {
+            if node.count > 0 {
+                for i in node.first..node.first + node.count {
+                    let primitive = &bounds[bvh.indices[i as usize] as usize];
+                    assert!(contains(&node.bounds, primitive));
+                }
+            } else {
+                let left = &bvh.nodes[node.first as usize];
+                let right = &bvh.nodes[node.first as usize + 1];
+                assert!(contains(&node.bounds, &left.bounds));
+                assert!(contains(&node.bounds, &right.bounds));
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let bounds = grid(4);
+        let bvh = Bvh::build(&bounds);
+        assert_eq!(bvh.validate(bounds.len()), Ok(()));
+        assert!(bvh.validate(bounds.len() + 1).is_err());
+
+        let mut repeated = bvh.clone();
+        repeated.indices[1] = repeated.indices[0];
+        assert!(repeated.validate(bounds.len()).is_err());
+
+        // A child pointing back at its parent would loop forever on the GPU
+        let mut looped = bvh.clone();
+        let interior = looped.nodes.iter().rposition(|n| n.count == 0).unwrap();
+        let child = looped.nodes[interior].first as usize;
+        looped.nodes[child] = Node {
+            first: interior as u32,
+            count: 0,
+            ..looped.nodes[child]
+        };
+        assert!(looped.validate(bounds.len()).is_err());
+    }
+
+    #[test]
+    fn test_build_empty() {
+        let bvh = Bvh::build(&[]);
+        assert!(bvh.nodes.is_empty());
+        assert!(bvh.indices.is_empty());
+    }
+}
@@ -1,6 +1,8 @@
-use clap::{arg, Parser, Subcommand, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
+use crate::loader;
+
 /// A simple raytracing renderer
 #[derive(Debug, Parser)]
 pub struct Cli {
@@ -10,17 +12,186 @@ pub struct Cli {
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum SceneFormat {
+    Ron,
+    Gltf,
+    Obj,
+    Ply,
+    Stl,
+    Rbs,
+}
+
+impl From<SceneFormat> for loader::Format {
+    fn from(format: SceneFormat) -> Self {
+        match format {
+            SceneFormat::Ron => loader::Format::Ron,
+            SceneFormat::Gltf => loader::Format::Gltf,
+            SceneFormat::Obj => loader::Format::Obj,
+            SceneFormat::Ply => loader::Format::Ply,
+            SceneFormat::Stl => loader::Format::Stl,
+            SceneFormat::Rbs => loader::Format::Rbs,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ExportFormat {
+    Ron,
+    /// glTF, written as a binary .glb file when the output has that extension
     Gltf,
+    /// Raybaby's binary scene format, which loads without parsing
+    Rbs,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ImportMode {
+    /// Bake node transforms into the vertices, leaving every object at the origin
+    World,
+    /// Keep meshes in their own space, shared between the objects using them
+    Local,
+}
+
+impl From<ImportMode> for loader::ImportMode {
+    fn from(mode: ImportMode) -> Self {
+        match mode {
+            ImportMode::World => loader::ImportMode::World,
+            ImportMode::Local => loader::ImportMode::Local,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+impl From<UpAxis> for loader::UpAxis {
+    fn from(axis: UpAxis) -> Self {
+        match axis {
+            UpAxis::Y => loader::UpAxis::Y,
+            UpAxis::Z => loader::UpAxis::Z,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Handedness {
+    Left,
+    Right,
+}
+
+impl From<Handedness> for loader::Handedness {
+    fn from(handedness: Handedness) -> Self {
+        match handedness {
+            Handedness::Left => loader::Handedness::Left,
+            Handedness::Right => loader::Handedness::Right,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    BlackmanHarris,
+}
+
+impl Filter {
+    /// The radius, in pixels, that suits the filter when none is given
+    pub fn default_radius(&self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::BlackmanHarris => 2.0,
+        }
+    }
+}
+
+// Must match the FILTER_* constants in the shader
+impl From<Filter> for u32 {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Box => 0,
+            Filter::Tent => 1,
+            Filter::Gaussian => 2,
+            Filter::BlackmanHarris => 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ToneMapping {
+    /// Clip colors brighter than white
+    Clamp,
+    Reinhard,
+    /// A fit of the ACES filmic curve
+    Aces,
+    /// An approximation of Blender's AgX view transform
+    Agx,
+}
+
+// Must match the TONE_MAPPING_* constants in the display shader
+impl From<ToneMapping> for u32 {
+    fn from(tone_mapping: ToneMapping) -> Self {
+        match tone_mapping {
+            ToneMapping::Clamp => 0,
+            ToneMapping::Reinhard => 1,
+            ToneMapping::Aces => 2,
+            ToneMapping::Agx => 3,
+        }
+    }
+}
+
+/// How scenes in other formats are converted into raybaby coordinates
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// Whether node transforms are baked into the geometry or kept on the objects
+    #[arg(long, value_enum, default_value_t = ImportMode::Local)]
+    pub import_mode: ImportMode,
+
+    /// The axis pointing up in the input scene, which becomes +Y
+    #[arg(long, value_enum, default_value_t = UpAxis::Y)]
+    pub up_axis: UpAxis,
+
+    /// The handedness of the input scene's coordinates. Right-handed scenes
+    /// are mirrored along Z into the left-handed raybaby coordinates.
+    #[arg(long, value_enum, default_value_t = Handedness::Right)]
+    pub handedness: Handedness,
+
+    /// The number of raybaby units per unit of the input scene, like 0.01
+    /// for a scene in centimeters
+    #[arg(long, default_value_t = 1.0)]
+    pub unit_scale: f32,
+}
+
+impl From<ImportArgs> for loader::ImportOptions {
+    fn from(args: ImportArgs) -> Self {
+        loader::ImportOptions {
+            mode: args.import_mode.into(),
+            up_axis: args.up_axis.into(),
+            handedness: args.handedness.into(),
+            unit_scale: args.unit_scale,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Render a scene
     Render {
-        /// The .ron scene to render
+        /// The scene to render, in any format that can be converted
         #[arg(long, required = true)]
         scene: PathBuf,
 
+        /// The format of the scene file [default: detected from the file]
+        #[arg(long)]
+        scene_format: Option<SceneFormat>,
+
+        #[command(flatten)]
+        import: ImportArgs,
+
         /// The skybox color
         #[arg(long, default_value_t = Color(0.0, 0.0, 0.0))]
         skybox_color: Color,
@@ -44,16 +215,101 @@ pub enum Commands {
         /// The strength of blur for objects out of focus
         #[arg(long, default_value_t = 200.0)]
         focal_blur_strength: f32,
+
+        /// An equirectangular Radiance .hdr image lighting the scene in place of
+        /// the skybox and ambient lighting
+        #[arg(long)]
+        environment: Option<PathBuf>,
+
+        /// The rotation of the environment around the vertical axis, in degrees
+        #[arg(
+            long,
+            default_value_t = 0.0,
+            requires = "environment",
+            allow_negative_numbers = true
+        )]
+        environment_rotation: f32,
+
+        /// The brightness multiplier of the environment
+        #[arg(long, default_value_t = 1.0, requires = "environment")]
+        environment_intensity: f32,
+
+        /// The filter used to weight samples within a pixel
+        #[arg(long, value_enum, default_value_t = Filter::BlackmanHarris)]
+        filter: Filter,
+
+        /// The radius of the filter in pixels [default: depends on the filter]
+        #[arg(long)]
+        filter_radius: Option<f32>,
+
+        /// The exposure adjustment in stops, applied before tone mapping
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        exposure: f32,
+
+        /// The operator mapping HDR colors to the display
+        #[arg(long, value_enum, default_value_t = ToneMapping::Aces)]
+        tone_mapping: ToneMapping,
+
+        /// The width of the rendered image
+        #[arg(long, default_value_t = 960)]
+        width: u32,
+
+        /// The height of the rendered image
+        #[arg(long, default_value_t = 540)]
+        height: u32,
+
+        /// Render without a window and write the result to this image file
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// The number of samples per pixel to accumulate before stopping, or
+        /// before writing the output image
+        #[arg(long)]
+        samples: Option<u32>,
+
+        /// The maximum number of seconds to spend rendering the output image
+        #[arg(long, requires = "output")]
+        time_limit: Option<f32>,
     },
-    /// Convert a scene to the raybaby format
+    /// Convert a scene to the raybaby format, or export it as glTF
     Convert {
         /// The scene file to convert
         #[arg(required = true)]
         scene: PathBuf,
 
-        /// The type of the input scene file to convert
-        #[arg(long, required = true)]
-        scene_format: SceneFormat,
+        /// The format of the input scene file [default: detected from the file]
+        #[arg(long)]
+        scene_format: Option<SceneFormat>,
+
+        #[command(flatten)]
+        import: ImportArgs,
+
+        /// The format to write [default: from the output's extension, or ron]
+        #[arg(long)]
+        to: Option<ExportFormat>,
+
+        /// The file to write the converted scene to, rather than stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Leave prebuilt BVHs out of binary scenes, making them smaller but
+        /// slower to load
+        #[arg(long)]
+        no_bvh: bool,
+    },
+    /// Check a scene for data that would stop it rendering properly, which is
+    /// also done before every render
+    Validate {
+        /// The scene file to check
+        #[arg(required = true)]
+        scene: PathBuf,
+
+        /// The format of the scene file [default: detected from the file]
+        #[arg(long)]
+        scene_format: Option<SceneFormat>,
+
+        #[command(flatten)]
+        import: ImportArgs,
     },
 }
 
@@ -75,8 +331,8 @@ impl std::fmt::Display for Color {
     }
 }
 
-impl Into<glam::Vec3> for Color {
-    fn into(self) -> glam::Vec3 {
-        glam::vec3(self.0, self.1, self.2)
+impl From<Color> for glam::Vec3 {
+    fn from(color: Color) -> Self {
+        glam::vec3(color.0, color.1, color.2)
     }
 }
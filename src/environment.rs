@@ -0,0 +1,115 @@
+use std::{f32::consts::PI, path::Path};
+
+/// An equirectangular HDR image lighting everything outside the scene, along
+/// with the distributions used to importance sample it by luminance.
+pub struct Environment {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 4]>,
+
+    /// A cumulative distribution over each row's pixels, `width + 1` values per row
+    pub conditional_cdf: Vec<f32>,
+
+    /// A cumulative distribution over the rows, `height + 1` values
+    pub marginal_cdf: Vec<f32>,
+}
+
+impl Environment {
+    /// Loads a Radiance .hdr image
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("Unable to load environment: {}\n  {}", path.display(), e))?
+            .into_rgba32f();
+
+        let (width, height) = image.dimensions();
+        let pixels = image.pixels().map(|p| p.0).collect();
+
+        Ok(Self::from_pixels(width, height, pixels))
+    }
+
+    pub fn from_pixels(width: u32, height: u32, pixels: Vec<[f32; 4]>) -> Self {
+        let (w, h) = (width as usize, height as usize);
+
+        // Rows near the poles cover less of the sphere, so they're weighted by sin(theta)
+        let mut conditional_cdf = Vec::with_capacity((w + 1) * h);
+        let mut row_weights = Vec::with_capacity(h);
+        for (y, row) in pixels.chunks(w).enumerate() {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let weights = row.iter().map(|p| luminance(*p) * sin_theta);
+
+            let total = push_cdf(&mut conditional_cdf, weights);
+            row_weights.push(total);
+        }
+
+        let mut marginal_cdf = Vec::with_capacity(h + 1);
+        push_cdf(&mut marginal_cdf, row_weights.into_iter());
+
+        Self {
+            width,
+            height,
+            pixels,
+            conditional_cdf,
+            marginal_cdf,
+        }
+    }
+}
+
+fn luminance([r, g, b, _]: [f32; 4]) -> f32 {
+    (0.2126 * r + 0.7152 * g + 0.0722 * b).max(0.0)
+}
+
+/// Appends the normalized cumulative distribution of the weights, falling back
+/// to a uniform one when they're all zero. Returns the total weight.
+fn push_cdf(cdf: &mut Vec<f32>, weights: impl ExactSizeIterator<Item = f32>) -> f32 {
+    let count = weights.len();
+    let start = cdf.len();
+
+    cdf.push(0.0);
+    let mut total = 0.0;
+    for weight in weights {
+        total += weight;
+        cdf.push(total);
+    }
+
+    for (i, value) in cdf[start..].iter_mut().enumerate() {
+        *value = if total > 0.0 {
+            *value / total
+        } else {
+            i as f32 / count as f32
+        };
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_distribution_favours_bright_pixels() {
+        let (width, height) = (4, 2);
+        let mut pixels = vec![[0.1; 4]; width * height];
+        pixels[5] = [100.0; 4];
+
+        let environment = Environment::from_pixels(width as u32, height as u32, pixels);
+        assert_eq!(environment.conditional_cdf.len(), (width + 1) * height);
+        assert_eq!(environment.marginal_cdf.len(), height + 1);
+
+        let row = &environment.conditional_cdf[width + 1..];
+        assert!(row.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(row[width], 1.0);
+        assert!(row[2] - row[1] > 0.9);
+        assert!(environment.marginal_cdf[2] - environment.marginal_cdf[1] > 0.9);
+    }
+
+    #[test]
+    fn test_black_environment_is_uniform() {
+        let environment = Environment::from_pixels(2, 2, vec![[0.0; 4]; 4]);
+        assert_eq!(
+            environment.conditional_cdf,
+            vec![0.0, 0.5, 1.0, 0.0, 0.5, 1.0]
+        );
+        assert_eq!(environment.marginal_cdf, vec![0.0, 0.5, 1.0]);
+    }
+}
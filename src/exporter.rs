@@ -0,0 +1,48 @@
+use std::path::Path;
+
+use crate::scene;
+
+pub mod gltf;
+pub mod rbs;
+
+/// The formats scenes can be written in
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    Ron,
+    /// glTF JSON, with its buffer embedded as a data URI
+    Gltf,
+    /// Binary glTF
+    Glb,
+    /// Raybaby's binary scene format, with or without prebuilt BVHs
+    Rbs {
+        bvh: bool,
+    },
+}
+
+impl Format {
+    pub fn from_extension(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "ron" => Some(Format::Ron),
+            "gltf" => Some(Format::Gltf),
+            "glb" => Some(Format::Glb),
+            "rbs" => Some(Format::Rbs { bvh: true }),
+            _ => None,
+        }
+    }
+}
+
+/// Writes a scene out as the contents of a file in the given format
+pub fn export(scene: &scene::Scene, format: Format) -> Result<Vec<u8>, String> {
+    match format {
+        Format::Ron => {
+            let config = ron::ser::PrettyConfig::default().struct_names(true);
+            let source = ron::ser::to_string_pretty(scene, config)
+                .map_err(|e| format!("Unable to serialize scene:\n  {}", e))?;
+
+            Ok((source + "\n").into_bytes())
+        }
+        Format::Gltf => gltf::export(scene, false),
+        Format::Glb => gltf::export(scene, true),
+        Format::Rbs { bvh } => rbs::export(scene, bvh),
+    }
+}
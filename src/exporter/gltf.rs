@@ -0,0 +1,761 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    f32::consts::{PI, TAU},
+    io::Cursor,
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    loader::{geometry, ImportOptions},
+    scene,
+};
+
+/// Marks a node whose mesh stands in for an analytic sphere, so importing the
+/// file gives back the sphere rather than its triangles
+pub const SPHERE_EXTENSION: &str = "RAYBABY_sphere";
+
+// Component types and buffer view targets from the glTF spec
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+const SPHERE_RINGS: u32 = 24;
+const SPHERE_SEGMENTS: u32 = 48;
+
+/// Where an object's triangles come from, so objects with the same geometry
+/// share its accessors
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Geometry {
+    Mesh(String),
+    Object(usize),
+    /// A tessellated sphere, by the bits of its radius
+    Sphere(u32),
+}
+
+/// A glTF document being built up, with a single buffer holding every
+/// accessor and image
+#[derive(Default)]
+struct Builder {
+    buffer: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+    images: Vec<Value>,
+    textures: Vec<Value>,
+    materials: Vec<Value>,
+    meshes: Vec<Value>,
+    nodes: Vec<Value>,
+    lights: Vec<Value>,
+    extensions: BTreeSet<String>,
+
+    /// The attributes and index accessor written for each geometry
+    primitives: HashMap<Geometry, (Value, usize)>,
+    /// glTF meshes by their geometry and material, since materials belong to
+    /// primitives rather than nodes
+    mesh_ids: HashMap<(Geometry, usize), usize>,
+    texture_ids: HashMap<scene::Texture, usize>,
+    material_ids: HashMap<scene::MaterialKey, usize>,
+}
+
+/// Writes a scene as a glTF file, either binary or JSON with its buffer
+/// embedded. Spheres are tessellated, and marked so they import as spheres.
+pub fn export(scene: &scene::Scene, binary: bool) -> Result<Vec<u8>, String> {
+    // Scenes import from glTF's conventions by default, so this undoes that
+    let basis = ImportOptions::default().basis().inverse();
+    let mut builder = Builder::default();
+
+    for (i, object) in scene.objects.iter().enumerate() {
+        let (geometry, transform) = match &object.surface {
+            // Spheres are drawn unrotated, scaled by the larger of their X and Y scale
+            scene::Surface::Sphere { radius } => {
+                let scale = object.transform.scale.x.max(object.transform.scale.y);
+                let transform = glam::Mat4::from_scale_rotation_translation(
+                    glam::Vec3::splat(scale),
+                    glam::Quat::IDENTITY,
+                    object.transform.position,
+                );
+
+                (Geometry::Sphere(radius.to_bits()), transform)
+            }
+            scene::Surface::Mesh { name } => (
+                Geometry::Mesh(name.clone()),
+                glam::Affine3A::from(&object.transform).into(),
+            ),
+            scene::Surface::MeshData { .. } => (
+                Geometry::Object(i),
+                glam::Affine3A::from(&object.transform).into(),
+            ),
+            scene::Surface::MeshFile { path, .. } => {
+                return Err(format!(
+                    "Object {} uses a mesh file that wasn't loaded: {}",
+                    i,
+                    path.display()
+                ))
+            }
+        };
+
+        let primitive = match builder.primitives.get(&geometry) {
+            Some(primitive) => primitive.clone(),
+            None => {
+                let mesh = match &object.surface {
+                    scene::Surface::Sphere { radius } => sphere_mesh(*radius),
+                    scene::Surface::Mesh { name } => scene
+                        .meshes
+                        .get(name)
+                        .cloned()
+                        .ok_or_else(|| format!("Object {} uses an unknown mesh: {}", i, name))?,
+                    scene::Surface::MeshData {
+                        vertices,
+                        indices,
+                        uvs,
+                        normals,
+                        tangents,
+                        colors,
+                    } => scene::Mesh {
+                        vertices: vertices.clone(),
+                        indices: indices.clone(),
+                        uvs: uvs.clone(),
+                        normals: normals.clone(),
+                        tangents: tangents.clone(),
+                        colors: colors.clone(),
+                    },
+                    scene::Surface::MeshFile { .. } => unreachable!(),
+                };
+
+                let primitive = builder.primitive(&geometry::transform_mesh(mesh, basis));
+                builder
+                    .primitives
+                    .insert(geometry.clone(), primitive.clone());
+                primitive
+            }
+        };
+
+        let material = scene
+            .material(&object.material)
+            .map_err(|e| format!("Invalid object at index {}:\n  {}", i, e))?;
+        let material = builder.material(scene, material)?;
+        let key = (geometry, material);
+        let mesh = match builder.mesh_ids.get(&key) {
+            Some(mesh) => *mesh,
+            None => {
+                let (attributes, indices) = primitive;
+                builder.meshes.push(json!({
+                    "primitives": [{
+                        "attributes": attributes,
+                        "indices": indices,
+                        "material": material,
+                    }],
+                }));
+
+                builder
+                    .mesh_ids
+                    .insert(key.clone(), builder.meshes.len() - 1);
+                builder.meshes.len() - 1
+            }
+        };
+
+        let mut node = json!({ "mesh": mesh });
+        set_transform(&mut node, basis * transform * basis.inverse());
+        if let scene::Surface::Sphere { radius } = &object.surface {
+            node["extensions"] = json!({ SPHERE_EXTENSION: { "radius": radius } });
+            builder.extensions.insert(SPHERE_EXTENSION.to_string());
+        }
+
+        builder.nodes.push(node);
+    }
+
+    let camera = &scene.camera;
+    let scene::Lens::Perspective {
+        fov,
+        focal_distance,
+    } = camera.lens;
+    let mut node = json!({ "camera": 0 });
+    set_transform(&mut node, facing(&camera.transform, basis));
+    builder.nodes.push(node);
+
+    for light in &scene.lights {
+        // Back to photometric units, at 683 lumens to the watt
+        let mut value = json!({
+            "color": light.color.to_array(),
+            "intensity": light.intensity * 683.0,
+        });
+
+        value["type"] = match light.kind {
+            scene::LightKind::Point => json!("point"),
+            scene::LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => {
+                value["spot"] = json!({
+                    "innerConeAngle": inner_cone_angle.to_radians(),
+                    "outerConeAngle": outer_cone_angle.to_radians(),
+                });
+
+                json!("spot")
+            }
+            scene::LightKind::Directional => json!("directional"),
+        };
+
+        if let Some(range) = light.range {
+            value["range"] = json!(range);
+        }
+
+        let mut node = json!({
+            "extensions": { "KHR_lights_punctual": { "light": builder.lights.len() } },
+        });
+
+        set_transform(&mut node, facing(&light.transform, basis));
+        builder.nodes.push(node);
+        builder.lights.push(value);
+        builder.extensions.insert("KHR_lights_punctual".to_string());
+    }
+
+    let mut root = json!({
+        "asset": { "version": "2.0", "generator": "raybaby" },
+        "scene": 0,
+        "scenes": [{ "nodes": (0..builder.nodes.len()).collect::<Vec<_>>() }],
+        "nodes": builder.nodes,
+        "cameras": [{
+            "type": "perspective",
+            "perspective": {
+                "yfov": fov.to_radians(),
+                "znear": camera.clipping.near,
+                "zfar": camera.clipping.far,
+            },
+            // glTF has no focus, so it's kept for importing the file again
+            "extras": { "focalDistance": focal_distance },
+        }],
+    });
+
+    // glTF doesn't allow empty arrays, so only write the ones with something in them
+    let arrays = [
+        ("meshes", builder.meshes),
+        ("materials", builder.materials),
+        ("textures", builder.textures),
+        ("images", builder.images),
+        ("accessors", builder.accessors),
+        ("bufferViews", builder.buffer_views),
+    ];
+
+    for (name, values) in arrays {
+        if !values.is_empty() {
+            root[name] = Value::Array(values);
+        }
+    }
+
+    if !builder.lights.is_empty() {
+        root["extensions"] = json!({ "KHR_lights_punctual": { "lights": builder.lights } });
+    }
+
+    if !builder.extensions.is_empty() {
+        root["extensionsUsed"] = json!(builder.extensions);
+    }
+
+    if !builder.buffer.is_empty() {
+        let mut buffer = json!({ "byteLength": builder.buffer.len() });
+        if !binary {
+            let data = base64::encode(&builder.buffer);
+            buffer["uri"] = json!(format!("data:application/octet-stream;base64,{}", data));
+        }
+
+        root["buffers"] = json!([buffer]);
+    }
+
+    if binary {
+        let json =
+            serde_json::to_vec(&root).map_err(|e| format!("Unable to serialize glTF:\n  {}", e))?;
+        Ok(glb(json, builder.buffer))
+    } else {
+        serde_json::to_vec_pretty(&root).map_err(|e| format!("Unable to serialize glTF:\n  {}", e))
+    }
+}
+
+impl Builder {
+    /// Appends bytes to the buffer, aligned for any component type
+    fn view(&mut self, bytes: &[u8], target: Option<u32>) -> usize {
+        self.buffer.resize(self.buffer.len().next_multiple_of(4), 0);
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.buffer.len(),
+            "byteLength": bytes.len(),
+        });
+
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+
+        self.buffer.extend_from_slice(bytes);
+        self.buffer_views.push(view);
+        self.buffer_views.len() - 1
+    }
+
+    fn accessor(&mut self, values: &[f32], kind: &str, width: usize) -> usize {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let view = self.view(&bytes, Some(ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": values.len() / width,
+            "type": kind,
+        }));
+
+        self.accessors.len() - 1
+    }
+
+    /// Writes a mesh's attributes and indices, returning the attributes and
+    /// the index accessor for its primitives
+    fn primitive(&mut self, mesh: &scene::Mesh) -> (Value, usize) {
+        let mut attributes = Map::new();
+
+        let positions: Vec<f32> = mesh.vertices.iter().flat_map(|v| v.to_array()).collect();
+        let position = self.accessor(&positions, "VEC3", 3);
+        let (min, max) = mesh.vertices.iter().fold(
+            (glam::Vec3::splat(f32::INFINITY), glam::Vec3::NEG_INFINITY),
+            |(min, max), v| (min.min(*v), max.max(*v)),
+        );
+
+        // Positions are the one attribute that must have bounds
+        if !mesh.vertices.is_empty() {
+            self.accessors[position]["min"] = json!(min.to_array());
+            self.accessors[position]["max"] = json!(max.to_array());
+        }
+
+        attributes.insert("POSITION".to_string(), json!(position));
+
+        let optional = [
+            (
+                "NORMAL",
+                "VEC3",
+                3,
+                mesh.normals.iter().flat_map(|v| v.to_array()).collect(),
+            ),
+            (
+                "TANGENT",
+                "VEC4",
+                4,
+                mesh.tangents.iter().flat_map(|v| v.to_array()).collect(),
+            ),
+            (
+                "TEXCOORD_0",
+                "VEC2",
+                2,
+                mesh.uvs.iter().flat_map(|v| v.to_array()).collect(),
+            ),
+            (
+                "COLOR_0",
+                "VEC4",
+                4,
+                mesh.colors
+                    .iter()
+                    .flat_map(|v| v.to_array())
+                    .collect::<Vec<_>>(),
+            ),
+        ];
+
+        for (name, kind, width, values) in optional {
+            if !values.is_empty() {
+                let accessor = self.accessor(&values, kind, width);
+                attributes.insert(name.to_string(), json!(accessor));
+            }
+        }
+
+        let indices: Vec<u8> = mesh
+            .indices
+            .iter()
+            .flat_map(|i| i.to_array())
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+
+        let view = self.view(&indices, Some(ELEMENT_ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_INT,
+            "count": mesh.indices.len() * 3,
+            "type": "SCALAR",
+        }));
+
+        (Value::Object(attributes), self.accessors.len() - 1)
+    }
+
+    /// Finds the index of an identical material, writing it the first time
+    fn material(
+        &mut self,
+        scene: &scene::Scene,
+        material: &scene::Material,
+    ) -> Result<usize, String> {
+        let key = scene::MaterialKey::from(material);
+        if let Some(id) = self.material_ids.get(&key) {
+            return Ok(*id);
+        }
+
+        let mut pbr = json!({
+            "baseColorFactor": material.color.to_array(),
+            "metallicFactor": material.metallic,
+            "roughnessFactor": material.roughness,
+        });
+
+        if let Some(texture) = &material.texture {
+            pbr["baseColorTexture"] = json!({ "index": self.texture(scene, texture)? });
+        }
+
+        let mut value = json!({ "pbrMetallicRoughness": pbr });
+        if let Some(texture) = &material.normal_texture {
+            value["normalTexture"] = json!({
+                "index": self.texture(scene, texture)?,
+                "scale": material.normal_scale,
+            });
+        }
+
+        // glTF's emissive factor stops at one, with any more in the strength extension
+        let mut extensions = Map::new();
+        let emission = material.emission();
+        let strength = emission.max_element();
+        if strength > 0.0 {
+            value["emissiveFactor"] = json!((emission / strength.max(1.0)).to_array());
+            if strength > 1.0 {
+                extensions.insert(
+                    "KHR_materials_emissive_strength".to_string(),
+                    json!({ "emissiveStrength": strength }),
+                );
+            }
+        }
+
+        if material.transmission > 0.0 {
+            extensions.insert(
+                "KHR_materials_transmission".to_string(),
+                json!({ "transmissionFactor": material.transmission }),
+            );
+        }
+
+        if material.ior != 1.5 {
+            extensions.insert(
+                "KHR_materials_ior".to_string(),
+                json!({ "ior": material.ior }),
+            );
+        }
+
+        // Absorption is the color after a single unit, so that's the attenuation distance
+        if let Some(absorption) = material.absorption {
+            extensions.insert(
+                "KHR_materials_volume".to_string(),
+                json!({
+                    "attenuationColor": absorption.to_array(),
+                    "attenuationDistance": 1.0,
+                }),
+            );
+        }
+
+        if !extensions.is_empty() {
+            self.extensions.extend(extensions.keys().cloned());
+            value["extensions"] = Value::Object(extensions);
+        }
+
+        self.materials.push(value);
+        self.material_ids.insert(key, self.materials.len() - 1);
+        Ok(self.materials.len() - 1)
+    }
+
+    /// Finds a texture's index, embedding its image as a PNG the first time
+    fn texture(&mut self, scene: &scene::Scene, texture: &scene::Texture) -> Result<usize, String> {
+        if let Some(id) = self.texture_ids.get(texture) {
+            return Ok(*id);
+        }
+
+        let image = match texture {
+            scene::Texture::File(path) => image::open(path)
+                .map_err(|e| format!("Unable to load texture: {}\n  {}", path.display(), e))?
+                .into_rgba8(),
+            scene::Texture::Image(index) => {
+                let image = scene
+                    .images
+                    .get(*index)
+                    .ok_or_else(|| format!("Unknown image: {}", index))?;
+
+                image::RgbaImage::from_raw(image.width, image.height, image.pixels.clone())
+                    .ok_or_else(|| {
+                        format!(
+                            "Image {} doesn't match its size: {}x{}",
+                            index, image.width, image.height
+                        )
+                    })?
+            }
+        };
+
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .map_err(|e| format!("Unable to encode image:\n  {}", e))?;
+
+        let view = self.view(&png, None);
+        self.images
+            .push(json!({ "bufferView": view, "mimeType": "image/png" }));
+        self.textures
+            .push(json!({ "source": self.images.len() - 1 }));
+
+        let id = self.textures.len() - 1;
+        self.texture_ids.insert(texture.clone(), id);
+        Ok(id)
+    }
+}
+
+/// The glTF node transform of a camera or light, which face -Z in glTF where
+/// they face +Z here
+fn facing(transform: &scene::Transform, basis: glam::Mat4) -> glam::Mat4 {
+    let (_, rotation, position) =
+        glam::Mat4::from(glam::Affine3A::from(transform)).to_scale_rotation_translation();
+    let facing_to_node = glam::Mat4::from_scale(glam::vec3(1.0, 1.0, -1.0));
+    basis * glam::Mat4::from_rotation_translation(rotation, position) * facing_to_node
+}
+
+/// Sets a node's translation, rotation and scale, leaving out the ones with no effect
+fn set_transform(node: &mut Value, matrix: glam::Mat4) {
+    let (scale, rotation, translation) = matrix.to_scale_rotation_translation();
+    if translation != glam::Vec3::ZERO {
+        node["translation"] = json!(translation.to_array());
+    }
+
+    if !rotation.abs_diff_eq(glam::Quat::IDENTITY, 1E-7) {
+        node["rotation"] = json!(rotation.to_array());
+    }
+
+    if !scale.abs_diff_eq(glam::Vec3::ONE, 1E-7) {
+        node["scale"] = json!(scale.to_array());
+    }
+}
+
+/// A sphere of triangles, textured the same way as analytic spheres: by
+/// longitude around the Y axis, and latitude down from the top
+fn sphere_mesh(radius: f32) -> scene::Mesh {
+    let mut mesh = scene::Mesh {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        uvs: Vec::new(),
+        normals: Vec::new(),
+        tangents: Vec::new(),
+        colors: Vec::new(),
+    };
+
+    for ring in 0..=SPHERE_RINGS {
+        let v = ring as f32 / SPHERE_RINGS as f32;
+        let (sin_latitude, cos_latitude) = (v * PI).sin_cos();
+        for segment in 0..=SPHERE_SEGMENTS {
+            let u = segment as f32 / SPHERE_SEGMENTS as f32;
+            let (sin_longitude, cos_longitude) = ((u - 0.5) * TAU).sin_cos();
+            let normal = glam::vec3(
+                sin_latitude * cos_longitude,
+                cos_latitude,
+                sin_latitude * sin_longitude,
+            );
+
+            mesh.vertices.push(normal * radius);
+            mesh.normals.push(normal);
+            mesh.uvs.push(glam::vec2(u, v));
+        }
+    }
+
+    // Each quad between two rings is two triangles, except at the poles where
+    // one of them would have no area
+    let columns = SPHERE_SEGMENTS + 1;
+    for ring in 0..SPHERE_RINGS {
+        for segment in 0..SPHERE_SEGMENTS {
+            let top = ring * columns + segment;
+            let bottom = top + columns;
+            if ring > 0 {
+                mesh.indices.push(glam::uvec3(top, top + 1, bottom));
+            }
+
+            if ring < SPHERE_RINGS - 1 {
+                mesh.indices.push(glam::uvec3(top + 1, bottom + 1, bottom));
+            }
+        }
+    }
+
+    mesh
+}
+
+/// Packs a glTF file's JSON and buffer into a GLB file
+fn glb(mut json: Vec<u8>, mut buffer: Vec<u8>) -> Vec<u8> {
+    const JSON: u32 = 0x4E4F534A;
+    const BIN: u32 = 0x004E4942;
+
+    // Chunks are aligned to four bytes, with JSON padded by spaces
+    json.resize(json.len().next_multiple_of(4), b' ');
+    buffer.resize(buffer.len().next_multiple_of(4), 0);
+
+    let mut chunks = vec![(JSON, json)];
+    if !buffer.is_empty() {
+        chunks.push((BIN, buffer));
+    }
+
+    let length = 12 + chunks.iter().map(|(_, data)| 8 + data.len()).sum::<usize>();
+    let mut bytes = Vec::with_capacity(length);
+    bytes.extend_from_slice(b"glTF");
+    bytes.extend(2u32.to_le_bytes());
+    bytes.extend((length as u32).to_le_bytes());
+    for (kind, data) in chunks {
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(kind.to_le_bytes());
+        bytes.extend(data);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{loader::gltf::GltfLoader, traits::SceneLoader};
+
+    fn material(color: glam::Vec4) -> scene::Material {
+        scene::Material {
+            color,
+            luminosity: 0.0,
+            emission_color: None,
+            metallic: 0.0,
+            roughness: 0.5,
+            transmission: 0.0,
+            ior: 1.5,
+            absorption: None,
+            texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+        }
+    }
+
+    fn transform(position: glam::Vec3, rotation: glam::Vec3) -> scene::Transform {
+        scene::Transform {
+            position,
+            rotation,
+            scale: glam::Vec3::ONE,
+        }
+    }
+
+    #[test]
+    fn test_sphere_mesh() {
+        let mesh = sphere_mesh(2.0);
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|v| (v.length() - 2.0).abs() < 1E-5));
+
+        // Every triangle has an area and faces out
+        for triangle in &mesh.indices {
+            let [a, b, c] = triangle.to_array().map(|i| mesh.vertices[i as usize]);
+            let normal = (b - a).cross(c - a);
+            assert!(normal.length() > 0.0);
+            assert!(normal.dot(a + b + c) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let triangle = scene::Mesh {
+            vertices: vec![
+                glam::vec3(0.0, 0.0, 0.0),
+                glam::vec3(0.0, 1.0, 0.0),
+                glam::vec3(1.0, 0.0, 1.0),
+            ],
+            indices: vec![glam::uvec3(0, 1, 2)],
+            uvs: vec![glam::Vec2::ZERO, glam::Vec2::Y, glam::Vec2::ONE],
+            normals: Vec::new(),
+            tangents: Vec::new(),
+            colors: Vec::new(),
+        };
+
+        let mut glowing = material(glam::vec4(1.0, 0.5, 0.25, 1.0));
+        glowing.luminosity = 4.0;
+        glowing.texture = Some(scene::Texture::Image(0));
+
+        let scene = scene::Scene {
+            camera: scene::Camera {
+                transform: transform(glam::vec3(0.0, 1.0, -5.0), glam::vec3(10.0, 20.0, 0.0)),
+                lens: scene::Lens::Perspective {
+                    fov: 45.0,
+                    focal_distance: 3.5,
+                },
+                clipping: scene::Clipping {
+                    near: 0.1,
+                    far: 100.0,
+                },
+            },
+            meshes: [("triangle".to_string(), triangle.clone())].into(),
+            images: vec![scene::Image {
+                width: 1,
+                height: 1,
+                pixels: vec![255, 128, 0, 255],
+            }],
+            materials: Default::default(),
+            objects: vec![
+                scene::Object {
+                    surface: scene::Surface::Sphere { radius: 0.5 },
+                    transform: transform(glam::vec3(1.0, 2.0, 3.0), glam::Vec3::ZERO),
+                    material: material(glam::Vec4::ONE).into(),
+                },
+                scene::Object {
+                    surface: scene::Surface::Mesh {
+                        name: "triangle".to_string(),
+                    },
+                    transform: transform(glam::vec3(-1.0, 0.0, 2.0), glam::vec3(0.0, 30.0, 0.0)),
+                    material: glowing.clone().into(),
+                },
+                scene::Object {
+                    surface: scene::Surface::Mesh {
+                        name: "triangle".to_string(),
+                    },
+                    transform: transform(glam::vec3(1.0, 0.0, 2.0), glam::vec3(0.0, -30.0, 0.0)),
+                    material: material(glam::Vec4::ONE).into(),
+                },
+            ],
+            lights: vec![scene::Light {
+                kind: scene::LightKind::Spot {
+                    inner_cone_angle: 10.0,
+                    outer_cone_angle: 20.0,
+                },
+                transform: transform(glam::vec3(0.0, 4.0, 0.0), glam::vec3(90.0, 0.0, 0.0)),
+                color: glam::Vec3::ONE,
+                intensity: 2.0,
+                range: None,
+            }],
+            bvhs: Default::default(),
+        };
+
+        let path = std::env::temp_dir().join(format!("raybaby-{}.glb", std::process::id()));
+        std::fs::write(&path, export(&scene, true).unwrap()).unwrap();
+        let imported = GltfLoader.load(&path, &ImportOptions::default());
+        std::fs::remove_file(&path).unwrap();
+        let (imported, warnings) = imported.unwrap();
+        assert!(warnings.is_empty());
+
+        // Objects keep their places, and the mesh is still shared between two of them
+        assert_eq!(imported.objects.len(), 3);
+        assert_eq!(imported.meshes.len(), 1);
+        for (object, original) in imported.objects.iter().zip(&scene.objects) {
+            let matrix = glam::Affine3A::from(&object.transform);
+            assert!(matrix.abs_diff_eq(glam::Affine3A::from(&original.transform), 1E-5));
+        }
+
+        assert_eq!(
+            imported.objects[0].surface,
+            scene::Surface::Sphere { radius: 0.5 }
+        );
+
+        let mesh = imported.meshes.values().next().unwrap();
+        assert_eq!(mesh.vertices, triangle.vertices);
+        assert_eq!(mesh.uvs, triangle.uvs);
+
+        let material = imported.material(&imported.objects[1].material).unwrap();
+        assert_eq!(material.color, glowing.color);
+        assert!(material.emission().abs_diff_eq(glowing.emission(), 1E-5));
+        assert_eq!(imported.images, scene.images);
+
+        let camera = &imported.camera;
+        let matrix = glam::Affine3A::from(&camera.transform);
+        assert!(matrix.abs_diff_eq(glam::Affine3A::from(&scene.camera.transform), 1E-5));
+        assert_eq!(camera.lens, scene.camera.lens);
+
+        let light = &imported.lights[0];
+        assert_eq!(light.kind, scene.lights[0].kind);
+        assert!((light.intensity - 2.0).abs() < 1E-5);
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+
+use crate::{
+    bvh,
+    loader::rbs::{Blob, BvhBlobs, MeshBlobs, Metadata, ALIGNMENT, HEADER_SIZE, MAGIC, VERSION},
+    scene,
+};
+
+/// Writes a scene as a binary scene file, with every mesh's arrays stored as
+/// raw blobs rather than text. Inline meshes are moved into the named meshes,
+/// and texture files are embedded, so the file stands alone. With `bvh`, each
+/// mesh's bottom-level BVH is built and stored too.
+pub fn export(scene: &scene::Scene, bvh: bool) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0; HEADER_SIZE];
+    let mut meshes = BTreeMap::new();
+    let mut images = scene.images.clone();
+    let mut embedded: HashMap<PathBuf, usize> = HashMap::new();
+    let mut objects = Vec::with_capacity(scene.objects.len());
+
+    for (name, mesh) in &scene.meshes {
+        let blobs = write_mesh(&mut bytes, mesh, bvh, scene.bvhs.get(name))
+            .map_err(|e| format!("{} (in mesh '{}')", e, name))?;
+        meshes.insert(name.clone(), blobs);
+    }
+
+    for (i, object) in scene.objects.iter().enumerate() {
+        let surface = match &object.surface {
+            scene::Surface::MeshData {
+                vertices,
+                indices,
+                uvs,
+                normals,
+                tangents,
+                colors,
+            } => {
+                let mesh = scene::Mesh {
+                    vertices: vertices.clone(),
+                    indices: indices.clone(),
+                    uvs: uvs.clone(),
+                    normals: normals.clone(),
+                    tangents: tangents.clone(),
+                    colors: colors.clone(),
+                };
+
+                let mut name = format!("object {}", i);
+                while scene.meshes.contains_key(&name) {
+                    name.push('\'');
+                }
+
+                let blobs = write_mesh(&mut bytes, &mesh, bvh, None)
+                    .map_err(|e| format!("Invalid object at index {}:\n  {}", i, e))?;
+                meshes.insert(name.clone(), blobs);
+                scene::Surface::Mesh { name }
+            }
+            scene::Surface::MeshFile { path, .. } => {
+                return Err(format!(
+                    "Object {} uses a mesh file that wasn't loaded: {}",
+                    i,
+                    path.display()
+                ))
+            }
+            surface => surface.clone(),
+        };
+
+        let mut material = object.material.clone();
+        if let scene::MaterialRef::Inline(material) = &mut material {
+            embed_textures(material, &mut images, &mut embedded)?;
+        }
+
+        objects.push(scene::Object {
+            surface,
+            transform: object.transform.clone(),
+            material,
+        });
+    }
+
+    let mut materials = scene.materials.clone();
+    for (name, material) in &mut materials {
+        embed_textures(material, &mut images, &mut embedded)
+            .map_err(|e| format!("{} (in material '{}')", e, name))?;
+    }
+
+    let image_blobs = images
+        .iter_mut()
+        .map(|image| write_blob(&mut bytes, &std::mem::take(&mut image.pixels)))
+        .collect();
+
+    let metadata = Metadata {
+        scene: scene::Scene {
+            camera: scene.camera.clone(),
+            meshes: BTreeMap::new(),
+            images,
+            materials,
+            objects,
+            lights: scene.lights.clone(),
+            bvhs: BTreeMap::new(),
+        },
+        meshes,
+        images: image_blobs,
+    };
+
+    let source =
+        ron::to_string(&metadata).map_err(|e| format!("Unable to serialize scene:\n  {}", e))?;
+    let metadata = write_blob(&mut bytes, source.as_bytes());
+
+    bytes[..8].copy_from_slice(&MAGIC);
+    bytes[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    bytes[16..24].copy_from_slice(&metadata.offset.to_le_bytes());
+    bytes[24..32].copy_from_slice(&metadata.length.to_le_bytes());
+    Ok(bytes)
+}
+
+/// Replaces a material's texture files with images added to the scene, so they
+/// travel with it. Each file is only added once.
+fn embed_textures(
+    material: &mut scene::Material,
+    images: &mut Vec<scene::Image>,
+    embedded: &mut HashMap<PathBuf, usize>,
+) -> Result<(), String> {
+    for texture in material.textures_mut() {
+        if let scene::Texture::File(path) = texture {
+            let index = match embedded.get(path) {
+                Some(index) => *index,
+                None => {
+                    let image = image::open(&*path)
+                        .map_err(|e| {
+                            format!("Unable to load texture: {}\n  {}", path.display(), e)
+                        })?
+                        .into_rgba8();
+
+                    images.push(scene::Image {
+                        width: image.width(),
+                        height: image.height(),
+                        pixels: image.into_raw(),
+                    });
+
+                    embedded.insert(path.clone(), images.len() - 1);
+                    images.len() - 1
+                }
+            };
+
+            *texture = scene::Texture::Image(index);
+        }
+    }
+
+    Ok(())
+}
+
+/// Appends a blob to the file, aligned after the previous one
+fn write_blob(bytes: &mut Vec<u8>, data: &[u8]) -> Blob {
+    bytes.resize(bytes.len().next_multiple_of(ALIGNMENT), 0);
+    let offset = bytes.len() as u64;
+    bytes.extend_from_slice(data);
+    Blob {
+        offset,
+        length: data.len() as u64,
+    }
+}
+
+/// Appends an array of values made of 32-bit words
+fn write_array<const N: usize>(
+    bytes: &mut Vec<u8>,
+    values: impl Iterator<Item = [u32; N]>,
+) -> Blob {
+    let data: Vec<u8> = values.flatten().flat_map(u32::to_le_bytes).collect();
+    write_blob(bytes, &data)
+}
+
+fn floats<const N: usize>(values: [f32; N]) -> [u32; N] {
+    values.map(f32::to_bits)
+}
+
+fn write_mesh(
+    bytes: &mut Vec<u8>,
+    mesh: &scene::Mesh,
+    bvh: bool,
+    prebuilt: Option<&bvh::Bvh>,
+) -> Result<MeshBlobs, String> {
+    let vertex_count = mesh.vertices.len() as u32;
+    if let Some(i) = mesh
+        .indices
+        .iter()
+        .find(|i| i.max_element() >= vertex_count)
+    {
+        return Err(format!(
+            "Triangle {:?} references a vertex out of range (vertex count: {})",
+            i, vertex_count
+        ));
+    }
+
+    let mut blobs = MeshBlobs {
+        vertices: write_array(bytes, mesh.vertices.iter().map(|v| floats(v.to_array()))),
+        indices: write_array(bytes, mesh.indices.iter().map(|i| i.to_array())),
+        uvs: None,
+        normals: None,
+        tangents: None,
+        colors: None,
+        bvh: None,
+    };
+
+    // Leave out attributes the mesh doesn't have, rather than writing empty blobs
+    if !mesh.uvs.is_empty() {
+        blobs.uvs = Some(write_array(
+            bytes,
+            mesh.uvs.iter().map(|v| floats(v.to_array())),
+        ));
+    }
+
+    if !mesh.normals.is_empty() {
+        let normals = mesh.normals.iter().map(|v| floats(v.to_array()));
+        blobs.normals = Some(write_array(bytes, normals));
+    }
+
+    if !mesh.tangents.is_empty() {
+        let tangents = mesh.tangents.iter().map(|v| floats(v.to_array()));
+        blobs.tangents = Some(write_array(bytes, tangents));
+    }
+
+    if !mesh.colors.is_empty() {
+        let colors = mesh.colors.iter().map(|v| floats(v.to_array()));
+        blobs.colors = Some(write_array(bytes, colors));
+    }
+
+    if bvh {
+        let built;
+        let tree = match prebuilt {
+            Some(tree) => tree,
+            None => {
+                built = bvh::Bvh::build_triangles(&mesh.vertices, &mesh.indices);
+                &built
+            }
+        };
+
+        let nodes = tree.nodes.iter().map(|node| {
+            let [min_x, min_y, min_z] = floats(node.bounds.min.to_array());
+            let [max_x, max_y, max_z] = floats(node.bounds.max.to_array());
+            [
+                min_x, min_y, min_z, node.first, max_x, max_y, max_z, node.count,
+            ]
+        });
+
+        blobs.bvh = Some(BvhBlobs {
+            nodes: write_array(bytes, nodes),
+            indices: write_array(bytes, tree.indices.iter().map(|i| [*i])),
+        });
+    }
+
+    Ok(blobs)
+}
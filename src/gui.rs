@@ -1,7 +1,7 @@
 use egui::RichText;
 
 use crate::{
-    app::Timing,
+    app::{self, Timing},
     storage::{Camera, Globals},
 };
 
@@ -9,6 +9,7 @@ pub struct Window {
     info: InfoPane,
     camera: CameraPane,
     environment: EnvironmentPane,
+    display: DisplayPane,
 }
 
 impl Window {
@@ -17,6 +18,7 @@ impl Window {
             info: InfoPane,
             camera: CameraPane,
             environment: EnvironmentPane,
+            display: DisplayPane,
         }
     }
 
@@ -38,6 +40,12 @@ impl Window {
             .show(ctx, |ui: &mut egui::Ui| {
                 self.environment.ui(ui, globals);
             });
+
+        egui::Window::new("Display")
+            .default_open(true)
+            .show(ctx, |ui: &mut egui::Ui| {
+                self.display.ui(ui, globals);
+            });
     }
 }
 
@@ -53,6 +61,18 @@ impl InfoPane {
 
             ui.label("Frames");
             ui.label(RichText::new(format!("{}", globals.frame)).monospace());
+
+            ui.end_row();
+
+            let samples = app::accumulated_samples(globals);
+            ui.label("Samples");
+            ui.label(
+                RichText::new(match globals.target_samples {
+                    0 => format!("{}", samples),
+                    target => format!("{} / {}", samples, target),
+                })
+                .monospace(),
+            );
         });
     }
 }
@@ -122,6 +142,56 @@ impl EnvironmentPane {
             ui.label("Ambient lighting strength");
             ui.add(egui::DragValue::new(&mut globals.ambient_lighting_strength).speed(0.1));
         });
+
+        if globals.has_environment != 0 {
+            draw_section(ui, "Environment map", |ui| {
+                ui.label("Intensity");
+                ui.add(
+                    egui::DragValue::new(&mut globals.environment_intensity)
+                        .speed(0.05)
+                        .clamp_range(0.0..=f32::MAX),
+                );
+
+                ui.end_row();
+
+                ui.label("Rotation");
+                ui.drag_angle(&mut globals.environment_rotation);
+            });
+        }
+    }
+}
+
+struct DisplayPane;
+
+impl DisplayPane {
+    // Must match the TONE_MAPPING_* constants in the display shader
+    const TONE_MAPPING_NAMES: [&'static str; 4] = ["Clamp", "Reinhard", "ACES", "AgX"];
+
+    fn ui(&mut self, ui: &mut egui::Ui, globals: &mut Globals) {
+        draw_section(ui, "Tone mapping", |ui| {
+            ui.label("Exposure");
+            ui.add(
+                egui::DragValue::new(&mut globals.exposure)
+                    .speed(0.05)
+                    .suffix(" EV"),
+            );
+
+            ui.end_row();
+
+            ui.label("Operator");
+            egui::ComboBox::from_id_source("Operator")
+                .selected_text(
+                    Self::TONE_MAPPING_NAMES
+                        .get(globals.tone_mapping as usize)
+                        .copied()
+                        .unwrap_or_default(),
+                )
+                .show_ui(ui, |ui| {
+                    for (i, name) in Self::TONE_MAPPING_NAMES.iter().enumerate() {
+                        ui.selectable_value(&mut globals.tone_mapping, i as u32, *name);
+                    }
+                });
+        });
     }
 }
 
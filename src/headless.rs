@@ -0,0 +1,330 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    app::{self, EnvironmentData, Parameters, Renderer},
+    environment::Environment,
+    scene, storage,
+};
+
+// The blit pass writes sRGB encoded colors, which is what image files expect
+const OUTPUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+pub struct Options {
+    pub output: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub time_limit: Option<Duration>,
+}
+
+/// Renders the scene offscreen until the sample count or time limit is reached,
+/// then writes the result to the output image.
+pub async fn render(
+    scene: &scene::Scene,
+    environment: Option<&Environment>,
+    parameters: &Parameters,
+    options: &Options,
+) -> Result<(), String> {
+    if parameters.target_samples.is_none() && options.time_limit.is_none() {
+        return Err("Rendering to an output image requires --samples or --time-limit".to_string());
+    }
+
+    let start = Instant::now();
+    let (image, samples) = render_image(scene, environment, parameters, options).await?;
+    image.save(&options.output).map_err(|e| {
+        format!(
+            "Unable to write image: {}\n  {}",
+            options.output.display(),
+            e
+        )
+    })?;
+
+    eprintln!(
+        "Rendered {} samples per pixel in {:.2}s to {}",
+        samples,
+        start.elapsed().as_secs_f32(),
+        options.output.display()
+    );
+
+    Ok(())
+}
+
+/// Renders the scene offscreen until the sample count or time limit is
+/// reached, returning the image and the samples per pixel it accumulated
+async fn render_image(
+    scene: &scene::Scene,
+    environment: Option<&Environment>,
+    parameters: &Parameters,
+    options: &Options,
+) -> Result<(image::RgbaImage, u32), String> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        dx12_shader_compiler: Default::default(),
+    });
+
+    let adapter = app::request_adapter(&instance, None)
+        .await
+        .ok_or_else(|| "Unable to find a graphics adapter".to_string())?;
+
+    let (device, queue) = app::request_device(&adapter)
+        .await
+        .map_err(|e| format!("Unable to create graphics device:\n  {}", e))?;
+
+    let (width, height) = (options.width, options.height);
+    let mut globals = app::create_globals(scene, environment, parameters, width, height);
+    if app::samples_per_frame(&globals) == 0 {
+        return Err("Rendering requires at least one sample per pixel per frame".to_string());
+    }
+
+    let environment = EnvironmentData::new(&device, &queue, environment)?;
+    let renderer = Renderer::new(
+        &device,
+        &queue,
+        scene,
+        environment,
+        &globals,
+        (width, height),
+        OUTPUT_FORMAT,
+    )?;
+
+    let target = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Headless target texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: OUTPUT_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+
+    let start = Instant::now();
+    loop {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Headless Compute Encoder"),
+        });
+
+        renderer.encode_compute_pass(&device, &mut encoder, &globals, width, height);
+        queue.submit(std::iter::once(encoder.finish()));
+        device.poll(wgpu::Maintain::Wait);
+
+        globals.frame += 1;
+
+        let time_reached = options
+            .time_limit
+            .is_some_and(|limit| start.elapsed() >= limit);
+
+        if app::is_converged(&globals) || time_reached {
+            break;
+        }
+    }
+
+    let pixels = read_target(&device, &queue, &renderer, &globals, &target, width, height);
+    let image = image::RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| "Rendered image has an unexpected size".to_string())?;
+
+    Ok((image, app::accumulated_samples(&globals)))
+}
+
+fn read_target(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    renderer: &Renderer,
+    globals: &storage::Globals,
+    target: &wgpu::Texture,
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    let unpadded_bytes_per_row = width * 4;
+    let padded_bytes_per_row = {
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        unpadded_bytes_per_row.div_ceil(alignment) * alignment
+    };
+
+    let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback buffer"),
+        size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Headless Render Encoder"),
+    });
+
+    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+    renderer.encode_render_pass(device, &mut encoder, globals, &view);
+
+    encoder.copy_texture_to_buffer(
+        target.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &readback_buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_bytes_per_row),
+                rows_per_image: Some(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = readback_buffer.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |result| {
+        result.expect("Unable to map readback buffer");
+    });
+
+    device.poll(wgpu::Maintain::Wait);
+
+    let data = slice.get_mapped_range();
+    let pixels = data
+        .chunks(padded_bytes_per_row as usize)
+        .flat_map(|row| &row[..unpadded_bytes_per_row as usize])
+        .copied()
+        .collect();
+
+    drop(data);
+    readback_buffer.unmap();
+
+    pixels
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_white_furnace() {
+        // A white metal sphere under a uniform environment reflects all the
+        // light reaching it, so it should vanish into the background
+        let transform = |z| scene::Transform {
+            position: glam::vec3(0.0, 0.0, z),
+            rotation: glam::Vec3::ZERO,
+            scale: glam::Vec3::ONE,
+        };
+
+        let material = scene::Material {
+            color: glam::Vec4::ONE,
+            luminosity: 0.0,
+            emission_color: None,
+            metallic: 1.0,
+            roughness: 0.2,
+            transmission: 0.0,
+            ior: 1.5,
+            absorption: None,
+            texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+        };
+
+        let scene = scene::Scene {
+            camera: scene::Camera {
+                transform: transform(0.0),
+                lens: scene::Lens::Perspective {
+                    fov: 30.0,
+                    focal_distance: 4.0,
+                },
+                clipping: scene::Clipping {
+                    near: 0.1,
+                    far: 100.0,
+                },
+            },
+            meshes: Default::default(),
+            images: Vec::new(),
+            materials: Default::default(),
+            objects: vec![scene::Object {
+                surface: scene::Surface::Sphere { radius: 1.0 },
+                transform: transform(5.0),
+                material: material.into(),
+            }],
+            lights: Vec::new(),
+            bvhs: Default::default(),
+        };
+
+        let environment = Environment::from_pixels(4, 2, vec![[0.5, 0.5, 0.5, 1.0]; 8]);
+        let parameters = Parameters {
+            frame: 0,
+            random_seed: 0,
+            max_ray_bounces: 30,
+            max_samples_per_pixel: 16,
+            skybox_color: glam::Vec3::ZERO,
+            ambient_lighting_color: glam::Vec3::ONE,
+            ambient_lighting_strength: 0.0,
+            focal_blur_strength: 0.0,
+            reconstruction_filter: 0,
+            filter_radius: 0.5,
+            environment_intensity: 1.0,
+            environment_rotation: 0.0,
+            target_samples: Some(256),
+            exposure: 0.0,
+            tone_mapping: 0,
+        };
+
+        let options = Options {
+            output: PathBuf::new(),
+            width: 32,
+            height: 32,
+            time_limit: None,
+        };
+
+        let rendered = pollster::block_on(render_image(
+            &scene,
+            Some(&environment),
+            &parameters,
+            &options,
+        ));
+
+        // Machines without any graphics adapter, not even a software one, can't run this
+        let image = match rendered {
+            Ok((image, _)) => image,
+            Err(e) if e.starts_with("Unable to find a graphics adapter") => return,
+            Err(e) => panic!("{}", e),
+        };
+
+        // Averaged in linear color, since the image is sRGB encoded
+        let average = |pixels: &mut dyn Iterator<Item = &image::Rgba<u8>>| {
+            let values: Vec<f32> = pixels
+                .map(|p| {
+                    let c = p.0[0] as f32 / 255.0;
+                    if c <= 0.04045 {
+                        c / 12.92
+                    } else {
+                        ((c + 0.055) / 1.055).powf(2.4)
+                    }
+                })
+                .collect();
+            values.iter().sum::<f32>() / values.len() as f32
+        };
+
+        let background = average(&mut image.rows().take(2).flatten());
+        let sphere = average(
+            &mut image
+                .enumerate_pixels()
+                .filter(|(x, y, _)| {
+                    let offset = glam::vec2(*x as f32 + 0.5, *y as f32 + 0.5) - 16.0;
+                    offset.length() < 11.0
+                })
+                .map(|(_, _, p)| p),
+        );
+
+        // Single scattering GGX loses a fraction of a percent at this
+        // roughness, where biased paths came out over a percent darker
+        assert!(
+            (sphere - background).abs() < background * 0.0075,
+            "sphere: {}, background: {}",
+            sphere,
+            background
+        );
+    }
+}
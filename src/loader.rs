@@ -1 +1,310 @@
+use std::{fmt, fs, io::Read, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{scene, traits::SceneLoader};
+
+pub mod geometry;
 pub mod gltf;
+pub mod obj;
+pub mod ply;
+pub mod rbs;
+pub mod ron;
+pub mod stl;
+
+/// The formats scenes can be loaded from
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
+pub enum Format {
+    Ron,
+    Gltf,
+    Obj,
+    Ply,
+    Stl,
+    Rbs,
+}
+
+impl Format {
+    /// Every format, in the order they're tried when recognising a file by its
+    /// contents. Those with a distinct header come before those guessed from
+    /// their first statement.
+    const ALL: [Format; 6] = [
+        Format::Rbs,
+        Format::Gltf,
+        Format::Ply,
+        Format::Stl,
+        Format::Ron,
+        Format::Obj,
+    ];
+
+    pub fn loader(self) -> &'static dyn SceneLoader {
+        match self {
+            Format::Ron => &ron::RonLoader,
+            Format::Gltf => &gltf::GltfLoader,
+            Format::Obj => &obj::ObjLoader,
+            Format::Ply => &ply::PlyLoader,
+            Format::Stl => &stl::StlLoader,
+            Format::Rbs => &rbs::RbsLoader,
+        }
+    }
+
+    /// Finds a file's format from its extension, or from its first bytes when
+    /// the extension is missing or unknown
+    pub fn detect(path: &Path) -> Result<Format, String> {
+        if let Some(format) = Format::from_extension(path) {
+            return Ok(format);
+        }
+
+        let read = |e| format!("Unable to read file: {}\n  {}", path.display(), e);
+        let mut file = fs::File::open(path).map_err(read)?;
+        let size = file.metadata().map_err(read)?.len();
+        let mut start = Vec::new();
+        file.by_ref()
+            .take(1024)
+            .read_to_end(&mut start)
+            .map_err(read)?;
+
+        Format::from_contents(&start, size).ok_or_else(|| {
+            format!(
+                "Unable to recognise the format of {}, try passing --scene-format",
+                path.display()
+            )
+        })
+    }
+
+    fn from_extension(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        Format::ALL
+            .into_iter()
+            .find(|format| format.loader().extensions().contains(&extension.as_str()))
+    }
+
+    fn from_contents(start: &[u8], size: u64) -> Option<Format> {
+        Format::ALL
+            .into_iter()
+            .find(|format| format.loader().matches(start, size))
+    }
+}
+
+/// Loads a scene file, detecting its format unless one is given
+pub fn load(
+    path: &Path,
+    format: Option<Format>,
+    options: &ImportOptions,
+) -> Result<(scene::Scene, Vec<Warning>), String> {
+    let format = match format {
+        Some(format) => format,
+        None => Format::detect(path)?,
+    };
+
+    let (scene, warnings) = format.loader().load(path, options)?;
+    scene.check_materials()?;
+    Ok((scene, warnings))
+}
+
+/// Something in a source file that couldn't be imported as it was
+#[derive(Clone, PartialEq, Debug)]
+pub struct Warning {
+    /// Where the problem is in the source file, like `mesh 'Cube', primitive 1`
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Whether node transforms are applied to the geometry or kept on the objects
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ImportMode {
+    /// Bake each node's transform into its vertices, leaving objects at the origin
+    World,
+    /// Keep each mesh in its own space, shared by every object using it, and
+    /// place objects with their node's transform
+    Local,
+}
+
+/// The axis pointing up in the source file
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+/// The handedness of the source file's coordinate system. Scenes here are
+/// left-handed: +X is right, +Y is up and cameras look along +Z.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Handedness {
+    Left,
+    Right,
+}
+
+/// How a source file's coordinates are converted into the scene's
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ImportOptions {
+    pub mode: ImportMode,
+    pub up_axis: UpAxis,
+    pub handedness: Handedness,
+
+    /// Scene units per source unit, like 0.01 for a file in centimeters
+    pub unit_scale: f32,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            mode: ImportMode::Local,
+            up_axis: UpAxis::Y,
+            handedness: Handedness::Right,
+            unit_scale: 1.0,
+        }
+    }
+}
+
+impl ImportOptions {
+    /// The change of basis from source coordinates to scene coordinates
+    pub fn basis(&self) -> glam::Mat4 {
+        // Rotates Z onto Y and Y onto -Z, keeping the handedness
+        let up = match self.up_axis {
+            UpAxis::Y => glam::Mat4::IDENTITY,
+            UpAxis::Z => glam::Mat4::from_rotation_x(-std::f32::consts::FRAC_PI_2),
+        };
+
+        let mirror = match self.handedness {
+            Handedness::Left => glam::Mat4::IDENTITY,
+            Handedness::Right => glam::Mat4::from_scale(glam::vec3(1.0, 1.0, -1.0)),
+        };
+
+        glam::Mat4::from_scale(glam::Vec3::splat(self.unit_scale)) * mirror * up
+    }
+}
+
+/// A camera looking slightly down at the middle of a scene's bounds, from far
+/// enough away to see all of it, for formats that don't have cameras
+pub fn framing_camera(min: glam::Vec3, max: glam::Vec3) -> scene::Camera {
+    const FOV: f32 = 40.0;
+    const PITCH: f32 = 20.0;
+
+    // Empty scenes have inverted bounds
+    let (min, max) = if min.cmple(max).all() {
+        (min, max)
+    } else {
+        (glam::Vec3::ZERO, glam::Vec3::ZERO)
+    };
+
+    let center = (min + max) / 2.0;
+    let radius = ((max - min).length() / 2.0).max(1E-3);
+    let distance = radius / (FOV / 2.0).to_radians().sin();
+    let forward = glam::Quat::from_rotation_x(PITCH.to_radians()) * glam::Vec3::Z;
+
+    scene::Camera {
+        transform: scene::Transform {
+            position: center - forward * distance,
+            rotation: glam::vec3(PITCH, 0.0, 0.0),
+            scale: glam::Vec3::ONE,
+        },
+        lens: scene::Lens::Perspective {
+            fov: FOV,
+            focal_distance: distance,
+        },
+        clipping: scene::Clipping {
+            near: (distance * 1E-3).min(0.1),
+            far: ((distance + radius) * 2.0).max(2000.0),
+        },
+    }
+}
+
+/// A plain gray material, for formats without materials or surfaces without one
+fn default_material() -> scene::Material {
+    scene::Material {
+        color: glam::vec4(0.8, 0.8, 0.8, 1.0),
+        luminosity: 0.0,
+        emission_color: None,
+        metallic: 0.0,
+        roughness: 1.0,
+        transmission: 0.0,
+        ior: 1.5,
+        absorption: None,
+        texture: None,
+        normal_texture: None,
+        normal_scale: 1.0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        let by_extension = |path: &str| Format::from_extension(Path::new(path));
+        assert_eq!(by_extension("scene.ron"), Some(Format::Ron));
+        assert_eq!(by_extension("dir.v2/Model.GLB"), Some(Format::Gltf));
+        assert_eq!(by_extension("model.fbx"), None);
+        assert_eq!(by_extension("model"), None);
+
+        let by_contents = |start: &[u8]| Format::from_contents(start, start.len() as u64);
+        assert_eq!(by_contents(b"glTF\x02\0\0\0"), Some(Format::Gltf));
+        assert_eq!(
+            by_contents(b" {\n  \"asset\": {\"version\": \"2.0\"}}"),
+            Some(Format::Gltf)
+        );
+        assert_eq!(by_contents(b"ply\nformat ascii 1.0\n"), Some(Format::Ply));
+        assert_eq!(by_contents(b"solid cube\n"), Some(Format::Stl));
+        assert_eq!(
+            by_contents(b"// A comment\n#![enable(implicit_some)]\nScene (\n"),
+            Some(Format::Ron)
+        );
+        assert_eq!(by_contents(b"(camera: ("), Some(Format::Ron));
+        assert_eq!(
+            by_contents(b"# Exported\n\nmtllib a.mtl\nv 0 0 0\n"),
+            Some(Format::Obj)
+        );
+        assert_eq!(by_contents(b"Hello"), None);
+
+        // Binary STL files are only recognised by their size matching their facet count
+        let mut stl = vec![0; 84];
+        stl[80] = 2;
+        assert_eq!(Format::from_contents(&stl, 184), Some(Format::Stl));
+        assert_eq!(Format::from_contents(&stl, 185), None);
+    }
+
+    #[test]
+    fn test_basis() {
+        let convert = |options: ImportOptions, point| options.basis().transform_point3(point);
+
+        // Right-handed Y-up, as in glTF: forward (-Z) stays forward (+Z)
+        let gltf = ImportOptions::default();
+        assert_eq!(
+            convert(gltf, glam::vec3(1.0, 2.0, -3.0)),
+            glam::vec3(1.0, 2.0, 3.0)
+        );
+
+        // Right-handed Z-up in centimeters, as in many modelling tools: Y is forward
+        let z_up = ImportOptions {
+            up_axis: UpAxis::Z,
+            unit_scale: 0.01,
+            ..Default::default()
+        };
+
+        let point = convert(z_up, glam::vec3(100.0, 200.0, 300.0));
+        assert!(point.abs_diff_eq(glam::vec3(1.0, 3.0, 2.0), 1E-6));
+    }
+
+    #[test]
+    fn test_framing_camera() {
+        let (min, max) = (glam::vec3(-1.0, 0.0, 4.0), glam::vec3(1.0, 2.0, 6.0));
+        let camera = framing_camera(min, max);
+
+        // The camera looks at the center, with the bounding sphere just filling its view
+        let position = camera.transform.position;
+        let forward = glam::Affine3A::from(&camera.transform).transform_vector3(glam::Vec3::Z);
+        let to_center = glam::vec3(0.0, 1.0, 5.0) - position;
+        assert!(to_center.normalize().abs_diff_eq(forward, 1E-5));
+
+        let radius = (max - min).length() / 2.0;
+        let scene::Lens::Perspective { fov, .. } = camera.lens;
+        assert!((radius / to_center.length() - (fov / 2.0).to_radians().sin()).abs() < 1E-5);
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+
+use glam::{f32, u32};
+
+use crate::scene;
+
+/// Creases sharper than this many degrees stay faceted when generating normals
+pub const DEFAULT_SMOOTHING_ANGLE: f32 = 30.0;
+
+/// A mesh whose vertices have been split wherever generated normals differ
+pub struct SmoothedMesh {
+    /// The original vertex each new vertex was split from, to copy its other attributes
+    pub sources: Vec<u32>,
+    pub indices: Vec<u32::UVec3>,
+    pub normals: Vec<f32::Vec3>,
+}
+
+/// Generates vertex normals by averaging the normals of the faces around each
+/// vertex, weighted by area. Faces meeting at more than `smoothing_angle`
+/// degrees don't share normals, so hard edges stay sharp. Vertices at the same
+/// position are welded first, since exporters often split them along UV seams.
+pub fn smooth_normals(
+    vertices: &[f32::Vec3],
+    indices: &[u32::UVec3],
+    smoothing_angle: f32,
+) -> SmoothedMesh {
+    let cos_threshold = smoothing_angle.to_radians().cos();
+
+    // Unnormalized, so larger faces contribute more to the average
+    let face_normals: Vec<f32::Vec3> = indices
+        .iter()
+        .map(|i| {
+            let [a, b, c] = i.to_array().map(|i| vertices[i as usize]);
+            (b - a).cross(c - a)
+        })
+        .collect();
+
+    let mut faces_at_position: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+    for (face, i) in indices.iter().enumerate() {
+        for index in i.to_array() {
+            let key = vertices[index as usize].to_array().map(f32::to_bits);
+            faces_at_position.entry(key).or_default().push(face);
+        }
+    }
+
+    let mut mesh = SmoothedMesh {
+        sources: Vec::new(),
+        indices: Vec::with_capacity(indices.len()),
+        normals: Vec::new(),
+    };
+
+    // Corners with the same source vertex and normal share a new vertex
+    let mut new_vertices: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+    for (face, i) in indices.iter().enumerate() {
+        let face_direction = face_normals[face].normalize_or_zero();
+        let corners = i.to_array().map(|index| {
+            let key = vertices[index as usize].to_array().map(f32::to_bits);
+            let normal = faces_at_position[&key]
+                .iter()
+                .map(|other| face_normals[*other])
+                .filter(|other| other.normalize_or_zero().dot(face_direction) >= cos_threshold)
+                .sum::<f32::Vec3>()
+                .normalize_or_zero();
+
+            *new_vertices
+                .entry((index, normal.to_array().map(f32::to_bits)))
+                .or_insert_with(|| {
+                    mesh.sources.push(index);
+                    mesh.normals.push(normal);
+                    mesh.sources.len() as u32 - 1
+                })
+        });
+
+        mesh.indices.push(u32::UVec3::from_array(corners));
+    }
+
+    mesh
+}
+
+/// Generates tangents for normal mapping in the MikkTSpace convention glTF
+/// uses: `xyz` points along increasing U and the bitangent is
+/// `cross(normal, xyz) * w`. Each face's tangent frame is found from its UVs and
+/// averaged into its vertices, weighted by the angle of the corner. Like
+/// MikkTSpace, vertices are split where faces with mirrored UVs meet, so
+/// frames of opposite handedness are never averaged together.
+pub fn generate_tangents(mesh: scene::Mesh) -> scene::Mesh {
+    let mut sources: Vec<usize> = Vec::new();
+    let mut tangents: Vec<f32::Vec3> = Vec::new();
+    let mut bitangents: Vec<f32::Vec3> = Vec::new();
+    let mut handedness: Vec<f32> = Vec::new();
+    let mut indices = Vec::with_capacity(mesh.indices.len());
+
+    // Corners with the same source vertex and handedness share a new vertex
+    let mut new_vertices: HashMap<(u32, bool), u32> = HashMap::new();
+    for i in &mesh.indices {
+        let corners = i.to_array().map(|i| i as usize);
+        let [a, b, c] = corners.map(|i| mesh.vertices[i]);
+
+        // glTF's V points down the image, but normal maps expect the bitangent to point up it
+        let [uv_a, uv_b, uv_c] = corners.map(|i| mesh.uvs[i] * f32::vec2(1.0, -1.0));
+
+        let (edge_ab, edge_ac) = (b - a, c - a);
+        let (delta_ab, delta_ac) = (uv_b - uv_a, uv_c - uv_a);
+        let determinant = delta_ab.x * delta_ac.y - delta_ac.x * delta_ab.y;
+        let degenerate = determinant.abs() < 1E-12;
+
+        // Faces whose UVs are mirrored have a negative determinant
+        let (tangent, bitangent) = if degenerate {
+            (f32::Vec3::ZERO, f32::Vec3::ZERO)
+        } else {
+            (
+                (edge_ab * delta_ac.y - edge_ac * delta_ab.y) / determinant,
+                (edge_ac * delta_ab.x - edge_ab * delta_ac.x) / determinant,
+            )
+        };
+
+        let positions = [a, b, c];
+        let new_corners = std::array::from_fn(|corner| {
+            let source = corners[corner];
+            let right_handed = degenerate || determinant > 0.0;
+            let vertex = *new_vertices
+                .entry((source as u32, right_handed))
+                .or_insert_with(|| {
+                    sources.push(source);
+                    tangents.push(f32::Vec3::ZERO);
+                    bitangents.push(f32::Vec3::ZERO);
+                    handedness.push(if right_handed { 1.0 } else { -1.0 });
+                    sources.len() as u32 - 1
+                });
+
+            let position = positions[corner];
+            let next = positions[(corner + 1) % 3] - position;
+            let previous = positions[(corner + 2) % 3] - position;
+            let angle = next.angle_between(previous);
+            if angle.is_finite() {
+                tangents[vertex as usize] += tangent * angle;
+                bitangents[vertex as usize] += bitangent * angle;
+            }
+
+            vertex
+        });
+
+        indices.push(u32::UVec3::from_array(new_corners));
+    }
+
+    let mut mesh = mesh;
+    remap(&mut mesh.vertices, &sources);
+    remap(&mut mesh.uvs, &sources);
+    remap(&mut mesh.normals, &sources);
+    remap(&mut mesh.colors, &sources);
+    mesh.indices = indices;
+    mesh.tangents = tangents
+        .iter()
+        .zip(&mesh.normals)
+        .zip(handedness)
+        .map(|((tangent, normal), handedness)| {
+            // Make the tangent perpendicular to the normal, falling back to any
+            // perpendicular direction where the UVs are degenerate
+            let tangent = (*tangent - *normal * normal.dot(*tangent))
+                .try_normalize()
+                .unwrap_or_else(|| normal.any_orthonormal_vector());
+
+            tangent.extend(handedness)
+        })
+        .collect();
+
+    mesh
+}
+
+/// Moves a mesh from source coordinates into the scene's, reversing the
+/// winding of mirrored faces so they still face out, and smooths normals for
+/// meshes without them
+pub fn convert_mesh(mesh: scene::Mesh, transform: f32::Mat4) -> scene::Mesh {
+    let mut mesh = transform_mesh(mesh, transform);
+    if mesh.normals.is_empty() {
+        let smoothed = smooth_normals(&mesh.vertices, &mesh.indices, DEFAULT_SMOOTHING_ANGLE);
+        let sources: Vec<usize> = smoothed.sources.iter().map(|i| *i as usize).collect();
+        remap(&mut mesh.vertices, &sources);
+        remap(&mut mesh.uvs, &sources);
+        remap(&mut mesh.tangents, &sources);
+        remap(&mut mesh.colors, &sources);
+        mesh.indices = smoothed.indices;
+        mesh.normals = smoothed.normals;
+    }
+
+    mesh
+}
+
+/// Replaces a vertex attribute with the values of each new vertex's source,
+/// leaving attributes the mesh doesn't have empty
+fn remap<T: Copy>(values: &mut Vec<T>, sources: &[usize]) {
+    if !values.is_empty() {
+        *values = sources.iter().map(|i| values[*i]).collect();
+    }
+}
+
+/// Transforms a mesh's vertices, normals and tangents, reversing the winding
+/// of mirrored faces so they still face out
+pub fn transform_mesh(mut mesh: scene::Mesh, transform: f32::Mat4) -> scene::Mesh {
+    let mirrored = transform.determinant() < 0.0;
+    if mirrored {
+        for i in &mut mesh.indices {
+            *i = u32::uvec3(i.x, i.z, i.y);
+        }
+    }
+
+    for vertex in &mut mesh.vertices {
+        *vertex = transform.transform_point3(*vertex);
+    }
+
+    // Normals transform by the inverse transpose to stay perpendicular to scaled surfaces
+    let normal_matrix = f32::Mat3::from_mat4(transform).inverse().transpose();
+    for normal in &mut mesh.normals {
+        *normal = (normal_matrix * *normal).normalize_or_zero();
+    }
+
+    for tangent in &mut mesh.tangents {
+        let direction = transform
+            .transform_vector3(tangent.truncate())
+            .normalize_or_zero();
+        let handedness = if mirrored { -tangent.w } else { tangent.w };
+        *tangent = direction.extend(handedness);
+    }
+
+    mesh
+}
+
+/// Joins meshes into one. Attributes are only kept if every mesh has them.
+pub fn merge_meshes(meshes: Vec<scene::Mesh>) -> scene::Mesh {
+    fn all<T>(meshes: &[scene::Mesh], attribute: fn(&scene::Mesh) -> &Vec<T>) -> bool {
+        meshes.iter().all(|mesh| !attribute(mesh).is_empty())
+    }
+
+    let uvs = all(&meshes, |mesh| &mesh.uvs);
+    let normals = all(&meshes, |mesh| &mesh.normals);
+    let tangents = all(&meshes, |mesh| &mesh.tangents);
+    let colors = all(&meshes, |mesh| &mesh.colors);
+
+    let mut merged = scene::Mesh {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        uvs: Vec::new(),
+        normals: Vec::new(),
+        tangents: Vec::new(),
+        colors: Vec::new(),
+    };
+
+    for mesh in meshes {
+        let offset = merged.vertices.len() as u32;
+        merged.vertices.extend(mesh.vertices);
+        merged
+            .indices
+            .extend(mesh.indices.iter().map(|i| *i + offset));
+
+        if uvs {
+            merged.uvs.extend(mesh.uvs);
+        }
+
+        if normals {
+            merged.normals.extend(mesh.normals);
+        }
+
+        if tangents {
+            merged.tangents.extend(mesh.tangents);
+        }
+
+        if colors {
+            merged.colors.extend(mesh.colors);
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Two squares meeting at a right angle along the x axis
+    fn fold() -> (Vec<f32::Vec3>, Vec<u32::UVec3>) {
+        let vertices = vec![
+            f32::vec3(0.0, 0.0, 0.0),
+            f32::vec3(1.0, 0.0, 0.0),
+            f32::vec3(1.0, 0.0, 1.0),
+            f32::vec3(0.0, 0.0, 1.0),
+            f32::vec3(1.0, 1.0, 0.0),
+            f32::vec3(0.0, 1.0, 0.0),
+        ];
+
+        let indices = vec![
+            u32::uvec3(0, 2, 1),
+            u32::uvec3(0, 3, 2),
+            u32::uvec3(0, 1, 4),
+            u32::uvec3(0, 4, 5),
+        ];
+
+        (vertices, indices)
+    }
+
+    #[test]
+    fn test_sharp_edges_are_split() {
+        let (vertices, indices) = fold();
+        let mesh = smooth_normals(&vertices, &indices, DEFAULT_SMOOTHING_ANGLE);
+
+        // The shared edge's vertices are split in two, one for each face
+        assert_eq!(mesh.normals.len(), 8);
+        for (face, i) in mesh.indices.iter().enumerate() {
+            let expected = if face < 2 { f32::Vec3::Y } else { f32::Vec3::Z };
+            for index in i.to_array() {
+                assert!(mesh.normals[index as usize].abs_diff_eq(expected, 1E-6));
+            }
+        }
+    }
+
+    #[test]
+    fn test_shallow_edges_are_smoothed() {
+        let (vertices, indices) = fold();
+        let mesh = smooth_normals(&vertices, &indices, 100.0);
+
+        assert_eq!(mesh.normals.len(), vertices.len());
+        assert_eq!(mesh.sources, [0, 2, 1, 3, 4, 5]);
+
+        let edge = mesh.normals[mesh.indices[0].x as usize];
+        assert!(edge.abs_diff_eq(f32::vec3(0.0, 1.0, 1.0).normalize(), 1E-6));
+    }
+
+    fn quad(uvs: Vec<f32::Vec2>) -> scene::Mesh {
+        scene::Mesh {
+            vertices: vec![
+                f32::vec3(0.0, 0.0, 0.0),
+                f32::vec3(1.0, 0.0, 0.0),
+                f32::vec3(1.0, 1.0, 0.0),
+                f32::vec3(0.0, 1.0, 0.0),
+            ],
+            indices: vec![u32::uvec3(0, 1, 2), u32::uvec3(0, 2, 3)],
+            uvs,
+            normals: vec![f32::Vec3::Z; 4],
+            tangents: Vec::new(),
+            colors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_tangents_follow_uvs() {
+        // The image's top row is at the top of the quad
+        let uvs = vec![
+            f32::vec2(0.0, 1.0),
+            f32::vec2(1.0, 1.0),
+            f32::vec2(1.0, 0.0),
+            f32::vec2(0.0, 0.0),
+        ];
+
+        let mesh = generate_tangents(quad(uvs.clone()));
+        assert_eq!(mesh.vertices.len(), 4);
+        for tangent in &mesh.tangents {
+            assert!(tangent.abs_diff_eq(f32::vec4(1.0, 0.0, 0.0, 1.0), 1E-6));
+        }
+
+        // Mirroring the image horizontally flips the tangent, but not the bitangent
+        let mirrored = uvs.iter().map(|uv| f32::vec2(1.0 - uv.x, uv.y)).collect();
+        let mesh = generate_tangents(quad(mirrored));
+        for tangent in &mesh.tangents {
+            assert!(tangent.abs_diff_eq(f32::vec4(-1.0, 0.0, 0.0, -1.0), 1E-6));
+        }
+    }
+
+    #[test]
+    fn test_tangents_split_at_mirrored_seams() {
+        // Two quads side by side, with the right one's UVs mirrored across
+        // the edge they share, like a symmetrical model sharing half a texture
+        let mut mesh = quad(vec![
+            f32::vec2(0.0, 1.0),
+            f32::vec2(1.0, 1.0),
+            f32::vec2(1.0, 0.0),
+            f32::vec2(0.0, 0.0),
+        ]);
+
+        mesh.vertices
+            .extend([f32::vec3(2.0, 0.0, 0.0), f32::vec3(2.0, 1.0, 0.0)]);
+        mesh.indices
+            .extend([u32::uvec3(1, 4, 5), u32::uvec3(1, 5, 2)]);
+        mesh.uvs.extend([f32::vec2(0.0, 1.0), f32::vec2(0.0, 0.0)]);
+        mesh.normals.extend([f32::Vec3::Z; 2]);
+
+        let mesh = generate_tangents(mesh);
+
+        // The seam's vertices are split in two, one for each side
+        assert_eq!(mesh.vertices.len(), 8);
+        for (face, i) in mesh.indices.iter().enumerate() {
+            let expected = if face < 2 {
+                f32::vec4(1.0, 0.0, 0.0, 1.0)
+            } else {
+                f32::vec4(-1.0, 0.0, 0.0, -1.0)
+            };
+
+            for index in i.to_array() {
+                assert!(mesh.tangents[index as usize].abs_diff_eq(expected, 1E-6));
+            }
+        }
+    }
+}
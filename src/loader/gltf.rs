@@ -1,105 +1,969 @@
-use std::path::PathBuf;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    path::Path,
+};
 
-use gltf::camera::Projection;
+use gltf::{camera::Projection, khr_lights_punctual::Kind, mesh::Mode};
 
-use crate::scene;
+use crate::{exporter::gltf::SPHERE_EXTENSION, scene, traits::SceneLoader};
 
-pub fn load(path: PathBuf) -> Result<scene::Scene, String> {
-    let (gltf, buffers, _) =
-        ::gltf::import(path).map_err(|e| format!("Error while parsing GLTF file: {}", e))?;
+use super::{geometry, ImportMode, ImportOptions, Warning};
 
-    let nodes: Vec<(gltf::Node, glam::Mat4)> = gltf
-        .nodes()
-        .flat_map(|n| flatten_transforms(n, glam::Mat4::IDENTITY))
-        .collect();
+/// Loads glTF scenes, as JSON `.gltf` files or binary `.glb` files
+pub struct GltfLoader;
 
-    let objects = nodes.iter().filter_map(|node| {
-        let mesh = node.0.mesh()?;
-        let vertices = mesh
-            .primitives()
-            .flat_map(|p| {
-                let reader = p.reader(|b| Some(&buffers[b.index()]));
-                let positions = reader.read_positions().unwrap();
-                positions.map(|p| {
-                    let p = glam::f32::Vec3::from(p);
-                    node.1.transform_point3(p)
-                })
-            })
-            .collect();
+impl SceneLoader for GltfLoader {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["gltf", "glb"]
+    }
 
-        let indices = mesh
-            .primitives()
-            .flat_map(|p| {
-                let reader = p.reader(|b| Some(&buffers[b.index()]));
-                let indices: Vec<u32> = reader.read_indices().unwrap().into_u32().collect();
-
-                indices[..]
-                    .windows(3)
-                    .step_by(3)
-                    .map(|w| glam::u32::UVec3 {
-                        x: w[0],
-                        y: w[1],
-                        z: w[2],
-                    })
-                    .collect::<Vec<glam::u32::UVec3>>()
-            })
-            .collect();
+    fn matches(&self, start: &[u8], _size: u64) -> bool {
+        // JSON files are recognised by the asset property every file must have
+        let json = start.trim_ascii_start().starts_with(b"{")
+            && start.windows(7).any(|window| window == b"\"asset\"");
 
-        let obj = scene::Object {
-            transform: node.1.into(),
-            surface: scene::Surface::MeshData {
-                vertices: vertices,
-                indices,
-            },
-            material: scene::Material {
-                color: glam::vec4(1.0, 1.0, 1.0, 1.0),
-                luminosity: 0.0,
-                smoothness: 0.0,
-            },
+        start.starts_with(b"glTF") || json
+    }
+
+    fn load(
+        &self,
+        path: &Path,
+        options: &ImportOptions,
+    ) -> Result<(scene::Scene, Vec<Warning>), String> {
+        load(path, options)
+    }
+}
+
+fn load(path: &Path, options: &ImportOptions) -> Result<(scene::Scene, Vec<Warning>), String> {
+    let bytes =
+        fs::read(path).map_err(|e| format!("Unable to read file: {}\n  {}", path.display(), e))?;
+
+    load_slice(bytes, path.parent(), options)
+}
+
+/// Loads a scene from the bytes of a glTF or GLB file, resolving external
+/// buffers and images relative to `base`
+fn load_slice(
+    bytes: Vec<u8>,
+    base: Option<&Path>,
+    options: &ImportOptions,
+) -> Result<(scene::Scene, Vec<Warning>), String> {
+    // The same bytes are parsed for the extensions the glTF crate drops
+    let ::gltf::Gltf {
+        document: gltf,
+        blob,
+    } = ::gltf::Gltf::from_slice(&bytes)
+        .map_err(|e| format!("Error while parsing GLTF file: {}", e))?;
+    let extensions = read_extensions(&bytes)?;
+    drop(bytes);
+
+    let buffers = ::gltf::import_buffers(&gltf, base, blob)
+        .map_err(|e| format!("Error while parsing GLTF file: {}", e))?;
+    let images = ::gltf::import_images(&gltf, base, &buffers)
+        .map_err(|e| format!("Error while parsing GLTF file: {}", e))?;
+
+    let basis = options.basis();
+    let nodes = world_transforms(&gltf);
+
+    // Each primitive can have its own material, so each becomes its own object
+    let mut objects = Vec::new();
+    let mut meshes = BTreeMap::new();
+    let mut mesh_names: HashMap<PrimitiveKey, Option<String>> = HashMap::new();
+    let mut bounds = (glam::Vec3::splat(f32::INFINITY), glam::Vec3::NEG_INFINITY);
+    let mut warnings = Vec::new();
+    for (node, world) in &nodes {
+        let Some(mesh) = node.mesh() else {
+            continue;
         };
 
-        Some(obj)
-    });
+        let node_transform = basis * *world * basis.inverse();
 
-    let camera = {
-        let cam: Option<scene::Camera> =
-            nodes
-                .iter()
-                .find_map(|node| match node.0.camera()?.projection() {
-                    Projection::Perspective(perspective) => Some(scene::Camera {
-                        transform: node.1.into(),
-                        lens: scene::Lens::Perspective {
-                            fov: perspective.yfov().to_degrees(),
-                            focal_distance: 0.0,
-                        },
-                        clipping: scene::Clipping {
-                            near: perspective.znear(),
-                            far: perspective.zfar().unwrap_or(2000.0),
+        // Spheres exported from a scene come back as spheres in either mode
+        let sphere = extensions.spheres.get(&node.index());
+        if let (Some(radius), Some(primitive)) = (sphere, mesh.primitives().next()) {
+            let transform = scene::Transform::from(node_transform);
+            let extent = radius * transform.scale.x.max(transform.scale.y);
+            bounds = (
+                bounds.0.min(transform.position - extent),
+                bounds.1.max(transform.position + extent),
+            );
+
+            objects.push(scene::Object {
+                surface: scene::Surface::Sphere { radius: *radius },
+                transform,
+                material: material(&primitive.material(), &extensions.emissive_strengths).into(),
+            });
+
+            continue;
+        }
+
+        // Meshes stay in their own space only when the node's transform can
+        // be written as a position, rotation and scale
+        let local = match options.mode {
+            ImportMode::World => None,
+            ImportMode::Local => {
+                let transform = to_transform(node_transform);
+                if transform.is_none() {
+                    warnings.push(Warning {
+                        location: match node.name() {
+                            Some(name) => format!("node '{}'", name),
+                            None => format!("node {}", node.index()),
                         },
-                    }),
-                    _ => None,
-                });
+                        message: "Baked sheared or mirrored transform into the vertices"
+                            .to_string(),
+                    });
+                }
+
+                transform
+            }
+        };
+
+        for primitive in mesh.primitives() {
+            let location = match mesh.name() {
+                Some(name) => format!("mesh '{}', primitive {}", name, primitive.index()),
+                None => format!("mesh {}, primitive {}", mesh.index(), primitive.index()),
+            };
+
+            let mut warn = |message| {
+                warnings.push(Warning {
+                    location: location.clone(),
+                    message,
+                })
+            };
+
+            let (surface, transform, object_to_world) = match &local {
+                Some(transform) => {
+                    // Primitives of different meshes can share their data too,
+                    // like the same geometry with different materials, as long
+                    // as their materials read the same attributes from it
+                    let material = primitive.material();
+                    let key = PrimitiveKey {
+                        mode: primitive.mode().as_gl_enum(),
+                        uv_set: uv_set(&material),
+                        tangents: material.normal_texture().is_some(),
+                        indices: primitive.indices().map(|accessor| accessor.index()),
+                        attributes: primitive
+                            .attributes()
+                            .map(|(semantic, accessor)| (semantic.to_string(), accessor.index()))
+                            .collect(),
+                    };
+
+                    let name = mesh_names.entry(key).or_insert_with(|| {
+                        let data = read_primitive(&primitive, &buffers, basis, &mut warn)?;
+                        let name = mesh_name(&mesh, &primitive, &meshes);
+                        meshes.insert(name.clone(), data);
+                        Some(name)
+                    });
+
+                    let Some(name) = name.clone() else {
+                        continue;
+                    };
+
+                    let surface = scene::Surface::Mesh { name };
+                    (surface, transform.clone(), node_transform)
+                }
+                None => {
+                    let Some(data) =
+                        read_primitive(&primitive, &buffers, basis * *world, &mut warn)
+                    else {
+                        continue;
+                    };
+
+                    (
+                        data.into(),
+                        glam::Mat4::IDENTITY.into(),
+                        glam::Mat4::IDENTITY,
+                    )
+                }
+            };
+
+            let vertices = match &surface {
+                scene::Surface::Mesh { name } => &meshes[name].vertices,
+                scene::Surface::MeshData { vertices, .. } => vertices,
+                scene::Surface::Sphere { .. } | scene::Surface::MeshFile { .. } => unreachable!(),
+            };
+
+            for vertex in vertices {
+                let vertex = object_to_world.transform_point3(*vertex);
+                bounds = (bounds.0.min(vertex), bounds.1.max(vertex));
+            }
 
-        match cam {
-            Some(cam) => cam,
-            None => return Err("No camera found in scene".to_string()),
+            objects.push(scene::Object {
+                transform,
+                surface,
+                material: material(&primitive.material(), &extensions.emissive_strengths).into(),
+            });
         }
+    }
+
+    let camera = nodes.iter().find_map(|(node, world)| {
+        let camera = node.camera()?;
+        let Projection::Perspective(perspective) = camera.projection() else {
+            return None;
+        };
+
+        let (rotation, position) = facing(basis * *world);
+
+        // glTF cameras have no focus distance, so unless one was exported from
+        // a scene, they focus on the middle of the scene
+        let scale = options.unit_scale;
+        let near = perspective.znear() * scale;
+        let focal_distance = if let Some(distance) = extensions.focal_distances.get(&camera.index())
+        {
+            distance * scale
+        } else if bounds.0.cmple(bounds.1).all() {
+            let center = (bounds.0 + bounds.1) / 2.0;
+            (center - position).dot(rotation * glam::Vec3::Z).max(near)
+        } else {
+            1.0
+        };
+
+        Some(scene::Camera {
+            transform: glam::Mat4::from_rotation_translation(rotation, position).into(),
+            lens: scene::Lens::Perspective {
+                fov: perspective.yfov().to_degrees(),
+                focal_distance,
+            },
+            clipping: scene::Clipping {
+                near,
+                far: perspective.zfar().unwrap_or(2000.0) * scale,
+            },
+        })
+    });
+
+    let Some(camera) = camera else {
+        return Err("No camera found in scene".to_string());
     };
 
+    let lights = nodes
+        .iter()
+        .filter_map(|(node, world)| {
+            let light = node.light()?;
+            let (rotation, position) = facing(basis * *world);
+
+            // glTF uses photometric units: candela for point and spot lights,
+            // and lux for directional lights. There are 683 lumens to the watt.
+            let mut intensity = light.intensity() / 683.0;
+            let kind = match light.kind() {
+                Kind::Directional => scene::LightKind::Directional,
+                Kind::Point => scene::LightKind::Point,
+                Kind::Spot {
+                    inner_cone_angle,
+                    outer_cone_angle,
+                } => scene::LightKind::Spot {
+                    inner_cone_angle: inner_cone_angle.to_degrees(),
+                    outer_cone_angle: outer_cone_angle.to_degrees(),
+                },
+            };
+
+            // Light from a point spreads with the square of distance, which changes with the units
+            if kind != scene::LightKind::Directional {
+                intensity *= options.unit_scale * options.unit_scale;
+            }
+
+            Some(scene::Light {
+                kind,
+                transform: glam::Mat4::from_rotation_translation(rotation, position).into(),
+                color: glam::Vec3::from(light.color()),
+                intensity,
+                range: light.range().map(|range| range * options.unit_scale),
+            })
+        })
+        .collect();
+
     let scene = scene::Scene {
         camera,
-        objects: objects.collect(),
+        meshes,
+        images: images.iter().map(convert_image).collect::<Result<_, _>>()?,
+        materials: Default::default(),
+        objects,
+        lights,
+        bvhs: Default::default(),
     };
 
-    Ok(scene)
+    Ok((scene, warnings))
+}
+
+/// The orientation and position of a camera or light node in scene
+/// coordinates. glTF cameras and lights face -Z, where they face +Z here.
+fn facing(node_to_scene: glam::Mat4) -> (glam::Quat, glam::Vec3) {
+    let facing_to_node = glam::Mat4::from_scale(glam::vec3(1.0, 1.0, -1.0));
+    let (_, rotation, position) = (node_to_scene * facing_to_node).to_scale_rotation_translation();
+    (rotation, position)
 }
 
-fn flatten_transforms(node: gltf::Node, transform: glam::Mat4) -> Vec<(gltf::Node, glam::Mat4)> {
-    let transform = glam::f32::Mat4::from_cols_array_2d(&node.transform().matrix()) * transform;
-    let mut nodes = vec![(node.clone(), transform)];
-    for child in node.children().into_iter() {
-        nodes.extend(flatten_transforms(child, transform));
+/// The transform written in a scene file for a matrix, or `None` if the
+/// matrix has shear or a mirror that a position, rotation and scale can't express
+fn to_transform(matrix: glam::Mat4) -> Option<scene::Transform> {
+    if matrix.determinant() <= 0.0 {
+        return None;
+    }
+
+    let transform = scene::Transform::from(matrix);
+    let rebuilt = glam::Mat4::from(glam::Affine3A::from(&transform));
+    let tolerance = 1E-4
+        * matrix
+            .to_cols_array()
+            .iter()
+            .fold(1.0, |a: f32, b| a.max(b.abs()));
+    rebuilt.abs_diff_eq(matrix, tolerance).then_some(transform)
+}
+
+/// A unique name for a primitive's mesh in the scene's `meshes`
+fn mesh_name(
+    mesh: &gltf::Mesh,
+    primitive: &gltf::Primitive,
+    meshes: &BTreeMap<String, scene::Mesh>,
+) -> String {
+    let name = match mesh.name() {
+        Some(name) => format!("{}.{}", name, primitive.index()),
+        None => format!("mesh{}.{}", mesh.index(), primitive.index()),
+    };
+
+    // Names in glTF files don't have to be unique
+    if meshes.contains_key(&name) {
+        format!("{}#{}", name, mesh.index())
+    } else {
+        name
+    }
+}
+
+/// Reads a primitive's triangles and vertex attributes, or returns `None` if
+/// it has nothing that can be rendered. Anything skipped is passed to `warn`.
+fn read_primitive(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+    transform: glam::Mat4,
+    warn: &mut impl FnMut(String),
+) -> Option<scene::Mesh> {
+    let reader = primitive.reader(|b| Some(&buffers[b.index()]));
+    let Some(positions) = reader.read_positions() else {
+        warn("Skipped primitive without positions".to_string());
+        return None;
+    };
+
+    let mut vertices: Vec<glam::f32::Vec3> = positions
+        .map(|p| transform.transform_point3(glam::f32::Vec3::from(p)))
+        .collect();
+
+    // Primitives without indices use each vertex in order
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..vertices.len() as u32).collect(),
+    };
+
+    let mut indices = match triangulate(primitive.mode(), &indices) {
+        Ok(triangles) => triangles,
+        Err(message) => {
+            warn(message);
+            return None;
+        }
+    };
+
+    // Mirroring turns faces inside out, so their winding is reversed to keep them facing out
+    let mirrored = transform.determinant() < 0.0;
+    if mirrored {
+        for i in &mut indices {
+            *i = glam::u32::uvec3(i.x, i.z, i.y);
+        }
+    }
+
+    if let Some(index) = indices
+        .iter()
+        .flat_map(|i| i.to_array())
+        .find(|i| *i as usize >= vertices.len())
+    {
+        warn(format!(
+            "Skipped primitive with index {} out of range (vertex count: {})",
+            index,
+            vertices.len()
+        ));
+        return None;
+    }
+
+    // Attributes without a value for every vertex can't be used
+    let mut per_vertex = |name: &str, count: usize| {
+        let matches = count == vertices.len();
+        if !matches {
+            warn(format!(
+                "Ignored {} {} for {} vertices",
+                count,
+                name,
+                vertices.len()
+            ));
+        }
+
+        matches
+    };
+
+    let material = primitive.material();
+    let mut uvs: Vec<glam::f32::Vec2> = reader
+        .read_tex_coords(uv_set(&material))
+        .map(|uvs| {
+            uvs.into_f32()
+                .map(glam::f32::Vec2::from)
+                .collect::<Vec<_>>()
+        })
+        .filter(|uvs| per_vertex("UVs", uvs.len()))
+        .unwrap_or_default();
+
+    let mut colors: Vec<glam::f32::Vec4> = reader
+        .read_colors(0)
+        .map(|colors| {
+            colors
+                .into_rgba_f32()
+                .map(glam::f32::Vec4::from)
+                .collect::<Vec<_>>()
+        })
+        .filter(|colors| per_vertex("colors", colors.len()))
+        .unwrap_or_default();
+
+    // Normals transform by the inverse transpose to stay perpendicular to scaled surfaces
+    let normal_matrix = glam::f32::Mat3::from_mat4(transform).inverse().transpose();
+    let read_normals = reader
+        .read_normals()
+        .map(|normals| {
+            normals
+                .map(|n| (normal_matrix * glam::f32::Vec3::from(n)).normalize_or_zero())
+                .collect::<Vec<_>>()
+        })
+        .filter(|normals| per_vertex("normals", normals.len()));
+
+    let object_to_world = glam::f32::Mat3::from_mat4(transform);
+    let read_tangents = reader
+        .read_tangents()
+        .map(|tangents| {
+            tangents
+                .map(|t| {
+                    let tangent = object_to_world * glam::f32::Vec3::from_slice(&t);
+                    let handedness = if mirrored { -t[3] } else { t[3] };
+                    tangent.normalize_or_zero().extend(handedness)
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|tangents| per_vertex("tangents", tangents.len()));
+
+    // Given tangents only match given normals, so they're generated along with them
+    let has_normals = read_normals.is_some();
+    let normals = match read_normals {
+        Some(normals) => normals,
+        None => {
+            let smoothed =
+                geometry::smooth_normals(&vertices, &indices, geometry::DEFAULT_SMOOTHING_ANGLE);
+
+            let sources = smoothed.sources.iter().map(|i| *i as usize);
+            vertices = sources.clone().map(|i| vertices[i]).collect();
+            if !uvs.is_empty() {
+                uvs = sources.clone().map(|i| uvs[i]).collect();
+            }
+
+            if !colors.is_empty() {
+                colors = sources.map(|i| colors[i]).collect();
+            }
+
+            indices = smoothed.indices;
+            smoothed.normals
+        }
+    };
+
+    let mesh = scene::Mesh {
+        vertices,
+        indices,
+        uvs,
+        normals,
+        tangents: read_tangents.filter(|_| has_normals).unwrap_or_default(),
+        colors,
+    };
+
+    if mesh.tangents.is_empty() && material.normal_texture().is_some() && !mesh.uvs.is_empty() {
+        Some(geometry::generate_tangents(mesh))
+    } else {
+        Some(mesh)
+    }
+}
+
+/// The set of texture coordinates a material's textures are mapped with
+fn uv_set(material: &gltf::Material) -> u32 {
+    match material.pbr_metallic_roughness().base_color_texture() {
+        Some(info) => info.tex_coord(),
+        None => material.normal_texture().map_or(0, |info| info.tex_coord()),
+    }
+}
+
+/// Groups a primitive's indices into triangles, following glTF's rules for each mode
+fn triangulate(mode: Mode, indices: &[u32]) -> Result<Vec<glam::u32::UVec3>, String> {
+    let triangles = match mode {
+        Mode::Triangles => {
+            if !indices.len().is_multiple_of(3) {
+                return Err(format!(
+                    "Skipped triangle list with {} indices, which isn't a multiple of 3",
+                    indices.len()
+                ));
+            }
+
+            indices
+                .chunks_exact(3)
+                .map(|w| glam::u32::uvec3(w[0], w[1], w[2]))
+                .collect()
+        }
+        // Every other triangle is flipped to keep the winding consistent
+        Mode::TriangleStrip => indices
+            .windows(3)
+            .enumerate()
+            .map(|(i, w)| match i % 2 {
+                0 => glam::u32::uvec3(w[0], w[1], w[2]),
+                _ => glam::u32::uvec3(w[0], w[2], w[1]),
+            })
+            .collect(),
+        Mode::TriangleFan => indices
+            .windows(2)
+            .skip(1)
+            .map(|w| glam::u32::uvec3(w[0], w[1], indices[0]))
+            .collect(),
+        Mode::Points | Mode::Lines | Mode::LineLoop | Mode::LineStrip => {
+            return Err(format!(
+                "Skipped primitive drawn as {:?}, which has no surface",
+                mode
+            ))
+        }
+    };
+
+    Ok(triangles)
+}
+
+fn material(
+    material: &gltf::Material,
+    emissive_strengths: &HashMap<usize, f32>,
+) -> scene::Material {
+    let pbr = material.pbr_metallic_roughness();
+
+    let strength = material
+        .index()
+        .and_then(|i| emissive_strengths.get(&i))
+        .copied()
+        .unwrap_or(1.0);
+
+    // Our luminosity scales the emission color, so split glTF's emission into both
+    let emission = glam::f32::Vec3::from(material.emissive_factor()) * strength;
+    let luminosity = emission.max_element();
+    let emission_color = (luminosity > 0.0).then(|| emission / luminosity);
+
+    // glTF gives the color light fades to after travelling the attenuation
+    // distance, where our absorption color is after a single unit
+    let absorption = material.volume().and_then(|volume| {
+        let color = glam::f32::Vec3::from(volume.attenuation_color());
+        let distance = volume.attenuation_distance();
+        (distance.is_finite() && color != glam::f32::Vec3::ONE).then(|| color.powf(1.0 / distance))
+    });
+
+    scene::Material {
+        color: glam::f32::Vec4::from(pbr.base_color_factor()),
+        luminosity,
+        emission_color,
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        transmission: material
+            .transmission()
+            .map_or(0.0, |t| t.transmission_factor()),
+        ior: material.ior().unwrap_or(1.5),
+        absorption,
+        texture: pbr
+            .base_color_texture()
+            .map(|info| scene::Texture::Image(info.texture().source().index())),
+        normal_texture: material
+            .normal_texture()
+            .map(|info| scene::Texture::Image(info.texture().source().index())),
+        normal_scale: material.normal_texture().map_or(1.0, |info| info.scale()),
+    }
+}
+
+/// Converts a decoded image to 8-bit RGBA
+fn convert_image(data: &gltf::image::Data) -> Result<scene::Image, String> {
+    use gltf::image::Format;
+
+    fn to_u16(bytes: &[u8]) -> Vec<u16> {
+        bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    fn to_f32(bytes: &[u8]) -> Vec<f32> {
+        bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    let (width, height, pixels) = (data.width, data.height, data.pixels.clone());
+    let image: Option<image::DynamicImage> = match data.format {
+        Format::R8 => image::GrayImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8 => image::GrayAlphaImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8B8 => image::RgbImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8B8A8 => image::RgbaImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R16 => {
+            image::ImageBuffer::<image::Luma<u16>, _>::from_raw(width, height, to_u16(&pixels))
+                .map(Into::into)
+        }
+        Format::R16G16 => {
+            image::ImageBuffer::<image::LumaA<u16>, _>::from_raw(width, height, to_u16(&pixels))
+                .map(Into::into)
+        }
+        Format::R16G16B16 => {
+            image::ImageBuffer::<image::Rgb<u16>, _>::from_raw(width, height, to_u16(&pixels))
+                .map(Into::into)
+        }
+        Format::R16G16B16A16 => {
+            image::ImageBuffer::<image::Rgba<u16>, _>::from_raw(width, height, to_u16(&pixels))
+                .map(Into::into)
+        }
+        Format::R32G32B32FLOAT => {
+            image::Rgb32FImage::from_raw(width, height, to_f32(&pixels)).map(Into::into)
+        }
+        Format::R32G32B32A32FLOAT => {
+            image::Rgba32FImage::from_raw(width, height, to_f32(&pixels)).map(Into::into)
+        }
+    };
+
+    let image =
+        image.ok_or_else(|| format!("Image data doesn't match its size: {}x{}", width, height))?;
+    Ok(scene::Image {
+        width,
+        height,
+        pixels: image.into_rgba8().into_raw(),
+    })
+}
+
+/// The data a primitive's triangles are made from, which several primitives can share
+#[derive(PartialEq, Eq, Hash, Debug)]
+struct PrimitiveKey {
+    mode: u32,
+
+    /// The texture coordinates read for the material
+    uv_set: u32,
+
+    /// Whether the material's normal map needs tangents generated
+    tangents: bool,
+
+    indices: Option<usize>,
+    attributes: BTreeSet<(String, usize)>,
+}
+
+/// Extensions and extras read from the raw JSON, since the glTF crate drops
+/// the ones it doesn't know about
+#[derive(Default, Debug)]
+struct Extensions {
+    /// `KHR_materials_emissive_strength` by material index
+    emissive_strengths: HashMap<usize, f32>,
+
+    /// The radius of each node marked as a sphere on export, by node index
+    spheres: HashMap<usize, f32>,
+
+    /// The focal distance of each camera exported from a scene, by camera index
+    focal_distances: HashMap<usize, f32>,
+}
+
+fn read_extensions(bytes: &[u8]) -> Result<Extensions, String> {
+    let json = if bytes.starts_with(b"glTF") {
+        gltf::Glb::from_slice(bytes)
+            .map_err(|e| format!("Error while parsing GLB file: {}", e))?
+            .json
+    } else {
+        Cow::Borrowed(bytes)
+    };
+
+    let root: serde_json::Value = serde_json::from_slice(&json)
+        .map_err(|e| format!("Error while parsing GLTF file: {}", e))?;
+
+    let array = |name| root[name].as_array().map(Vec::as_slice).unwrap_or_default();
+    let strengths = array("materials")
+        .iter()
+        .enumerate()
+        .filter_map(|(i, material)| {
+            let strength =
+                &material["extensions"]["KHR_materials_emissive_strength"]["emissiveStrength"];
+            Some((i, strength.as_f64()? as f32))
+        });
+
+    let spheres = array("nodes").iter().enumerate().filter_map(|(i, node)| {
+        let radius = &node["extensions"][SPHERE_EXTENSION]["radius"];
+        Some((i, radius.as_f64()? as f32))
+    });
+
+    let focal_distances = array("cameras")
+        .iter()
+        .enumerate()
+        .filter_map(|(i, camera)| {
+            let distance = &camera["extras"]["focalDistance"];
+            Some((i, distance.as_f64()? as f32))
+        });
+
+    Ok(Extensions {
+        emissive_strengths: strengths.collect(),
+        spheres: spheres.collect(),
+        focal_distances: focal_distances.collect(),
+    })
+}
+
+/// Every node in the default scene with its transform to world space
+fn world_transforms(gltf: &gltf::Document) -> Vec<(gltf::Node<'_>, glam::Mat4)> {
+    fn visit<'a>(
+        node: gltf::Node<'a>,
+        parent: glam::Mat4,
+        nodes: &mut Vec<(gltf::Node<'a>, glam::Mat4)>,
+    ) {
+        let transform = parent * glam::Mat4::from_cols_array_2d(&node.transform().matrix());
+        nodes.push((node.clone(), transform));
+        for child in node.children() {
+            visit(child, transform, nodes);
+        }
+    }
+
+    // Files without scenes are treated as one scene of every node without a parent
+    let roots: Vec<gltf::Node> = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+        Some(scene) => scene.nodes().collect(),
+        None => {
+            let children: HashSet<usize> = gltf
+                .nodes()
+                .flat_map(|node| node.children().map(|child| child.index()))
+                .collect();
+
+            gltf.nodes()
+                .filter(|node| !children.contains(&node.index()))
+                .collect()
+        }
+    };
+
+    let mut nodes = Vec::new();
+    for root in roots {
+        visit(root, glam::Mat4::IDENTITY, &mut nodes);
     }
 
     nodes
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MATERIALS: &str = r#"{
+        "asset": { "version": "2.0" },
+        "extensionsUsed": [
+            "KHR_materials_emissive_strength",
+            "KHR_materials_ior",
+            "KHR_materials_transmission"
+        ],
+        "materials": [
+            {
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": [0.5, 0.25, 1.0, 1.0],
+                    "metallicFactor": 0.75,
+                    "roughnessFactor": 0.5
+                },
+                "emissiveFactor": [1.0, 0.5, 0.0],
+                "extensions": {
+                    "KHR_materials_emissive_strength": { "emissiveStrength": 4.0 }
+                }
+            },
+            {
+                "extensions": {
+                    "KHR_materials_ior": { "ior": 1.33 },
+                    "KHR_materials_transmission": { "transmissionFactor": 1.0 }
+                }
+            }
+        ]
+    }"#;
+
+    const HIERARCHY: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [
+            { "translation": [1.0, 0.0, 0.0], "rotation": [0.0, 0.7071068, 0.0, 0.7071068], "children": [1] },
+            { "translation": [0.0, 0.0, 2.0], "scale": [3.0, 3.0, 3.0], "children": [2] },
+            { "translation": [0.0, 1.0, 0.0] },
+            { "translation": [5.0, 5.0, 5.0] }
+        ]
+    }"#;
+
+    #[test]
+    fn test_world_transforms() {
+        let gltf = gltf::Gltf::from_slice(HIERARCHY.as_bytes()).unwrap();
+        let nodes = world_transforms(&gltf);
+
+        // Each node in the scene appears once, and nodes outside it don't appear at all
+        let indices: Vec<_> = nodes.iter().map(|(node, _)| node.index()).collect();
+        assert_eq!(indices, [0, 1, 2]);
+
+        // Children are placed in their parent's space
+        let positions: Vec<_> = nodes
+            .iter()
+            .map(|(_, transform)| transform.transform_point3(glam::Vec3::ZERO))
+            .collect();
+
+        assert!(positions[1].abs_diff_eq(glam::vec3(3.0, 0.0, 0.0), 1E-5));
+        assert!(positions[2].abs_diff_eq(glam::vec3(3.0, 3.0, 0.0), 1E-5));
+    }
+
+    #[test]
+    fn test_to_transform() {
+        let matrix = glam::Mat4::from_scale_rotation_translation(
+            glam::vec3(2.0, 2.0, 2.0),
+            glam::Quat::from_rotation_y(1.0),
+            glam::vec3(1.0, 2.0, 3.0),
+        );
+
+        assert!(to_transform(matrix).is_some());
+
+        // Non-uniform scale under a rotation shears the child
+        let sheared = matrix
+            * glam::Mat4::from_scale(glam::vec3(1.0, 4.0, 1.0))
+            * glam::Mat4::from_rotation_z(0.5);
+        assert!(to_transform(sheared).is_none());
+
+        let mirrored = matrix * glam::Mat4::from_scale(glam::vec3(1.0, 1.0, -1.0));
+        assert!(to_transform(mirrored).is_none());
+    }
+
+    #[test]
+    fn test_triangulate_modes() {
+        let indices = [0, 1, 2, 3, 4];
+        let triangles = |mode| triangulate(mode, &indices).unwrap();
+
+        assert_eq!(
+            triangles(Mode::TriangleStrip),
+            [
+                glam::u32::uvec3(0, 1, 2),
+                glam::u32::uvec3(1, 3, 2),
+                glam::u32::uvec3(2, 3, 4),
+            ]
+        );
+
+        assert_eq!(
+            triangles(Mode::TriangleFan),
+            [
+                glam::u32::uvec3(1, 2, 0),
+                glam::u32::uvec3(2, 3, 0),
+                glam::u32::uvec3(3, 4, 0),
+            ]
+        );
+
+        assert!(triangulate(Mode::Triangles, &indices).is_err());
+        assert!(triangulate(Mode::LineStrip, &indices).is_err());
+    }
+
+    #[test]
+    fn test_shared_accessors() {
+        // One triangle with normals and two sets of UVs, used by two meshes
+        let floats: [f32; 30] = [
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, // positions
+            0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, // normals
+            0.0, 0.0, 1.0, 0.0, 0.0, 1.0, // UVs
+            0.0, 0.0, 0.0, 1.0, 1.0, 0.0, // second UVs
+        ];
+
+        let mut png = Vec::new();
+        image::RgbaImage::new(1, 1)
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+
+        let json = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "buffers": [{{
+                    "byteLength": 120,
+                    "uri": "data:application/octet-stream;base64,{}"
+                }}],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 72, "byteLength": 24 }},
+                    {{ "buffer": 0, "byteOffset": 96, "byteLength": 24 }}
+                ],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                       "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0] }},
+                    {{ "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" }},
+                    {{ "bufferView": 2, "componentType": 5126, "count": 3, "type": "VEC2" }},
+                    {{ "bufferView": 3, "componentType": 5126, "count": 3, "type": "VEC2" }}
+                ],
+                "images": [{{ "uri": "data:image/png;base64,{}" }}],
+                "textures": [{{ "source": 0 }}],
+                "materials": [
+                    {{}},
+                    {{ "normalTexture": {{ "index": 0, "texCoord": 1 }} }}
+                ],
+                "meshes": [
+                    {{ "primitives": [{{ "attributes": {{ "POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2, "TEXCOORD_1": 3 }}, "material": 0 }}] }},
+                    {{ "primitives": [{{ "attributes": {{ "POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2, "TEXCOORD_1": 3 }}, "material": 1 }}] }},
+                    {{ "primitives": [{{ "attributes": {{ "POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2, "TEXCOORD_1": 3 }}, "material": 0 }}] }}
+                ],
+                "cameras": [{{ "type": "perspective", "perspective": {{ "yfov": 1.0, "znear": 0.1 }} }}],
+                "nodes": [{{ "mesh": 0 }}, {{ "mesh": 1 }}, {{ "mesh": 2 }}, {{ "camera": 0 }}]
+            }}"#,
+            base64::encode(bytemuck::cast_slice(&floats)),
+            base64::encode(&png),
+        );
+
+        let (scene, _) = load_slice(
+            json.into_bytes(),
+            Some(Path::new("")),
+            &ImportOptions::default(),
+        )
+        .unwrap();
+
+        // Primitives with the same material share a mesh, but the normal
+        // mapped one reads its own UVs and gets its own tangents
+        let names: Vec<_> = scene
+            .objects
+            .iter()
+            .map(|object| match &object.surface {
+                scene::Surface::Mesh { name } => name.clone(),
+                surface => panic!("Expected a mesh, found {:?}", surface),
+            })
+            .collect();
+
+        assert_eq!(scene.meshes.len(), 2);
+        assert_eq!(names[0], names[2]);
+        assert_ne!(names[0], names[1]);
+
+        // The UVs of the vertex at x = 1, whichever order the vertices end up in
+        let uv = |mesh: &scene::Mesh| {
+            let i = mesh.vertices.iter().position(|v| v.x == 1.0).unwrap();
+            mesh.uvs[i]
+        };
+
+        let plain = &scene.meshes[&names[0]];
+        assert_eq!(uv(plain), glam::f32::vec2(1.0, 0.0));
+        assert!(plain.tangents.is_empty());
+
+        let normal_mapped = &scene.meshes[&names[1]];
+        assert_eq!(uv(normal_mapped), glam::f32::vec2(0.0, 1.0));
+        assert_eq!(normal_mapped.tangents.len(), 3);
+    }
+
+    #[test]
+    fn test_import_materials() {
+        let gltf = gltf::Gltf::from_slice(MATERIALS.as_bytes()).unwrap();
+        let strengths = read_extensions(MATERIALS.as_bytes())
+            .unwrap()
+            .emissive_strengths;
+        let materials: Vec<_> = gltf.materials().map(|m| material(&m, &strengths)).collect();
+
+        let emissive = &materials[0];
+        assert_eq!(emissive.color, glam::f32::vec4(0.5, 0.25, 1.0, 1.0));
+        assert_eq!(emissive.metallic, 0.75);
+        assert_eq!(emissive.roughness, 0.5);
+        assert_eq!(emissive.emission(), glam::f32::vec3(4.0, 2.0, 0.0));
+
+        let glass = &materials[1];
+        assert_eq!(glass.transmission, 1.0);
+        assert_eq!(glass.ior, 1.33);
+        assert_eq!(glass.emission(), glam::f32::Vec3::ZERO);
+    }
+}
@@ -0,0 +1,666 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{scene, traits::SceneLoader};
+
+use super::{default_material, framing_camera, geometry, ImportOptions, Warning};
+
+/// A face corner's indices into the file's positions, UVs and normals
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+struct Corner {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+/// The faces of a group that share a material
+#[derive(Debug)]
+struct Group {
+    name: String,
+    material: Option<String>,
+    triangles: Vec<[Corner; 3]>,
+}
+
+#[derive(Debug, Default)]
+struct Obj {
+    positions: Vec<glam::Vec3>,
+    uvs: Vec<glam::Vec2>,
+    normals: Vec<glam::Vec3>,
+    groups: Vec<Group>,
+
+    /// MTL files named by `mtllib`, relative to the OBJ file
+    libraries: Vec<String>,
+}
+
+/// A material from an MTL file, with its texture paths still to be loaded
+#[derive(Debug)]
+struct Mtl {
+    material: scene::Material,
+    texture: Option<PathBuf>,
+    normal_texture: Option<PathBuf>,
+}
+
+/// Loads Wavefront OBJ files and the MTL files they use. Each group becomes an
+/// object, split wherever its material changes.
+pub struct ObjLoader;
+
+impl SceneLoader for ObjLoader {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["obj"]
+    }
+
+    fn matches(&self, start: &[u8], _size: u64) -> bool {
+        // Files have no header, so look for a statement only OBJ files start with
+        let Some(line) = String::from_utf8_lossy(start)
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+        else {
+            return false;
+        };
+
+        let keyword = line.split_whitespace().next().unwrap_or("");
+        ["v", "vt", "vn", "f", "o", "g", "s", "mtllib", "usemtl"].contains(&keyword)
+    }
+
+    fn load(
+        &self,
+        path: &Path,
+        options: &ImportOptions,
+    ) -> Result<(scene::Scene, Vec<Warning>), String> {
+        load(path, options)
+    }
+}
+
+fn load(path: &Path, options: &ImportOptions) -> Result<(scene::Scene, Vec<Warning>), String> {
+    let source = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read file: {}\n  {}", path.display(), e))?;
+
+    let mut warnings = Vec::new();
+    let obj = parse_obj(&source, &mut warnings)?;
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut materials = HashMap::new();
+    for library in &obj.libraries {
+        let path = base.join(library);
+        match fs::read_to_string(&path) {
+            Ok(source) => {
+                let location = format!("material library '{}'", library);
+                let mut mtl_warnings = Vec::new();
+                let parsed = parse_mtl(
+                    &source,
+                    path.parent().unwrap_or(Path::new("")),
+                    &mut mtl_warnings,
+                )
+                .map_err(|e| format!("Unable to parse {}:\n  {}", location, e))?;
+                materials.extend(parsed);
+                warnings.extend(mtl_warnings.into_iter().map(|warning| Warning {
+                    location: format!("{}, {}", location, warning.location),
+                    message: warning.message,
+                }));
+            }
+            Err(e) => warnings.push(Warning {
+                location: format!("material library '{}'", library),
+                message: format!("Ignored missing file: {}", e),
+            }),
+        }
+    }
+
+    let basis = options.basis();
+    let mut images = Vec::new();
+    let mut image_ids: HashMap<PathBuf, Option<usize>> = HashMap::new();
+    let mut bounds = (glam::Vec3::splat(f32::INFINITY), glam::Vec3::NEG_INFINITY);
+    let mut objects = Vec::new();
+    for group in &obj.groups {
+        let mtl = group.material.as_ref().and_then(|name| {
+            let mtl = materials.get(name);
+            if mtl.is_none() {
+                warnings.push(Warning {
+                    location: format!("group '{}'", group.name),
+                    message: format!("Used the default material in place of unknown '{}'", name),
+                });
+            }
+
+            mtl
+        });
+
+        // Images are embedded, since the scene may not be written next to the OBJ file
+        let mut texture = |path: &Option<PathBuf>| {
+            let path = path.as_ref()?;
+            let id = image_ids
+                .entry(path.clone())
+                .or_insert_with(|| match image::open(path) {
+                    Ok(image) => {
+                        let image = image.into_rgba8();
+                        images.push(scene::Image {
+                            width: image.width(),
+                            height: image.height(),
+                            pixels: image.into_raw(),
+                        });
+
+                        Some(images.len() - 1)
+                    }
+                    Err(e) => {
+                        warnings.push(Warning {
+                            location: format!("texture '{}'", path.display()),
+                            message: format!("Ignored texture that couldn't be loaded: {}", e),
+                        });
+
+                        None
+                    }
+                });
+
+            id.map(scene::Texture::Image)
+        };
+
+        let material = match mtl {
+            Some(mtl) => scene::Material {
+                texture: texture(&mtl.texture),
+                normal_texture: texture(&mtl.normal_texture),
+                ..mtl.material.clone()
+            },
+            None => default_material(),
+        };
+
+        let mesh = build_mesh(&obj, group, basis, material.normal_texture.is_some());
+        for vertex in &mesh.vertices {
+            bounds = (bounds.0.min(*vertex), bounds.1.max(*vertex));
+        }
+
+        objects.push(scene::Object {
+            surface: mesh.into(),
+            transform: glam::Mat4::IDENTITY.into(),
+            material: material.into(),
+        });
+    }
+
+    let scene = scene::Scene {
+        camera: framing_camera(bounds.0, bounds.1),
+        meshes: Default::default(),
+        images,
+        materials: Default::default(),
+        objects,
+        lights: Vec::new(),
+        bvhs: Default::default(),
+    };
+
+    Ok((scene, warnings))
+}
+
+/// Reads an OBJ file's vertex data and faces, triangulating polygons
+fn parse_obj(source: &str, warnings: &mut Vec<Warning>) -> Result<Obj, String> {
+    let mut obj = Obj::default();
+    let mut group_name = "default".to_string();
+    let mut material = None;
+    let mut group_ids: HashMap<(String, Option<String>), usize> = HashMap::new();
+    let mut current = None;
+    let mut warned_lines = false;
+
+    for (number, line) in logical_lines(source) {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+
+        let args: Vec<&str> = tokens.collect();
+        let error = |e: String| format!("Invalid '{}' on line {}:\n  {}", keyword, number, e);
+        match keyword {
+            "v" => {
+                let position = floats(&args, 3).map_err(error)?;
+                obj.positions.push(glam::Vec3::from_slice(&position));
+            }
+            "vt" => {
+                // V is optional, and textures here have their first row at V = 0
+                let uv = floats(&args, 1).map_err(error)?;
+                obj.uvs
+                    .push(glam::vec2(uv[0], 1.0 - uv.get(1).copied().unwrap_or(0.0)));
+            }
+            "vn" => {
+                let normal = floats(&args, 3).map_err(error)?;
+                obj.normals.push(glam::Vec3::from_slice(&normal));
+            }
+            "f" => {
+                let corners = args
+                    .iter()
+                    .map(|arg| corner(arg, &obj))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(error)?;
+
+                if corners.len() < 3 {
+                    return Err(error(format!(
+                        "Expected at least 3 corners but found {}",
+                        corners.len()
+                    )));
+                }
+
+                let id = *current.get_or_insert_with(|| {
+                    *group_ids
+                        .entry((group_name.clone(), material.clone()))
+                        .or_insert_with(|| {
+                            obj.groups.push(Group {
+                                name: group_name.clone(),
+                                material: material.clone(),
+                                triangles: Vec::new(),
+                            });
+
+                            obj.groups.len() - 1
+                        })
+                });
+
+                // Polygons are assumed to be convex, so they're split into a fan
+                let triangles = &mut obj.groups[id].triangles;
+                for i in 1..corners.len() - 1 {
+                    triangles.push([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            "o" | "g" => {
+                group_name = match args.is_empty() {
+                    true => "default".to_string(),
+                    false => args.join(" "),
+                };
+
+                current = None;
+            }
+            "usemtl" => {
+                material = (!args.is_empty()).then(|| args.join(" "));
+                current = None;
+            }
+            "mtllib" => obj.libraries.push(args.join(" ")),
+            "l" | "p" if !warned_lines => {
+                warned_lines = true;
+                warnings.push(Warning {
+                    location: format!("line {}", number),
+                    message: "Skipped lines and points, which have no surface to render"
+                        .to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(obj)
+}
+
+/// Reads the materials in an MTL file, with texture paths relative to `base`
+fn parse_mtl(
+    source: &str,
+    base: &Path,
+    warnings: &mut Vec<Warning>,
+) -> Result<HashMap<String, Mtl>, String> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, Mtl)> = None;
+
+    for (number, line) in logical_lines(source) {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+
+        let args: Vec<&str> = tokens.collect();
+        let error = |e: String| format!("Invalid '{}' on line {}:\n  {}", keyword, number, e);
+        if keyword == "newmtl" {
+            materials.extend(current.take());
+            let mtl = Mtl {
+                material: default_material(),
+                texture: None,
+                normal_texture: None,
+            };
+
+            current = Some((args.join(" "), mtl));
+            continue;
+        }
+
+        let Some((_, mtl)) = &mut current else {
+            continue;
+        };
+
+        let material = &mut mtl.material;
+        match keyword {
+            "Kd" => {
+                let [r, g, b] = color(&args).map_err(error)?;
+                material.color = glam::vec4(r, g, b, material.color.w);
+            }
+            "Ke" => {
+                // Luminosity scales the emission color, so the emission is split into both
+                let emission = glam::Vec3::from(color(&args).map_err(error)?);
+                material.luminosity = emission.max_element();
+                material.emission_color =
+                    (material.luminosity > 0.0).then(|| emission / material.luminosity);
+            }
+            "Ns" => {
+                // The Beckmann roughness that matches a Phong exponent is
+                // sqrt(2 / (Ns + 2)), which is the square of ours
+                let exponent = floats(&args, 1).map_err(error)?[0].max(0.0);
+                material.roughness = (2.0 / (exponent + 2.0)).powf(0.25);
+            }
+            "Pr" => material.roughness = floats(&args, 1).map_err(error)?[0],
+            "Pm" => material.metallic = floats(&args, 1).map_err(error)?[0],
+            "Ni" => material.ior = floats(&args, 1).map_err(error)?[0],
+            // Dissolve fades the surface out rather than refracting through it,
+            // and isn't something materials can do yet
+            "d" | "Tr" => {
+                let value = floats(&args, 1).map_err(error)?[0];
+                let opaque = if keyword == "d" { 1.0 } else { 0.0 };
+                if value != opaque {
+                    warnings.push(Warning {
+                        location: format!("line {}", number),
+                        message: format!("Ignored unsupported dissolve '{}'", line.trim()),
+                    });
+                }
+            }
+            "map_Kd" => mtl.texture = Some(base.join(texture_path(&args).map_err(error)?)),
+            "map_Bump" | "map_bump" | "bump" | "norm" => {
+                mtl.normal_texture = Some(base.join(texture_path(&args).map_err(error)?));
+                if let Some(i) = args.iter().position(|arg| *arg == "-bm") {
+                    let scale = floats(args.get(i + 1..i + 2).unwrap_or(&[]), 1).map_err(error)?;
+                    material.normal_scale = scale[0];
+                }
+            }
+            _ => {}
+        }
+    }
+
+    materials.extend(current);
+    Ok(materials)
+}
+
+/// Builds a group's mesh in scene coordinates. Vertices are shared by corners
+/// with the same attributes, and normals are generated if any are missing.
+fn build_mesh(obj: &Obj, group: &Group, basis: glam::Mat4, normal_mapped: bool) -> scene::Mesh {
+    let mut ids: HashMap<Corner, u32> = HashMap::new();
+    let mut corners = Vec::new();
+    let indices: Vec<glam::UVec3> = group
+        .triangles
+        .iter()
+        .map(|triangle| {
+            glam::UVec3::from_array(triangle.map(|corner| {
+                *ids.entry(corner).or_insert_with(|| {
+                    corners.push(corner);
+                    corners.len() as u32 - 1
+                })
+            }))
+        })
+        .collect();
+
+    let mesh = scene::Mesh {
+        vertices: corners.iter().map(|c| obj.positions[c.position]).collect(),
+        indices,
+        uvs: corners
+            .iter()
+            .map(|c| c.uv.map(|i| obj.uvs[i]))
+            .collect::<Option<_>>()
+            .unwrap_or_default(),
+        normals: corners
+            .iter()
+            .map(|c| c.normal.map(|i| obj.normals[i]))
+            .collect::<Option<_>>()
+            .unwrap_or_default(),
+        tangents: Vec::new(),
+        colors: Vec::new(),
+    };
+
+    let mesh = geometry::convert_mesh(mesh, basis);
+    if normal_mapped && !mesh.uvs.is_empty() {
+        geometry::generate_tangents(mesh)
+    } else {
+        mesh
+    }
+}
+
+/// Lines with their 1-based numbers, joining those continued with a
+/// backslash and removing comments
+fn logical_lines(source: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut pending: Option<(usize, String)> = None;
+    for (i, line) in source.lines().enumerate() {
+        let line = strip_comment(line);
+        let (number, mut joined) = pending.take().unwrap_or((i + 1, String::new()));
+        match line.trim_end().strip_suffix('\\') {
+            Some(start) => {
+                joined.push_str(start);
+                joined.push(' ');
+                pending = Some((number, joined));
+            }
+            None => {
+                joined.push_str(line);
+                lines.push((number, joined));
+            }
+        }
+    }
+
+    lines.extend(pending);
+    lines
+}
+
+/// A line without its comment. `#` only starts one at the start of the line
+/// or after whitespace, since it can appear in file names like `wall#2.png`.
+fn strip_comment(line: &str) -> &str {
+    let start = line.char_indices().find(|(i, c)| {
+        *c == '#'
+            && line[..*i]
+                .chars()
+                .next_back()
+                .is_none_or(char::is_whitespace)
+    });
+
+    match start {
+        Some((i, _)) => &line[..i],
+        None => line,
+    }
+}
+
+/// Parses every argument as a number, requiring at least `count` of them
+fn floats(args: &[&str], count: usize) -> Result<Vec<f32>, String> {
+    if args.len() < count {
+        return Err(format!(
+            "Expected {} numbers but found {}",
+            count,
+            args.len()
+        ));
+    }
+
+    args.iter()
+        .map(|arg| {
+            arg.parse::<f32>()
+                .map_err(|e| format!("Invalid number '{}': {}", arg, e))
+        })
+        .collect()
+}
+
+/// Parses an MTL color, where a single value is used for all channels
+fn color(args: &[&str]) -> Result<[f32; 3], String> {
+    if args.first() == Some(&"spectral") || args.first() == Some(&"xyz") {
+        return Err("Only RGB colors are supported".to_string());
+    }
+
+    let values = floats(args, 1)?;
+    Ok(match values[..] {
+        [r, g, b, ..] => [r, g, b],
+        _ => [values[0]; 3],
+    })
+}
+
+/// Finds the file name in a texture statement, skipping the options before it
+fn texture_path(args: &[&str]) -> Result<String, String> {
+    let mut i = 0;
+    while let Some(option) = args.get(i).filter(|arg| arg.starts_with('-')) {
+        // Options take up to three numbers, or a single word like `on` or `off`
+        i += 1;
+        match *option {
+            "-clamp" | "-blendu" | "-blendv" | "-imfchan" | "-type" => i += 1,
+            _ => {
+                while i < args.len() - 1 && args[i].parse::<f32>().is_ok() {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    match args.get(i..) {
+        Some(name) if !name.is_empty() => Ok(name.join(" ")),
+        _ => Err("Missing texture file name".to_string()),
+    }
+}
+
+/// Parses a face corner like `1`, `1/2`, `1//3` or `1/2/3`, where negative
+/// indices count back from the last vertex read
+fn corner(arg: &str, obj: &Obj) -> Result<Corner, String> {
+    let index = |value: Option<&str>, count: usize, name: &str| -> Result<Option<usize>, String> {
+        let Some(value) = value.filter(|value| !value.is_empty()) else {
+            return Ok(None);
+        };
+
+        let index: i64 = value
+            .parse()
+            .map_err(|e| format!("Invalid {} index '{}': {}", name, value, e))?;
+
+        let resolved = match index {
+            i if i > 0 => i - 1,
+            i => count as i64 + i,
+        };
+
+        match usize::try_from(resolved) {
+            Ok(resolved) if resolved < count => Ok(Some(resolved)),
+            _ => Err(format!(
+                "The {} index {} is out of range (count: {})",
+                name, index, count
+            )),
+        }
+    };
+
+    let mut parts = arg.split('/');
+    let position = index(parts.next(), obj.positions.len(), "position")?
+        .ok_or_else(|| format!("Missing position index in '{}'", arg))?;
+
+    Ok(Corner {
+        position,
+        uv: index(parts.next(), obj.uvs.len(), "UV")?,
+        normal: index(parts.next(), obj.normals.len(), "normal")?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CUBE_FACES: &str = "
+        mtllib cube.mtl
+        v 0 0 0
+        v 1 0 0
+        v 1 1 0
+        v 0 1 0
+        vt 0 0
+        vt 1 0
+        vt 1 1
+        vn 0 0 1
+
+        o Front
+        usemtl red
+        f 1/1/1 2/2/1 3/3/1 4//1
+        usemtl blue
+        f -4 -2 -1
+        l 1 2
+
+        # Back to the first material, on a continued line
+        g Front
+        usemtl red
+        f 1 2 \\
+          3
+    ";
+
+    #[test]
+    fn test_parse_obj() {
+        let mut warnings = Vec::new();
+        let obj = parse_obj(CUBE_FACES, &mut warnings).unwrap();
+
+        assert_eq!(obj.libraries, ["cube.mtl"]);
+        assert_eq!(obj.uvs[2], glam::vec2(1.0, 0.0));
+        assert_eq!(warnings.len(), 1);
+
+        // Faces with the same group and material are collected together
+        let groups: Vec<_> = obj
+            .groups
+            .iter()
+            .map(|g| (g.name.as_str(), g.material.as_deref(), g.triangles.len()))
+            .collect();
+
+        assert_eq!(
+            groups,
+            [("Front", Some("red"), 3), ("Front", Some("blue"), 1)]
+        );
+
+        let quad = &obj.groups[0].triangles;
+        assert_eq!(quad[1][2].normal, Some(0));
+        assert_eq!(quad[1][2].uv, None);
+        assert_eq!(obj.groups[1].triangles[0].map(|c| c.position), [0, 2, 3]);
+    }
+
+    #[test]
+    fn test_out_of_range_index() {
+        let error = parse_obj("v 0 0 0\nf 1 2 3", &mut Vec::new()).unwrap_err();
+        assert!(error.contains("line 2"));
+    }
+
+    #[test]
+    fn test_parse_mtl() {
+        let source = "
+            newmtl glass
+            Kd 0.5 0.25 1
+            Ni 1.33
+            d 0.1
+            Ns 0
+
+            newmtl lamp
+            Ke 4 2 0
+            map_Kd -s 2 2 1 -clamp on textures/lamp#2 base.png # The lamp's shade
+            bump -bm 0.5 normal.png
+        ";
+
+        let mut warnings = Vec::new();
+        let materials = parse_mtl(source, Path::new("models"), &mut warnings).unwrap();
+
+        // Dissolve is opacity, not refraction, so partly transparent materials don't become glass
+        let glass = &materials["glass"].material;
+        assert_eq!(glass.color, glam::vec4(0.5, 0.25, 1.0, 1.0));
+        assert_eq!(glass.ior, 1.33);
+        assert_eq!(glass.transmission, 0.0);
+        assert_eq!(glass.roughness, 1.0);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].location, "line 5");
+
+        let lamp = &materials["lamp"];
+        assert_eq!(lamp.material.emission(), glam::vec3(4.0, 2.0, 0.0));
+        assert_eq!(
+            lamp.texture.as_deref(),
+            Some(Path::new("models/textures/lamp#2 base.png"))
+        );
+
+        assert_eq!(
+            lamp.normal_texture.as_deref(),
+            Some(Path::new("models/normal.png"))
+        );
+
+        assert_eq!(lamp.material.normal_scale, 0.5);
+    }
+
+    #[test]
+    fn test_build_mesh() {
+        let obj = parse_obj(CUBE_FACES, &mut Vec::new()).unwrap();
+        let basis = ImportOptions::default().basis();
+        let mesh = build_mesh(&obj, &obj.groups[0], basis, false);
+
+        // Corners with different UVs stay separate vertices
+        assert_eq!(mesh.vertices.len(), 7);
+        assert_eq!(mesh.indices.len(), 3);
+
+        // Not every corner has a normal, so they're generated, facing the
+        // same way once mirrored into left-handed coordinates
+        for normal in &mesh.normals {
+            assert!(normal.abs_diff_eq(glam::vec3(0.0, 0.0, -1.0), 1E-6));
+        }
+    }
+}
@@ -0,0 +1,544 @@
+use std::{fs, path::Path};
+
+use crate::{scene, traits::SceneLoader};
+
+use super::{default_material, framing_camera, geometry, ImportOptions, Warning};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Property {
+    Scalar {
+        name: String,
+        scalar: Scalar,
+    },
+    List {
+        name: String,
+        count: Scalar,
+        item: Scalar,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+#[derive(Debug)]
+struct Header {
+    encoding: Encoding,
+    elements: Vec<Element>,
+}
+
+/// Where a vertex property's value goes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Role {
+    Position(usize),
+    Normal(usize),
+    Color(usize),
+    Ignored,
+}
+
+/// Reads values from the body of a PLY file, in whichever encoding it uses
+enum Reader<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { bytes: &'a [u8], big_endian: bool },
+}
+
+/// Loads PLY files, in ASCII or binary, as a single object. Vertex colors
+/// multiply a white material, so they show as they are.
+pub struct PlyLoader;
+
+impl SceneLoader for PlyLoader {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["ply"]
+    }
+
+    fn matches(&self, start: &[u8], _size: u64) -> bool {
+        start.starts_with(b"ply\n") || start.starts_with(b"ply\r\n")
+    }
+
+    fn load(
+        &self,
+        path: &Path,
+        options: &ImportOptions,
+    ) -> Result<(scene::Scene, Vec<Warning>), String> {
+        load(path, options)
+    }
+}
+
+fn load(path: &Path, options: &ImportOptions) -> Result<(scene::Scene, Vec<Warning>), String> {
+    let bytes =
+        fs::read(path).map_err(|e| format!("Unable to read file: {}\n  {}", path.display(), e))?;
+
+    let mut warnings = Vec::new();
+    let mesh = geometry::convert_mesh(parse(&bytes, &mut warnings)?, options.basis());
+
+    let bounds = mesh.vertices.iter().fold(
+        (glam::Vec3::splat(f32::INFINITY), glam::Vec3::NEG_INFINITY),
+        |(min, max), vertex| (min.min(*vertex), max.max(*vertex)),
+    );
+
+    let material = match mesh.colors.is_empty() {
+        true => default_material(),
+        false => scene::Material {
+            color: glam::Vec4::ONE,
+            ..default_material()
+        },
+    };
+
+    let scene = scene::Scene {
+        camera: framing_camera(bounds.0, bounds.1),
+        meshes: Default::default(),
+        images: Vec::new(),
+        materials: Default::default(),
+        objects: vec![scene::Object {
+            surface: mesh.into(),
+            transform: glam::Mat4::IDENTITY.into(),
+            material: material.into(),
+        }],
+        lights: Vec::new(),
+        bvhs: Default::default(),
+    };
+
+    Ok((scene, warnings))
+}
+
+/// Reads the vertices and faces of a PLY file, in source coordinates
+fn parse(bytes: &[u8], warnings: &mut Vec<Warning>) -> Result<scene::Mesh, String> {
+    let (header, body) = parse_header(bytes)?;
+    let mut reader = match header.encoding {
+        Encoding::Ascii => Reader::Ascii(
+            std::str::from_utf8(body)
+                .map_err(|e| format!("Invalid ASCII data: {}", e))?
+                .split_ascii_whitespace(),
+        ),
+        Encoding::LittleEndian => Reader::Binary {
+            bytes: body,
+            big_endian: false,
+        },
+        Encoding::BigEndian => Reader::Binary {
+            bytes: body,
+            big_endian: true,
+        },
+    };
+
+    let mut mesh = scene::Mesh {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        uvs: Vec::new(),
+        normals: Vec::new(),
+        tangents: Vec::new(),
+        colors: Vec::new(),
+    };
+
+    let mut skipped_faces = 0;
+    for element in &header.elements {
+        let error = |e: String| format!("Invalid element '{}':\n  {}", element.name, e);
+        match element.name.as_str() {
+            "vertex" => read_vertices(element, &mut reader, &mut mesh).map_err(error)?,
+            "face" => {
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        let Property::List { name, count, item } = property else {
+                            reader.skip(property).map_err(error)?;
+                            continue;
+                        };
+
+                        if name != "vertex_indices" && name != "vertex_index" {
+                            reader.skip(property).map_err(error)?;
+                            continue;
+                        }
+
+                        let count = reader.read(*count).map_err(error)? as usize;
+                        let corners = (0..count)
+                            .map(|_| reader.read(*item).map(|index| index as u32))
+                            .collect::<Result<Vec<_>, _>>()
+                            .map_err(error)?;
+
+                        if corners.len() < 3 {
+                            skipped_faces += 1;
+                            continue;
+                        }
+
+                        // Polygons are assumed to be convex, so they're split into a fan
+                        for i in 1..corners.len() - 1 {
+                            mesh.indices
+                                .push(glam::uvec3(corners[0], corners[i], corners[i + 1]));
+                        }
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        reader.skip(property).map_err(error)?;
+                    }
+                }
+            }
+        }
+    }
+
+    if skipped_faces > 0 {
+        warnings.push(Warning {
+            location: "element 'face'".to_string(),
+            message: format!("Skipped {} faces with fewer than 3 vertices", skipped_faces),
+        });
+    }
+
+    let vertex_count = mesh.vertices.len() as u32;
+    if let Some(i) = mesh
+        .indices
+        .iter()
+        .find(|i| i.max_element() >= vertex_count)
+    {
+        return Err(format!(
+            "Face {:?} references a vertex out of range (vertex count: {})",
+            i, vertex_count
+        ));
+    }
+
+    Ok(mesh)
+}
+
+fn read_vertices(
+    element: &Element,
+    reader: &mut Reader,
+    mesh: &mut scene::Mesh,
+) -> Result<(), String> {
+    let roles: Vec<Role> = element
+        .properties
+        .iter()
+        .map(|property| match property {
+            Property::Scalar { name, .. } => match name.as_str() {
+                "x" => Role::Position(0),
+                "y" => Role::Position(1),
+                "z" => Role::Position(2),
+                "nx" => Role::Normal(0),
+                "ny" => Role::Normal(1),
+                "nz" => Role::Normal(2),
+                "red" | "r" | "diffuse_red" => Role::Color(0),
+                "green" | "g" | "diffuse_green" => Role::Color(1),
+                "blue" | "b" | "diffuse_blue" => Role::Color(2),
+                "alpha" | "a" | "diffuse_alpha" => Role::Color(3),
+                _ => Role::Ignored,
+            },
+            Property::List { .. } => Role::Ignored,
+        })
+        .collect();
+
+    let has = |role: fn(usize) -> Role| (0..3).all(|i| roles.contains(&role(i)));
+    if !has(Role::Position) {
+        return Err("Vertices need x, y and z properties".to_string());
+    }
+
+    let (has_normals, has_colors) = (has(Role::Normal), has(Role::Color));
+    for _ in 0..element.count {
+        let mut position = glam::Vec3::ZERO;
+        let mut normal = glam::Vec3::ZERO;
+        let mut color = glam::Vec4::ONE;
+        for (property, role) in element.properties.iter().zip(&roles) {
+            let Property::Scalar { scalar, .. } = property else {
+                reader.skip(property)?;
+                continue;
+            };
+
+            let value = reader.read(*scalar)?;
+            match *role {
+                Role::Position(i) => position[i] = value as f32,
+                Role::Normal(i) => normal[i] = value as f32,
+                Role::Color(i) => color[i] = (value / scalar.max()) as f32,
+                Role::Ignored => {}
+            }
+        }
+
+        mesh.vertices.push(position);
+        if has_normals {
+            mesh.normals.push(normal);
+        }
+
+        // Colors are sRGB encoded, but blended in linear space
+        if has_colors {
+            let rgb = color.truncate().to_array().map(srgb_to_linear);
+            mesh.colors.push(glam::Vec3::from(rgb).extend(color.w));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the header, returning it with the rest of the file
+fn parse_header(bytes: &[u8]) -> Result<(Header, &[u8]), String> {
+    const END: &[u8] = b"end_header";
+
+    if !bytes.starts_with(b"ply") {
+        return Err("Missing 'ply' at the start of the file".to_string());
+    }
+
+    let end = bytes
+        .windows(END.len())
+        .position(|window| window == END)
+        .ok_or_else(|| "Missing 'end_header'".to_string())?;
+
+    // The body starts after the end of the header's last line
+    let body = bytes[end..]
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .map_or(bytes.len(), |i| end + i + 1);
+
+    let text = std::str::from_utf8(&bytes[..end]).map_err(|e| format!("Invalid header: {}", e))?;
+
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for (number, line) in text.lines().enumerate().skip(1) {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let error = |e: String| format!("Invalid header on line {}:\n  {}", number + 1, e);
+        match tokens[..] {
+            ["format", format, _] => {
+                encoding = Some(match format {
+                    "ascii" => Encoding::Ascii,
+                    "binary_little_endian" => Encoding::LittleEndian,
+                    "binary_big_endian" => Encoding::BigEndian,
+                    _ => return Err(error(format!("Unknown format '{}'", format))),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|e| error(format!("Invalid count '{}': {}", count, e)))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => {
+                let property = Property::List {
+                    name: name.to_string(),
+                    count: Scalar::parse(count).map_err(error)?,
+                    item: Scalar::parse(item).map_err(error)?,
+                };
+
+                elements
+                    .last_mut()
+                    .ok_or_else(|| error("Property outside an element".to_string()))?
+                    .properties
+                    .push(property);
+            }
+            ["property", scalar, name] => {
+                let property = Property::Scalar {
+                    name: name.to_string(),
+                    scalar: Scalar::parse(scalar).map_err(error)?,
+                };
+
+                elements
+                    .last_mut()
+                    .ok_or_else(|| error("Property outside an element".to_string()))?
+                    .properties
+                    .push(property);
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(error(format!("Unexpected '{}'", line.trim()))),
+        }
+    }
+
+    let encoding = encoding.ok_or_else(|| "Missing 'format' in header".to_string())?;
+    Ok((Header { encoding, elements }, &bytes[body..]))
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(format!("Unknown type '{}'", name)),
+        })
+    }
+
+    /// The value of full intensity for colors of this type
+    fn max(self) -> f64 {
+        match self {
+            Scalar::I8 => i8::MAX as f64,
+            Scalar::U8 => u8::MAX as f64,
+            Scalar::I16 => i16::MAX as f64,
+            Scalar::U16 => u16::MAX as f64,
+            Scalar::I32 => i32::MAX as f64,
+            Scalar::U32 => u32::MAX as f64,
+            Scalar::F32 | Scalar::F64 => 1.0,
+        }
+    }
+}
+
+impl Reader<'_> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, String> {
+        let (bytes, big_endian) = match self {
+            Reader::Ascii(tokens) => {
+                let token = tokens
+                    .next()
+                    .ok_or_else(|| "Unexpected end of file".to_string())?;
+
+                return token
+                    .parse()
+                    .map_err(|e| format!("Invalid number '{}': {}", token, e));
+            }
+            Reader::Binary { bytes, big_endian } => (bytes, *big_endian),
+        };
+
+        let size = match scalar {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        };
+
+        if bytes.len() < size {
+            return Err("Unexpected end of file".to_string());
+        }
+
+        let mut value = [0; 8];
+        value[..size].copy_from_slice(&bytes[..size]);
+        *bytes = &bytes[size..];
+        if big_endian {
+            value[..size].reverse();
+        }
+
+        let [a, b, c, d, ..] = value;
+        Ok(match scalar {
+            Scalar::I8 => a as i8 as f64,
+            Scalar::U8 => a as f64,
+            Scalar::I16 => i16::from_le_bytes([a, b]) as f64,
+            Scalar::U16 => u16::from_le_bytes([a, b]) as f64,
+            Scalar::I32 => i32::from_le_bytes([a, b, c, d]) as f64,
+            Scalar::U32 => u32::from_le_bytes([a, b, c, d]) as f64,
+            Scalar::F32 => f32::from_le_bytes([a, b, c, d]) as f64,
+            Scalar::F64 => f64::from_le_bytes(value),
+        })
+    }
+
+    fn skip(&mut self, property: &Property) -> Result<(), String> {
+        match property {
+            Property::Scalar { scalar, .. } => {
+                self.read(*scalar)?;
+            }
+            Property::List { count, item, .. } => {
+                let count = self.read(*count)? as usize;
+                for _ in 0..count {
+                    self.read(*item)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HEADER: &str = "ply
+format FORMAT 1.0
+comment A colored triangle and a quad
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 2
+property list uchar int vertex_indices
+property uchar flags
+end_header
+";
+
+    #[test]
+    fn test_parse_ascii() {
+        let source = HEADER.replace("FORMAT", "ascii")
+            + "0 0 0 255 0 0\n1 0 0 0 255 0\n1 1 0 0 0 255\n0 1 0 255 255 255\n"
+            + "3 0 1 2 7\n4 0 1 2 3 7\n";
+
+        let mesh = parse(source.as_bytes(), &mut Vec::new()).unwrap();
+        assert_eq!(mesh.vertices[2], glam::vec3(1.0, 1.0, 0.0));
+        assert_eq!(mesh.colors[1], glam::vec4(0.0, 1.0, 0.0, 1.0));
+        assert!(mesh.normals.is_empty());
+        assert_eq!(
+            mesh.indices,
+            [
+                glam::uvec3(0, 1, 2),
+                glam::uvec3(0, 1, 2),
+                glam::uvec3(0, 2, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_binary() {
+        let mut bytes = HEADER.replace("FORMAT", "binary_big_endian").into_bytes();
+        for (position, color) in [([0.0f32, 0.0, 0.0], 255), ([1.0, 0.0, 0.0], 0)]
+            .iter()
+            .chain(&[([0.0, 2.0, 0.0], 188), ([0.0, 0.0, 3.0], 0)])
+        {
+            for value in position {
+                bytes.extend(value.to_be_bytes());
+            }
+
+            bytes.extend([*color, *color, *color]);
+        }
+
+        bytes.extend([3u8]);
+        for index in [1i32, 2, 3] {
+            bytes.extend(index.to_be_bytes());
+        }
+
+        bytes.extend([0u8]);
+
+        // Faces with too few vertices are skipped
+        bytes.extend([2u8, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+
+        let mut warnings = Vec::new();
+        let mesh = parse(&bytes, &mut warnings).unwrap();
+        assert_eq!(mesh.vertices[3], glam::vec3(0.0, 0.0, 3.0));
+        assert_eq!(mesh.indices, [glam::uvec3(1, 2, 3)]);
+        assert_eq!(warnings.len(), 1);
+
+        // sRGB 188 is about half intensity
+        assert!((mesh.colors[2].x - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let source = HEADER.replace("FORMAT", "binary_little_endian") + "\0\0";
+        assert!(parse(source.as_bytes(), &mut Vec::new()).is_err());
+    }
+}
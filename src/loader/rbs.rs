@@ -0,0 +1,364 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{bvh, scene, traits::SceneLoader};
+
+use super::{ImportOptions, Warning};
+
+/// The first bytes of every binary scene file
+pub const MAGIC: [u8; 8] = *b"RAYBABY\0";
+
+/// The layout version in the header, raised whenever the layout changes in a
+/// way older builds can't read
+pub const VERSION: u32 = 1;
+
+/// The magic number, version, reserved flags, and the metadata's offset and length
+pub const HEADER_SIZE: usize = 32;
+
+/// Blobs start at multiples of this, so they can be mapped as arrays of any
+/// of the vector types in the file
+pub const ALIGNMENT: usize = 16;
+
+/// A range of bytes in the file
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct Blob {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Where a mesh's arrays are in the file. Values are little-endian 32-bit
+/// floats or integers, packed without padding.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct MeshBlobs {
+    pub vertices: Blob,
+    pub indices: Blob,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uvs: Option<Blob>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normals: Option<Blob>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tangents: Option<Blob>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub colors: Option<Blob>,
+
+    /// The mesh's bottom-level BVH, so it doesn't need building again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bvh: Option<BvhBlobs>,
+}
+
+/// A BVH's nodes, each its minimum corner, `first`, maximum corner and
+/// `count`, and the order of the triangles its leaves reference
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct BvhBlobs {
+    pub nodes: Blob,
+    pub indices: Blob,
+}
+
+/// Everything in the file besides its blobs, written as RON after them
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct Metadata {
+    /// The scene without its meshes, and with its images' pixels left out.
+    /// Every object's surface is a sphere or a named mesh.
+    pub scene: scene::Scene,
+
+    pub meshes: BTreeMap<String, MeshBlobs>,
+
+    /// The pixels of each of the scene's images
+    pub images: Vec<Blob>,
+}
+
+/// Loads binary scenes written by `convert`. Like RON scenes they're already
+/// in scene coordinates, so import options don't apply.
+///
+/// The file is read into memory and its blobs are copied out into the scene's
+/// meshes and images, so loading needs about twice the file's size at its peak.
+/// What's saved over other formats is parsing and building BVHs, not copying.
+pub struct RbsLoader;
+
+impl SceneLoader for RbsLoader {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["rbs"]
+    }
+
+    fn matches(&self, start: &[u8], _size: u64) -> bool {
+        start.starts_with(&MAGIC)
+    }
+
+    fn load(
+        &self,
+        path: &Path,
+        _options: &ImportOptions,
+    ) -> Result<(scene::Scene, Vec<Warning>), String> {
+        let bytes = fs::read(path)
+            .map_err(|e| format!("Unable to read file: {}\n  {}", path.display(), e))?;
+
+        Ok((read(&bytes)?, Vec::new()))
+    }
+}
+
+/// Reads a scene from the contents of a binary scene file
+pub fn read(bytes: &[u8]) -> Result<scene::Scene, String> {
+    let header = bytes
+        .get(..HEADER_SIZE)
+        .ok_or_else(|| "File is too short for a header".to_string())?;
+
+    if header[..8] != MAGIC {
+        return Err("Not a binary scene file".to_string());
+    }
+
+    let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let version = word(8);
+    if version == 0 || version > VERSION {
+        return Err(format!(
+            "File is version {}, but only versions 1 to {} can be read",
+            version, VERSION
+        ));
+    }
+
+    let metadata = {
+        let long = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        let blob = Blob {
+            offset: long(16),
+            length: long(24),
+        };
+
+        let source = std::str::from_utf8(slice(bytes, blob)?)
+            .map_err(|e| format!("Invalid metadata:\n  {}", e))?;
+        ron::from_str::<Metadata>(source).map_err(|e| format!("Invalid metadata:\n  {}", e))?
+    };
+
+    let mut scene = metadata.scene;
+    if scene.images.len() != metadata.images.len() {
+        return Err(format!(
+            "Expected pixels for {} images but found {}",
+            scene.images.len(),
+            metadata.images.len()
+        ));
+    }
+
+    for (image, blob) in scene.images.iter_mut().zip(&metadata.images) {
+        image.pixels = slice(bytes, *blob)?.to_vec();
+    }
+
+    for (name, blobs) in &metadata.meshes {
+        let (mesh, bvh) =
+            read_mesh(bytes, blobs).map_err(|e| format!("{} (in mesh '{}')", e, name))?;
+
+        if let Some(bvh) = bvh {
+            scene.bvhs.insert(name.clone(), bvh);
+        }
+
+        scene.meshes.insert(name.clone(), mesh);
+    }
+
+    Ok(scene)
+}
+
+fn read_mesh(bytes: &[u8], blobs: &MeshBlobs) -> Result<(scene::Mesh, Option<bvh::Bvh>), String> {
+    // Missing attributes are read as empty arrays
+    let mesh = scene::Mesh {
+        vertices: array(bytes, blobs.vertices, vec3)?,
+        indices: array(bytes, blobs.indices, glam::UVec3::from_array)?,
+        uvs: optional(bytes, blobs.uvs, vec2)?,
+        normals: optional(bytes, blobs.normals, vec3)?,
+        tangents: optional(bytes, blobs.tangents, vec4)?,
+        colors: optional(bytes, blobs.colors, vec4)?,
+    };
+
+    let bvh = match &blobs.bvh {
+        Some(blobs) => Some(bvh::Bvh {
+            nodes: array(bytes, blobs.nodes, node)?,
+            indices: array(bytes, blobs.indices, |[i]| i)?,
+        }),
+        None => None,
+    };
+
+    Ok((mesh, bvh))
+}
+
+/// The bytes of a blob, if it's within the file
+fn slice(bytes: &[u8], blob: Blob) -> Result<&[u8], String> {
+    let start = blob.offset as usize;
+    start
+        .checked_add(blob.length as usize)
+        .and_then(|end| bytes.get(start..end))
+        .ok_or_else(|| {
+            format!(
+                "Blob of {} bytes at {} runs past the end of the file",
+                blob.length, blob.offset
+            )
+        })
+}
+
+/// Reads a blob as an array of values made of `N` 32-bit words each
+fn array<T, const N: usize>(
+    bytes: &[u8],
+    blob: Blob,
+    convert: fn([u32; N]) -> T,
+) -> Result<Vec<T>, String> {
+    let data = slice(bytes, blob)?;
+    if data.len() % (N * 4) != 0 {
+        return Err(format!(
+            "Blob of {} bytes at {} isn't a whole number of {}-byte values",
+            blob.length,
+            blob.offset,
+            N * 4
+        ));
+    }
+
+    let values = data.chunks_exact(N * 4).map(|value| {
+        convert(std::array::from_fn(|i| {
+            u32::from_le_bytes(value[i * 4..i * 4 + 4].try_into().unwrap())
+        }))
+    });
+
+    Ok(values.collect())
+}
+
+fn optional<T, const N: usize>(
+    bytes: &[u8],
+    blob: Option<Blob>,
+    convert: fn([u32; N]) -> T,
+) -> Result<Vec<T>, String> {
+    blob.map_or(Ok(Vec::new()), |blob| array(bytes, blob, convert))
+}
+
+fn vec2(words: [u32; 2]) -> glam::Vec2 {
+    glam::Vec2::from_array(words.map(f32::from_bits))
+}
+
+fn vec3(words: [u32; 3]) -> glam::Vec3 {
+    glam::Vec3::from_array(words.map(f32::from_bits))
+}
+
+fn vec4(words: [u32; 4]) -> glam::Vec4 {
+    glam::Vec4::from_array(words.map(f32::from_bits))
+}
+
+fn node(words: [u32; 8]) -> bvh::Node {
+    let [min_x, min_y, min_z, first, max_x, max_y, max_z, count] = words;
+    bvh::Node {
+        bounds: bvh::Aabb {
+            min: vec3([min_x, min_y, min_z]),
+            max: vec3([max_x, max_y, max_z]),
+        },
+        first,
+        count,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exporter;
+
+    fn scene() -> scene::Scene {
+        let mut scene =
+            ::ron::from_str::<scene::Scene>(include_str!("../../examples/01-spheres.ron"))
+                .expect("Unable to parse scene");
+        scene
+            .objects
+            .retain(|object| matches!(object.surface, scene::Surface::Sphere { .. }));
+
+        let quad = scene::Mesh {
+            vertices: vec![
+                glam::vec3(0.0, 0.0, 0.0),
+                glam::vec3(1.0, 0.0, 0.0),
+                glam::vec3(1.0, 1.0, 0.0),
+                glam::vec3(0.0, 1.0, 0.0),
+            ],
+            indices: vec![glam::uvec3(0, 1, 2), glam::uvec3(0, 2, 3)],
+            uvs: Vec::new(),
+            normals: vec![glam::Vec3::NEG_Z; 4],
+            tangents: Vec::new(),
+            colors: vec![glam::Vec4::ONE, glam::Vec4::X, glam::Vec4::Y, glam::Vec4::Z],
+        };
+
+        let mut object = scene.objects[0].clone();
+        object.surface = scene::Surface::Mesh {
+            name: "quad".to_string(),
+        };
+        scene.objects.push(object.clone());
+
+        // A library material whose texture has to follow the image
+        let scene::MaterialRef::Inline(material) = &object.material else {
+            panic!("Expected an inline material");
+        };
+        let textured = scene::Material {
+            texture: Some(scene::Texture::Image(0)),
+            ..material.clone()
+        };
+
+        object.surface = quad.clone().into();
+        object.material = scene::MaterialRef::Named("textured".to_string());
+        scene.objects.push(object);
+        scene.materials.insert("textured".to_string(), textured);
+
+        scene.meshes.insert("quad".to_string(), quad);
+        scene.images.push(scene::Image {
+            width: 1,
+            height: 2,
+            pixels: vec![0, 1, 2, 3, 252, 253, 254, 255],
+        });
+
+        scene
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let scene = scene();
+        let bytes = exporter::export(&scene, exporter::Format::Rbs { bvh: true }).unwrap();
+        let loaded = read(&bytes).unwrap();
+
+        // The inline mesh is moved into the named meshes, and both get a BVH
+        let quad = &scene.meshes["quad"];
+        assert_eq!(loaded.meshes.len(), 2);
+        assert!(loaded.meshes.values().all(|mesh| mesh == quad));
+        for (name, bvh) in &loaded.bvhs {
+            bvh.validate(loaded.meshes[name].indices.len()).unwrap();
+            assert_eq!(
+                *bvh,
+                bvh::Bvh::build_triangles(&quad.vertices, &quad.indices)
+            );
+        }
+
+        let last = scene.objects.len() - 1;
+        assert_eq!(loaded.objects[..last], scene.objects[..last]);
+        assert!(matches!(
+            &loaded.objects[last].surface,
+            scene::Surface::Mesh { name } if loaded.meshes.contains_key(name)
+        ));
+
+        assert_eq!(loaded.images, scene.images);
+        assert_eq!(loaded.materials, scene.materials);
+        assert_eq!(loaded.camera, scene.camera);
+        assert_eq!(loaded.lights, scene.lights);
+
+        let bytes = exporter::export(&scene, exporter::Format::Rbs { bvh: false }).unwrap();
+        assert!(read(&bytes).unwrap().bvhs.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let bytes = exporter::export(&scene(), exporter::Format::Rbs { bvh: true }).unwrap();
+        assert!(RbsLoader.matches(&bytes, bytes.len() as u64));
+
+        // Blobs past the end of a truncated file are caught rather than read
+        let metadata = u64::from_le_bytes(bytes[16..24].try_into().unwrap()) as usize;
+        let truncated = read(&bytes[..metadata]).unwrap_err();
+        assert!(truncated.contains("past the end of the file"));
+        assert!(read(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read(&bytes[..8]).is_err());
+
+        for version in [0, VERSION + 1] {
+            let mut unknown = bytes.clone();
+            unknown[8..12].copy_from_slice(&version.to_le_bytes());
+            assert!(read(&unknown).unwrap_err().contains("version"));
+        }
+    }
+}
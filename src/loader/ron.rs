@@ -0,0 +1,490 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{scene, traits::SceneLoader};
+
+use super::{geometry, Format, ImportOptions, Warning};
+
+/// Loads scenes in raybaby's own RON format. They're already in scene
+/// coordinates, so import options don't apply.
+///
+/// Scenes can list other scene files in `includes`, whose meshes, images,
+/// materials, objects and lights are added to their own, and objects can use the meshes
+/// in a file of any format with a `MeshFile` surface. Both are loaded along
+/// with the scene, relative to the file naming them.
+pub struct RonLoader;
+
+impl SceneLoader for RonLoader {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["ron"]
+    }
+
+    fn matches(&self, start: &[u8], _size: u64) -> bool {
+        // Skip comments and extension attributes, then expect the scene struct,
+        // with or without its name
+        let start = String::from_utf8_lossy(start);
+        let body = start
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with("#!"))
+            .collect::<String>();
+
+        body.trim_start_matches("Scene")
+            .trim_start()
+            .starts_with('(')
+    }
+
+    fn load(
+        &self,
+        path: &Path,
+        _options: &ImportOptions,
+    ) -> Result<(scene::Scene, Vec<Warning>), String> {
+        let mut resolver = Resolver::default();
+        let file = resolver.load(path)?;
+
+        let scene = scene::Scene {
+            camera: file.camera.ok_or("Scene has no camera")?,
+            meshes: file.meshes,
+            images: file.images,
+            materials: file.materials,
+            objects: file.objects,
+            lights: file.lights,
+            bvhs: Default::default(),
+        };
+
+        Ok((scene, resolver.warnings))
+    }
+}
+
+/// A scene file as written, before its includes and mesh files are loaded.
+/// Only the scene being loaded needs a camera, and included files' cameras
+/// are ignored.
+#[derive(Deserialize)]
+#[serde(rename = "Scene", deny_unknown_fields)]
+struct SceneFile {
+    #[serde(default, deserialize_with = "some")]
+    camera: Option<scene::Camera>,
+
+    /// Other scene files to add to this one, relative to it
+    #[serde(default)]
+    includes: Vec<PathBuf>,
+
+    #[serde(default)]
+    meshes: BTreeMap<String, scene::Mesh>,
+
+    #[serde(default)]
+    images: Vec<scene::Image>,
+
+    #[serde(default)]
+    materials: BTreeMap<String, scene::Material>,
+
+    #[serde(default)]
+    objects: Vec<scene::Object>,
+
+    #[serde(default)]
+    lights: Vec<scene::Light>,
+}
+
+/// Reads the camera without `Some(...)` around it, as in `scene::Scene`
+fn some<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Default)]
+struct Resolver {
+    /// The files being loaded, each included by the one before, as canonical
+    /// paths to compare and as the paths they were named by
+    chain: Vec<(PathBuf, PathBuf)>,
+
+    /// Meshes already loaded from mesh files, by canonical path and format
+    mesh_files: HashMap<(PathBuf, Format), scene::Mesh>,
+
+    warnings: Vec<Warning>,
+}
+
+impl Resolver {
+    /// Loads a scene file along with everything it includes
+    fn load(&mut self, path: &Path) -> Result<SceneFile, String> {
+        // Files that can't be found fail to read below, naming the chain including them
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+        if let Some(start) = self.chain.iter().position(|(c, _)| *c == canonical) {
+            let cycle: Vec<String> = self.chain[start..]
+                .iter()
+                .map(|(_, named)| named.display().to_string())
+                .chain([path.display().to_string()])
+                .collect();
+
+            return Err(format!("Scene includes itself: {}", cycle.join(" -> ")));
+        }
+
+        self.chain.push((canonical, path.to_path_buf()));
+        let file = self.read(path);
+        self.chain.pop();
+        file
+    }
+
+    /// Names the chain of files including the one being loaded in an error
+    /// from that file. Errors from the scene being loaded are left as they are.
+    fn context(&self, e: String) -> String {
+        if self.chain.len() < 2 {
+            return e;
+        }
+
+        let chain: Vec<String> = self
+            .chain
+            .iter()
+            .map(|(_, named)| named.display().to_string())
+            .collect();
+
+        format!("Unable to load {}:\n  {}", chain.join(" -> "), e)
+    }
+
+    fn read(&mut self, path: &Path) -> Result<SceneFile, String> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read file: {}\n  {}", path.display(), e))
+            .map_err(|e| self.context(e))?;
+
+        let mut file =
+            ::ron::from_str::<SceneFile>(&source).map_err(|e| self.context(e.to_string()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        for include in &mut file.includes {
+            *include = base.join(&*include);
+        }
+
+        for material in file.materials.values_mut() {
+            material.resolve_paths(base);
+        }
+
+        for object in &mut file.objects {
+            object.resolve_paths(base);
+        }
+
+        for (i, object) in file.objects.iter_mut().enumerate() {
+            if let scene::Surface::MeshFile { path, format } = &object.surface {
+                let name = self
+                    .mesh_file(path, *format)
+                    .and_then(|(name, mesh)| {
+                        insert("Mesh", &mut file.meshes, name.clone(), mesh)?;
+                        Ok(name)
+                    })
+                    .map_err(|e| {
+                        self.context(format!(
+                            "Unable to load mesh file for object {}: {}\n  {}",
+                            i,
+                            path.display(),
+                            e
+                        ))
+                    })?;
+
+                object.surface = scene::Surface::Mesh { name };
+            }
+        }
+
+        // Errors loading included files already name the chain including them
+        for include in std::mem::take(&mut file.includes) {
+            let included = self.load(&include)?;
+            merge(&mut file, included).map_err(|e| {
+                self.context(format!("Unable to include {}:\n  {}", include.display(), e))
+            })?;
+        }
+
+        Ok(file)
+    }
+
+    /// Loads the meshes in a file as a single mesh, named by the file's path
+    /// relative to the scene being loaded. Paths are compared canonically, so
+    /// a file reached through different paths is only loaded once.
+    fn mesh_file(
+        &mut self,
+        path: &Path,
+        format: Option<Format>,
+    ) -> Result<(String, scene::Mesh), String> {
+        let format = match format {
+            Some(format) => format,
+            None => Format::detect(path)?,
+        };
+
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let root = self.chain.first().and_then(|(root, _)| root.parent());
+        let name = root
+            .and_then(|root| canonical.strip_prefix(root).ok())
+            .unwrap_or(&canonical)
+            .display()
+            .to_string();
+
+        let key = (canonical, format);
+        if let Some(mesh) = self.mesh_files.get(&key) {
+            return Ok((name, mesh.clone()));
+        }
+
+        let (objects, meshes) = match format {
+            // Scene files can use mesh files too, so they're loaded here to look for cycles
+            Format::Ron => {
+                let file = self.load(path)?;
+                (file.objects, file.meshes)
+            }
+            format => {
+                let (scene, warnings) = format.loader().load(path, &ImportOptions::default())?;
+                self.warnings
+                    .extend(warnings.into_iter().map(|warning| Warning {
+                        location: format!("{}, {}", path.display(), warning.location),
+                        message: warning.message,
+                    }));
+
+                (scene.objects, scene.meshes)
+            }
+        };
+
+        let parts = objects
+            .into_iter()
+            .enumerate()
+            .map(|(i, object)| {
+                let mesh = match object.surface {
+                    scene::Surface::MeshData {
+                        vertices,
+                        indices,
+                        uvs,
+                        normals,
+                        tangents,
+                        colors,
+                    } => scene::Mesh {
+                        vertices,
+                        indices,
+                        uvs,
+                        normals,
+                        tangents,
+                        colors,
+                    },
+                    scene::Surface::Mesh { name } => meshes
+                        .get(&name)
+                        .cloned()
+                        .ok_or_else(|| format!("Object {} uses an unknown mesh: {}", i, name))?,
+                    scene::Surface::Sphere { .. } | scene::Surface::MeshFile { .. } => {
+                        return Err(format!("Object {} is a sphere, not a mesh", i))
+                    }
+                };
+
+                let transform = glam::Affine3A::from(&object.transform);
+                Ok(geometry::transform_mesh(mesh, transform.into()))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        if parts.is_empty() {
+            return Err("File has no meshes".to_string());
+        }
+
+        let mesh = geometry::merge_meshes(parts);
+        self.mesh_files.insert(key, mesh.clone());
+        Ok((name, mesh))
+    }
+}
+
+/// Adds a mesh or material to a scene file's, unless one with the same name
+/// is already there, in which case they have to match
+fn insert<T: PartialEq>(
+    kind: &str,
+    values: &mut BTreeMap<String, T>,
+    name: String,
+    value: T,
+) -> Result<(), String> {
+    match values.get(&name) {
+        Some(existing) if *existing != value => Err(format!(
+            "{} '{}' is defined more than once, differently",
+            kind, name
+        )),
+        Some(_) => Ok(()),
+        None => {
+            values.insert(name, value);
+            Ok(())
+        }
+    }
+}
+
+/// Adds an included scene file's contents to the file including it
+fn merge(file: &mut SceneFile, mut included: SceneFile) -> Result<(), String> {
+    // The included images go after the file's own, so its textures need to follow them
+    let offset = file.images.len();
+    let inline = included
+        .objects
+        .iter_mut()
+        .filter_map(|object| match &mut object.material {
+            scene::MaterialRef::Inline(material) => Some(material),
+            scene::MaterialRef::Named(_) => None,
+        });
+
+    for material in included.materials.values_mut().chain(inline) {
+        for texture in material.textures_mut() {
+            if let scene::Texture::Image(index) = texture {
+                *index += offset;
+            }
+        }
+    }
+
+    for (name, mesh) in included.meshes {
+        insert("Mesh", &mut file.meshes, name, mesh)?;
+    }
+
+    for (name, material) in included.materials {
+        insert("Material", &mut file.materials, name, material)?;
+    }
+
+    file.images.extend(included.images);
+    file.objects.extend(included.objects);
+    file.lights.extend(included.lights);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CAMERA: &str = "camera: (
+        lens: Perspective(fov: 50, focal_distance: 10),
+        transform: (position: (0, 0, 0), rotation: (0, 0, 0), scale: (1, 1, 1)),
+        clipping: (near: 0.1, far: 100),
+    ),";
+
+    const PLAIN: &str = "(color: (1, 1, 1, 1), luminosity: 0)";
+
+    fn object(surface: &str, material: &str) -> String {
+        format!(
+            "(
+                surface: {},
+                transform: (position: (0, 0, 5), rotation: (0, 0, 0), scale: (2, 2, 2)),
+                material: {},
+            )",
+            surface, material
+        )
+    }
+
+    /// Writes files to a new directory for a test, returning its path
+    fn write(test: &str, files: &[(&str, String)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("raybaby-{}-{}", test, std::process::id()));
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn test_includes() {
+        let triangle = "solid triangle
+            facet normal 0 0 -1
+                outer loop
+                    vertex 0 0 0
+                    vertex 0 1 0
+                    vertex 1 0 0
+                endloop
+            endfacet
+        endsolid triangle";
+
+        let image = "(width: 1, height: 1, pixels: \"AAAA/w==\")";
+        let props = format!(
+            "Scene(
+                images: [{}],
+                materials: {{\"textured\": (color: (1, 1, 1, 1), luminosity: 0, texture: Some(Image(0)))}},
+                objects: [{}, {}],
+            )",
+            image,
+            object("Sphere(radius: 1)", "\"textured\""),
+            object("MeshFile(path: \"../models/triangle.stl\")", PLAIN),
+        );
+
+        let dir = write(
+            "includes",
+            &[
+                ("models/triangle.stl", triangle.to_string()),
+                ("props/props.ron", props),
+                (
+                    "scene.ron",
+                    format!(
+                        "Scene({} includes: [\"props/props.ron\"], images: [{}], objects: [{}])",
+                        CAMERA,
+                        image,
+                        object(
+                            "MeshFile(path: \"models/triangle.stl\", format: Some(Stl))",
+                            PLAIN
+                        ),
+                    ),
+                ),
+            ],
+        );
+
+        let loaded = RonLoader.load(&dir.join("scene.ron"), &ImportOptions::default());
+        fs::remove_dir_all(&dir).unwrap();
+        let (scene, warnings) = loaded.unwrap();
+        assert!(warnings.is_empty());
+
+        // Included objects and images go after the scene's own, and textures
+        // still find theirs, even in included materials
+        assert_eq!(scene.objects.len(), 3);
+        assert_eq!(scene.images.len(), 2);
+        let material = scene.material(&scene.objects[1].material).unwrap();
+        assert_eq!(material.texture, Some(scene::Texture::Image(1)));
+
+        // The mesh file's transform is applied, and the file is loaded as one
+        // mesh however it's reached, named by its path from the scene
+        assert_eq!(scene.meshes.len(), 1);
+        let mesh = &scene.meshes["models/triangle.stl"];
+        assert_eq!(mesh.indices.len(), 1);
+        assert!(mesh.vertices.contains(&glam::vec3(0.0, 1.0, 0.0)));
+
+        for i in [0, 2] {
+            let scene::Surface::Mesh { name } = &scene.objects[i].surface else {
+                panic!("Mesh file wasn't loaded");
+            };
+            assert_eq!(name, "models/triangle.stl");
+        }
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = write(
+            "include-errors",
+            &[
+                ("a.ron", format!("Scene({} includes: [\"b.ron\"])", CAMERA)),
+                ("b.ron", "(includes: [\"a.ron\"])".to_string()),
+                ("c.ron", format!("({} includes: [\"d.ron\"])", CAMERA)),
+                ("d.ron", "(includes: [\"e.ron\"])".to_string()),
+                ("e.ron", "(objects: [(surface: Cube)])".to_string()),
+                ("f.ron", format!("({} include: [\"e.ron\"])", CAMERA)),
+            ],
+        );
+
+        let load = |name: &str| RonLoader.load(&dir.join(name), &ImportOptions::default());
+        let cycle = load("a.ron");
+        let invalid = load("c.ron");
+        let misspelled = load("f.ron");
+        fs::remove_dir_all(&dir).unwrap();
+
+        let cycle = cycle.unwrap_err();
+        assert!(cycle.starts_with("Scene includes itself"));
+        let files: Vec<&str> = cycle
+            .split(" -> ")
+            .map(|path| path.rsplit(['/', '\\']).next().unwrap())
+            .collect();
+        assert_eq!(files[1..], ["b.ron", "a.ron"]);
+
+        // The error names every file that led to the broken one, once
+        let invalid = invalid.unwrap_err();
+        assert_eq!(invalid.matches("Unable to load").count(), 1);
+        assert!(invalid.contains("c.ron -> "));
+        assert!(invalid.contains("d.ron -> "));
+        assert!(invalid.contains("e.ron:\n"));
+
+        // A misspelled key fails rather than leaving out what it would have added
+        let misspelled = misspelled.unwrap_err();
+        assert!(misspelled.contains("`include`"), "{}", misspelled);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{scene, traits::SceneLoader};
+
+use super::{default_material, framing_camera, geometry, ImportOptions, Warning};
+
+/// The triangles of one solid, which ASCII files can have several of
+#[derive(Debug)]
+struct Solid {
+    name: String,
+    triangles: Vec<[glam::Vec3; 3]>,
+}
+
+/// Loads STL files, in ASCII or binary, with one object for each solid.
+/// Facet normals are ignored, and vertex normals are generated instead.
+pub struct StlLoader;
+
+impl SceneLoader for StlLoader {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["stl"]
+    }
+
+    fn matches(&self, start: &[u8], size: u64) -> bool {
+        // Binary files have no magic number, only a facet count matching their size
+        let binary_size = start
+            .get(80..84)
+            .map(|count| 84 + 50 * u32::from_le_bytes(count.try_into().unwrap()) as u64);
+
+        start.starts_with(b"solid") || binary_size == Some(size)
+    }
+
+    fn load(
+        &self,
+        path: &Path,
+        options: &ImportOptions,
+    ) -> Result<(scene::Scene, Vec<Warning>), String> {
+        load(path, options)
+    }
+}
+
+fn load(path: &Path, options: &ImportOptions) -> Result<(scene::Scene, Vec<Warning>), String> {
+    let bytes =
+        fs::read(path).map_err(|e| format!("Unable to read file: {}\n  {}", path.display(), e))?;
+
+    let basis = options.basis();
+    let mut bounds = (glam::Vec3::splat(f32::INFINITY), glam::Vec3::NEG_INFINITY);
+    let mut objects = Vec::new();
+    let mut warnings = Vec::new();
+    for solid in parse(&bytes)? {
+        if solid.triangles.is_empty() {
+            warnings.push(Warning {
+                location: format!("solid '{}'", solid.name),
+                message: "Skipped solid without any facets".to_string(),
+            });
+
+            continue;
+        }
+
+        let mesh = geometry::convert_mesh(weld(&solid.triangles), basis);
+        for vertex in &mesh.vertices {
+            bounds = (bounds.0.min(*vertex), bounds.1.max(*vertex));
+        }
+
+        objects.push(scene::Object {
+            surface: mesh.into(),
+            transform: glam::Mat4::IDENTITY.into(),
+            material: default_material().into(),
+        });
+    }
+
+    let scene = scene::Scene {
+        camera: framing_camera(bounds.0, bounds.1),
+        meshes: Default::default(),
+        images: Vec::new(),
+        materials: Default::default(),
+        objects,
+        lights: Vec::new(),
+        bvhs: Default::default(),
+    };
+
+    Ok((scene, warnings))
+}
+
+fn parse(bytes: &[u8]) -> Result<Vec<Solid>, String> {
+    // Binary files can start with "solid" too, but their size matches their
+    // facet count, and unlike text they almost always have zero bytes
+    let binary_size = bytes
+        .get(80..84)
+        .map(|count| 84 + 50 * u32::from_le_bytes(count.try_into().unwrap()) as usize);
+
+    let ascii = bytes.starts_with(b"solid") && !bytes.contains(&0);
+    if ascii && binary_size != Some(bytes.len()) {
+        let source = std::str::from_utf8(bytes).map_err(|e| format!("Invalid ASCII STL: {}", e))?;
+        parse_ascii(source)
+    } else {
+        parse_binary(bytes).map(|solid| vec![solid])
+    }
+}
+
+fn parse_binary(bytes: &[u8]) -> Result<Solid, String> {
+    let (Some(header), Some(expected)) = (bytes.get(..80), bytes.get(80..84)) else {
+        return Err("File is too short for a binary STL header".to_string());
+    };
+
+    let count = u32::from_le_bytes(expected.try_into().unwrap()) as usize;
+    let facets = &bytes[84..];
+    if facets.len() < count * 50 {
+        return Err(format!(
+            "Expected {} facets but found {}",
+            count,
+            facets.len() / 50
+        ));
+    }
+
+    // Each facet is a normal, three vertices and an attribute byte count
+    let float =
+        |bytes: &[u8], i: usize| f32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+    let triangles = facets
+        .chunks_exact(50)
+        .take(count)
+        .map(|facet| {
+            [1, 2, 3].map(|v| {
+                glam::vec3(
+                    float(facet, v * 3),
+                    float(facet, v * 3 + 1),
+                    float(facet, v * 3 + 2),
+                )
+            })
+        })
+        .collect();
+
+    let name = String::from_utf8_lossy(header)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string();
+
+    Ok(Solid { name, triangles })
+}
+
+fn parse_ascii(source: &str) -> Result<Vec<Solid>, String> {
+    let mut solids: Vec<Solid> = Vec::new();
+    let mut corners = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let error = |e: String| format!("Invalid line {}:\n  {}", number + 1, e);
+        match tokens.next() {
+            Some("solid") => solids.push(Solid {
+                name: tokens.collect::<Vec<_>>().join(" "),
+                triangles: Vec::new(),
+            }),
+            Some("vertex") => {
+                let values = tokens
+                    .map(|token| {
+                        token
+                            .parse::<f32>()
+                            .map_err(|e| format!("Invalid number '{}': {}", token, e))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(error)?;
+
+                let [x, y, z] = values[..] else {
+                    return Err(error(format!(
+                        "Expected 3 numbers but found {}",
+                        values.len()
+                    )));
+                };
+
+                corners.push(glam::vec3(x, y, z));
+            }
+            Some("endloop") => {
+                let [a, b, c] = corners[..] else {
+                    return Err(error(format!(
+                        "Expected 3 vertices but found {}",
+                        corners.len()
+                    )));
+                };
+
+                solids
+                    .last_mut()
+                    .ok_or_else(|| error("Facet outside a solid".to_string()))?
+                    .triangles
+                    .push([a, b, c]);
+
+                corners.clear();
+            }
+            _ => {}
+        }
+    }
+
+    Ok(solids)
+}
+
+/// Joins the corners of separate triangles at the same position into shared
+/// vertices, so normals can be smoothed across them
+fn weld(triangles: &[[glam::Vec3; 3]]) -> scene::Mesh {
+    let mut ids: HashMap<[u32; 3], u32> = HashMap::new();
+    let mut vertices = Vec::new();
+    let indices = triangles
+        .iter()
+        .map(|triangle| {
+            glam::UVec3::from_array(triangle.map(|vertex| {
+                *ids.entry(vertex.to_array().map(f32::to_bits))
+                    .or_insert_with(|| {
+                        vertices.push(vertex);
+                        vertices.len() as u32 - 1
+                    })
+            }))
+        })
+        .collect();
+
+    scene::Mesh {
+        vertices,
+        indices,
+        uvs: Vec::new(),
+        normals: Vec::new(),
+        tangents: Vec::new(),
+        colors: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TETRAHEDRON: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, 0.0, 1.0],
+    ];
+
+    const FACES: [[usize; 3]; 4] = [[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]];
+
+    #[test]
+    fn test_parse_ascii() {
+        let mut source = "solid tetra hedron\n".to_string();
+        for face in FACES {
+            source += "  facet normal 0 0 0\n    outer loop\n";
+            for i in face {
+                let [x, y, z] = TETRAHEDRON[i];
+                source += &format!("      vertex {} {} {}\n", x, y, z);
+            }
+
+            source += "    endloop\n  endfacet\n";
+        }
+
+        source += "endsolid tetra hedron\n";
+
+        let solids = parse(source.as_bytes()).unwrap();
+        assert_eq!(solids.len(), 1);
+        assert_eq!(solids[0].name, "tetra hedron");
+        assert_eq!(solids[0].triangles.len(), 4);
+
+        // Each corner of the tetrahedron is shared by three faces
+        let mesh = weld(&solids[0].triangles);
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices[3], glam::uvec3(2, 1, 3));
+    }
+
+    #[test]
+    fn test_parse_binary() {
+        // Binary files can start with "solid" too
+        let mut bytes = b"solid but binary".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend((FACES.len() as u32).to_le_bytes());
+        for face in FACES {
+            bytes.extend([0; 12]);
+            for i in face {
+                bytes.extend(TETRAHEDRON[i].iter().flat_map(|v| v.to_le_bytes()));
+            }
+
+            bytes.extend([0; 2]);
+        }
+
+        let solids = parse(&bytes).unwrap();
+        assert_eq!(solids[0].name, "solid but binary");
+        assert_eq!(solids[0].triangles[1][2], glam::vec3(0.0, 0.0, 1.0));
+
+        bytes.truncate(bytes.len() - 1);
+        assert!(parse(&bytes).is_err());
+    }
+}
@@ -1,13 +1,24 @@
 mod app;
+mod atlas;
+mod bvh;
 mod cli;
+mod environment;
+mod exporter;
 mod gui;
+mod headless;
 mod loader;
 mod pipeline;
 mod scene;
 mod storage;
 mod traits;
+mod validate;
 
-use std::{fs, time::Instant};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
 
 use app::State;
 use clap::Parser;
@@ -24,38 +35,94 @@ async fn run() -> Result<(), String> {
         cli::Commands::Convert {
             scene,
             scene_format,
+            import,
+            to,
+            output,
+            no_bvh,
         } => {
-            let scene = match scene_format {
-                cli::SceneFormat::Gltf => loader::gltf::load(scene)
-                    .map_err(|e| format!("Unable to parse scene file:\n  {}", e))?,
-            };
+            let scene = load_scene(&scene, scene_format, import)?;
 
-            let scene_str = {
-                let config = ron::ser::PrettyConfig::default().struct_names(true);
-                ron::ser::to_string_pretty(&scene, config)
-                    .map_err(|e| format!("Unable to serialize scene:\n  {}", e))?
+            let extension = output.as_deref().and_then(exporter::Format::from_extension);
+            let format = match (to, extension) {
+                (Some(cli::ExportFormat::Ron), _) => exporter::Format::Ron,
+                (Some(cli::ExportFormat::Gltf), Some(exporter::Format::Glb)) => {
+                    exporter::Format::Glb
+                }
+                (Some(cli::ExportFormat::Gltf), _) => exporter::Format::Gltf,
+                (Some(cli::ExportFormat::Rbs), _) | (None, Some(exporter::Format::Rbs { .. })) => {
+                    exporter::Format::Rbs { bvh: !no_bvh }
+                }
+                (None, Some(format)) => format,
+                (None, None) => exporter::Format::Ron,
             };
 
-            println!("{}", &scene_str);
+            let bytes = exporter::export(&scene, format)?;
+            match output {
+                Some(path) => fs::write(&path, bytes)
+                    .map_err(|e| format!("Unable to write file: {}\n  {}", path.display(), e)),
+                None => io::stdout()
+                    .write_all(&bytes)
+                    .map_err(|e| format!("Unable to write scene:\n  {}", e)),
+            }
+        }
+        cli::Commands::Validate {
+            scene: path,
+            scene_format,
+            import,
+        } => {
+            let scene = load_scene(&path, scene_format, import)?;
+            let problems = validate::validate(&scene);
+            for problem in &problems {
+                println!("{}", problem);
+            }
+
+            let errors = problems
+                .iter()
+                .filter(|problem| problem.severity == validate::Severity::Error)
+                .count();
+            let summary = format!(
+                "{} and {} in {}",
+                plural(errors, "error"),
+                plural(problems.len() - errors, "warning"),
+                path.display()
+            );
+
+            if errors > 0 {
+                return Err(format!("Found {}", summary));
+            }
+
+            match problems.len() {
+                0 => println!("No problems found in {}", path.display()),
+                _ => println!("Found {}", summary),
+            }
 
             Ok(())
         }
         cli::Commands::Render {
             scene,
+            scene_format,
+            import,
             skybox_color,
             ambient_lighting_color,
             ambient_lighting_strength,
             max_ray_bounces_per_ray,
             max_samples_per_pixel,
             focal_blur_strength,
+            filter,
+            filter_radius,
+            environment,
+            environment_rotation,
+            environment_intensity,
+            exposure,
+            tone_mapping,
+            width,
+            height,
+            output,
+            samples,
+            time_limit,
         } => {
-            let scene = {
-                let scene = fs::read_to_string(scene.as_path())
-                    .map_err(|_| format!("Unable to read file: {}", scene.as_path().display()))?;
-
-                ron::from_str::<scene::Scene>(&scene)
-                    .map_err(|e| format!("Unable to parse scene file:\n  {}", e))?
-            };
+            let scene = load_scene(&scene, scene_format, import)?;
+            check_scene(&scene)?;
 
             let parameters = app::Parameters {
                 frame: 0,
@@ -66,51 +133,69 @@ async fn run() -> Result<(), String> {
                 ambient_lighting_color: ambient_lighting_color.into(),
                 ambient_lighting_strength,
                 focal_blur_strength,
+                reconstruction_filter: filter.into(),
+                filter_radius: filter_radius.unwrap_or(filter.default_radius()),
+                environment_intensity,
+                environment_rotation,
+                target_samples: samples,
+                exposure,
+                tone_mapping: tone_mapping.into(),
             };
 
+            let environment = environment
+                .map(|path| environment::Environment::load(&path))
+                .transpose()?;
+
+            if let Some(output) = output {
+                let options = headless::Options {
+                    output,
+                    width,
+                    height,
+                    time_limit: time_limit.map(Duration::from_secs_f32),
+                };
+
+                return headless::render(&scene, environment.as_ref(), &parameters, &options).await;
+            }
+
             let event_loop = EventLoop::new();
             let window = WindowBuilder::new()
                 .with_title("Raybaby")
-                .with_inner_size(LogicalSize {
-                    width: 960,
-                    height: 540,
-                })
+                .with_inner_size(LogicalSize { width, height })
                 .build(&event_loop)
                 .map_err(|e| e.to_string())?;
 
-            let mut state = State::new(&window, &scene, &parameters).await;
+            let mut state = State::new(&window, &scene, environment.as_ref(), &parameters).await?;
 
             event_loop.run(move |event, _, control_flow| match event {
                 Event::WindowEvent {
                     ref event,
                     window_id,
-                } if window_id == window.id() => {
-                    if !state.input(event) {
-                        match event {
-                            WindowEvent::CloseRequested
-                            | WindowEvent::KeyboardInput {
-                                input:
-                                    KeyboardInput {
-                                        state: ElementState::Pressed,
-                                        virtual_keycode: Some(VirtualKeyCode::Escape),
-                                        ..
-                                    },
+                } if window_id == window.id() && !state.input(event) => match event {
+                    WindowEvent::CloseRequested
+                    | WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::Escape),
                                 ..
-                            } => *control_flow = ControlFlow::Exit,
-                            WindowEvent::Resized(physical_size) => {
-                                state.resize(*physical_size);
-                            }
-                            WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
-                                state.resize(**new_inner_size);
-                            }
-                            _ => {}
-                        }
+                            },
+                        ..
+                    } => *control_flow = ControlFlow::Exit,
+                    WindowEvent::Resized(physical_size) => {
+                        state.resize(*physical_size);
                     }
+                    WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                        state.resize(**new_inner_size);
+                    }
+                    // Keep the GUI responsive after rendering has converged
+                    _ => window.request_redraw(),
+                },
+                Event::WindowEvent { window_id, .. } if window_id == window.id() => {
+                    window.request_redraw();
                 }
                 Event::RedrawRequested(window_id) if window_id == window.id() => {
-                    state.update();
                     match state.render(&window) {
-                        Ok(_) => {}
+                        Ok(_) => state.update(),
                         // Reconfigure the surface if lost
                         Err(wgpu::SurfaceError::Lost) => state.resize(state.size()),
                         // The system is out of memory, we should probably quit
@@ -120,7 +205,13 @@ async fn run() -> Result<(), String> {
                     }
                 }
                 Event::MainEventsCleared => {
-                    window.request_redraw();
+                    // Once converged, only redraw when something happens
+                    if state.is_converged() {
+                        *control_flow = ControlFlow::Wait;
+                    } else {
+                        *control_flow = ControlFlow::Poll;
+                        window.request_redraw();
+                    }
                 }
                 _ => {}
             });
@@ -128,6 +219,49 @@ async fn run() -> Result<(), String> {
     }
 }
 
+/// Loads a scene in any format, printing warnings about anything that
+/// couldn't be imported as it was
+fn load_scene(
+    path: &Path,
+    format: Option<cli::SceneFormat>,
+    import: cli::ImportArgs,
+) -> Result<scene::Scene, String> {
+    let (scene, warnings) = loader::load(path, format.map(Into::into), &import.into())
+        .map_err(|e| format!("Unable to parse scene file:\n  {}", e))?;
+
+    for warning in &warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    Ok(scene)
+}
+
+/// Validates a scene before rendering it, printing warnings and failing with
+/// every error, since bad data can crash the GPU rather than just render badly
+fn check_scene(scene: &scene::Scene) -> Result<(), String> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = validate::validate(scene)
+        .into_iter()
+        .partition(|problem| problem.severity == validate::Severity::Error);
+
+    for warning in &warnings {
+        eprintln!("{}", warning);
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+    Err(format!("Invalid scene:\n  {}", errors.join("\n  ")))
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
+
 fn main() {
     env_logger::init();
     match pollster::block_on(run()) {
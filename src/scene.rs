@@ -1,10 +1,75 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
 use glam::{f32, u32};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{bvh, loader};
 
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
 pub struct Scene {
     pub camera: Camera,
+
+    /// Meshes that objects can reference by name, so each is only stored once
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meshes: BTreeMap<String, Mesh>,
+
+    /// Images embedded in the scene, which textures can reference by index
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<Image>,
+
+    /// Materials that objects can reference by name, so each is only stored once
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub materials: BTreeMap<String, Material>,
+
     pub objects: Vec<Object>,
+
+    /// Lights without a surface, which only light the scene and can't be seen
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lights: Vec<Light>,
+
+    /// Bottom-level BVHs already built for meshes, by mesh name, as loaded from
+    /// a binary scene file. Meshes without one have theirs built when rendering.
+    #[serde(skip)]
+    pub bvhs: BTreeMap<String, bvh::Bvh>,
+}
+
+impl Scene {
+    /// The material an object uses, looking it up in `materials` if it's named
+    pub fn material<'a>(&'a self, material: &'a MaterialRef) -> Result<&'a Material, String> {
+        match material {
+            MaterialRef::Inline(material) => Ok(material),
+            MaterialRef::Named(name) => self
+                .materials
+                .get(name)
+                .ok_or_else(|| format!("Unknown material: '{}'", name)),
+        }
+    }
+
+    /// Checks that every material objects use by name is in `materials`,
+    /// listing the ones that aren't
+    pub fn check_materials(&self) -> Result<(), String> {
+        let unknown: BTreeSet<&str> = self
+            .objects
+            .iter()
+            .filter_map(|obj| match &obj.material {
+                MaterialRef::Named(name) if !self.materials.contains_key(name) => {
+                    Some(name.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+
+        if unknown.is_empty() {
+            return Ok(());
+        }
+
+        let names: Vec<String> = unknown.iter().map(|name| format!("'{}'", name)).collect();
+        Err(format!("Unknown materials: {}", names.join(", ")))
+    }
 }
 
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
@@ -29,7 +94,53 @@ pub struct Clipping {
 pub struct Object {
     pub surface: Surface,
     pub transform: Transform,
-    pub material: Material,
+    pub material: MaterialRef,
+}
+
+impl Object {
+    /// Makes the object's texture and mesh file paths relative to `base` rather
+    /// than the working directory, so scenes can be loaded from anywhere
+    pub fn resolve_paths(&mut self, base: &Path) {
+        if let MaterialRef::Inline(material) = &mut self.material {
+            material.resolve_paths(base);
+        }
+
+        if let Surface::MeshFile { path, .. } = &mut self.surface {
+            *path = base.join(&*path);
+        }
+    }
+}
+
+/// A light at a single point, or infinitely far away. Lights shine along the
+/// +Z axis of their transform, like cameras.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct Light {
+    pub kind: LightKind,
+    pub transform: Transform,
+    pub color: f32::Vec3,
+
+    /// Watts per steradian for point and spot lights, or watts per square
+    /// unit of surface facing a directional light
+    pub intensity: f32,
+
+    /// The distance beyond which point and spot lights have no effect, fading
+    /// out smoothly before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<f32>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub enum LightKind {
+    /// Shines equally in every direction
+    Point,
+    /// Shines in a cone, fading from full intensity at the inner angle to
+    /// nothing at the outer one. Angles are in degrees from the center.
+    Spot {
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+    /// Shines in parallel rays, like the sun
+    Directional,
 }
 
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
@@ -40,7 +151,78 @@ pub enum Surface {
     MeshData {
         vertices: Vec<f32::Vec3>,
         indices: Vec<u32::UVec3>,
+
+        /// Texture coordinates for each vertex, if the mesh is textured
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        uvs: Vec<f32::Vec2>,
+
+        /// Normals for each vertex to interpolate across faces, for smooth shading
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        normals: Vec<f32::Vec3>,
+
+        /// Tangents for each vertex, for normal mapping
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tangents: Vec<f32::Vec4>,
+
+        /// Linear RGBA colors for each vertex, multiplying the material's color
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        colors: Vec<f32::Vec4>,
     },
+    Mesh {
+        name: String,
+    },
+    /// The meshes in another file, relative to the scene file, merged into one
+    /// with their transforms applied. They're loaded into `meshes` with the
+    /// scene, so this is only ever seen in scene files.
+    MeshFile {
+        path: PathBuf,
+
+        /// The format of the file [default: detected from the file]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        format: Option<loader::Format>,
+    },
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct Mesh {
+    pub vertices: Vec<f32::Vec3>,
+    pub indices: Vec<u32::UVec3>,
+
+    /// Texture coordinates for each vertex, if the mesh is textured
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uvs: Vec<f32::Vec2>,
+
+    /// Normals for each vertex to interpolate across faces, for smooth shading
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub normals: Vec<f32::Vec3>,
+
+    /// Tangents for each vertex, for normal mapping. The bitangent is
+    /// `cross(normal, tangent.xyz) * tangent.w`, as in glTF.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tangents: Vec<f32::Vec4>,
+
+    /// Linear RGBA colors for each vertex, multiplying the material's color
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub colors: Vec<f32::Vec4>,
+}
+
+/// An image with 8-bit RGBA pixels and rows from the top. Colors are sRGB encoded.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+
+    #[serde(with = "bytes")]
+    pub pixels: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
+pub enum Texture {
+    /// An image file, relative to the scene file
+    File(PathBuf),
+
+    /// An image in the scene's `images`
+    Image(usize),
 }
 
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
@@ -50,18 +232,222 @@ pub struct Transform {
     pub scale: f32::Vec3,
 }
 
+/// Unknown fields are rejected, so fields of older versions like
+/// `smoothness` aren't silently ignored
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Material {
+    /// Base color: the diffuse albedo of dielectrics, or the reflectance of metals
     pub color: f32::Vec4,
     pub luminosity: f32,
-    pub smoothness: f32,
+
+    /// The color of emitted light, when it differs from the base color
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emission_color: Option<f32::Vec3>,
+
+    /// Whether the surface is a metal (1) or a dielectric (0)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub metallic: f32,
+
+    /// Microfacet roughness, from a perfect mirror (0) to fully diffuse (1)
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+
+    /// Chance of light refracting through the surface like glass or water,
+    /// rather than bouncing off it. Transmitted light is tinted by `color`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub transmission: f32,
+
+    /// Index of refraction of the material behind the surface
+    #[serde(default = "default_ior")]
+    pub ior: f32,
+
+    /// The color white light fades to after travelling one unit through the
+    /// material, for tinting thick glass or liquids by depth
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub absorption: Option<f32::Vec3>,
+
+    /// An image the base color is multiplied by, mapped with the surface's UVs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub texture: Option<Texture>,
+
+    /// A tangent space normal map, which needs the mesh to have tangents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_texture: Option<Texture>,
+
+    /// How strongly the normal map tilts the surface normal
+    #[serde(default = "default_normal_scale", skip_serializing_if = "is_one")]
+    pub normal_scale: f32,
+}
+
+impl Material {
+    /// The light the surface emits, scaled by its luminosity
+    pub fn emission(&self) -> f32::Vec3 {
+        self.emission_color.unwrap_or(self.color.truncate()) * self.luminosity
+    }
+
+    /// The material's base color and normal textures, whichever it has
+    pub fn textures_mut(&mut self) -> impl Iterator<Item = &mut Texture> {
+        [&mut self.texture, &mut self.normal_texture]
+            .into_iter()
+            .flatten()
+    }
+
+    /// Makes the material's texture paths relative to `base`
+    pub fn resolve_paths(&mut self, base: &Path) {
+        for texture in self.textures_mut() {
+            if let Texture::File(path) = texture {
+                *path = base.join(&*path);
+            }
+        }
+    }
+}
+
+/// A material's exact bit pattern, to find identical materials by hash
+#[derive(PartialEq, Eq, Hash)]
+pub struct MaterialKey {
+    values: Vec<u32>,
+    textures: [Option<Texture>; 2],
 }
 
-impl Into<Transform> for glam::Mat4 {
-    fn into(self) -> Transform {
-        let (scale, rotation, position) = self.to_scale_rotation_translation();
-        let rotation = rotation.to_euler(glam::EulerRot::XYZ);
-        let rotation = glam::f32::Vec3::new(rotation.0, rotation.1, rotation.2);
+impl From<&Material> for MaterialKey {
+    fn from(material: &Material) -> Self {
+        // Destructured so new fields can't be left out of the key
+        let Material {
+            color,
+            luminosity,
+            emission_color,
+            metallic,
+            roughness,
+            transmission,
+            ior,
+            absorption,
+            texture,
+            normal_texture,
+            normal_scale,
+        } = material;
+
+        // Options are marked by whether they're present, so `None` can't match a value
+        let optional = |value: &Option<f32::Vec3>| {
+            let values = value.unwrap_or(f32::Vec3::ZERO).to_array();
+            [value.is_some() as u32]
+                .into_iter()
+                .chain(values.map(|v| v.to_bits()))
+        };
+
+        let values = color
+            .to_array()
+            .into_iter()
+            .chain([
+                *luminosity,
+                *metallic,
+                *roughness,
+                *transmission,
+                *ior,
+                *normal_scale,
+            ])
+            .map(|v| v.to_bits())
+            .chain(optional(emission_color))
+            .chain(optional(absorption))
+            .collect();
+
+        MaterialKey {
+            values,
+            textures: [texture.clone(), normal_texture.clone()],
+        }
+    }
+}
+
+/// An object's material, either written out in full or the name of one in the
+/// scene's `materials`. Scene files have either a `Material(...)` or a string.
+#[derive(Clone, PartialEq, Debug)]
+pub enum MaterialRef {
+    Inline(Material),
+    Named(String),
+}
+
+impl From<Material> for MaterialRef {
+    fn from(material: Material) -> Self {
+        MaterialRef::Inline(material)
+    }
+}
+
+impl Serialize for MaterialRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MaterialRef::Inline(material) => material.serialize(serializer),
+            MaterialRef::Named(name) => serializer.serialize_str(name),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MaterialRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MaterialRefVisitor;
+
+        impl<'de> de::Visitor<'de> for MaterialRefVisitor {
+            type Value = MaterialRef;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a material or the name of one")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                Ok(MaterialRef::Named(name.to_string()))
+            }
+
+            // Read inline materials as structs themselves, rather than through
+            // an untagged enum, so errors in them point at the mistake
+            fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                Material::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(MaterialRef::Inline)
+            }
+        }
+
+        deserializer.deserialize_any(MaterialRefVisitor)
+    }
+}
+
+fn default_roughness() -> f32 {
+    1.0
+}
+
+fn default_ior() -> f32 {
+    1.5
+}
+
+fn default_normal_scale() -> f32 {
+    1.0
+}
+
+fn is_zero(value: &f32) -> bool {
+    *value == 0.0
+}
+
+fn is_one(value: &f32) -> bool {
+    *value == 1.0
+}
+
+impl From<&Transform> for glam::Affine3A {
+    fn from(transform: &Transform) -> Self {
+        glam::Affine3A::from_scale_rotation_translation(
+            transform.scale,
+            glam::Quat::from_euler(
+                glam::EulerRot::XYZ,
+                transform.rotation.x.to_radians(),
+                transform.rotation.y.to_radians(),
+                transform.rotation.z.to_radians(),
+            ),
+            transform.position,
+        )
+    }
+}
+
+impl From<glam::Mat4> for Transform {
+    fn from(matrix: glam::Mat4) -> Self {
+        let (scale, rotation, position) = matrix.to_scale_rotation_translation();
+        let (x, y, z) = rotation.to_euler(glam::EulerRot::XYZ);
+        let rotation = glam::f32::vec3(x.to_degrees(), y.to_degrees(), z.to_degrees());
 
         Transform {
             position,
@@ -71,6 +457,60 @@ impl Into<Transform> for glam::Mat4 {
     }
 }
 
+impl From<Mesh> for Surface {
+    fn from(mesh: Mesh) -> Self {
+        Surface::MeshData {
+            vertices: mesh.vertices,
+            indices: mesh.indices,
+            uvs: mesh.uvs,
+            normals: mesh.normals,
+            tangents: mesh.tangents,
+            colors: mesh.colors,
+        }
+    }
+}
+
+/// Serializes pixels as a byte string rather than a list of numbers, which RON
+/// writes as base64
+mod bytes {
+    use serde::{de, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> de::Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a byte string")
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+                Ok(bytes.to_vec())
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(bytes)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+
+                Ok(bytes)
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -79,6 +519,70 @@ mod test {
     fn test_deserialize() {
         let source = include_str!("../examples/01-spheres.ron");
         let scene = ron::from_str::<Scene>(source).expect("Unable to parse scene");
-        assert!(scene.objects.len() > 0);
+        assert!(!scene.objects.is_empty());
+    }
+
+    #[test]
+    fn test_material_refs() {
+        let named = ron::from_str::<MaterialRef>("\"gold\"");
+        assert_eq!(named, Ok(MaterialRef::Named("gold".to_string())));
+
+        let source = "Material(color: (1, 1, 1, 1), luminosity: 0, texture: Some(Image(2)))";
+        let Ok(MaterialRef::Inline(material)) = ron::from_str::<MaterialRef>(source) else {
+            panic!("Unable to parse inline material");
+        };
+        assert_eq!(material.texture, Some(Texture::Image(2)));
+
+        // Mistakes in inline materials are reported as they would be for any material
+        let error = ron::from_str::<MaterialRef>("(color: (1, 1, 1, 1))").unwrap_err();
+        assert!(error.to_string().contains("luminosity"));
+
+        // Materials from before roughness replaced smoothness don't load as fully rough
+        let source = "(color: (1, 1, 1, 1), luminosity: 0, smoothness: 0.9)";
+        let error = ron::from_str::<MaterialRef>(source).unwrap_err();
+        assert!(error.to_string().contains("smoothness"));
+
+        let mut scene = ron::from_str::<Scene>(include_str!("../examples/01-spheres.ron")).unwrap();
+        scene.materials.insert("gold".to_string(), material);
+        for name in ["gold", "silver", "bronze", "silver"] {
+            let mut object = scene.objects[0].clone();
+            object.material = MaterialRef::Named(name.to_string());
+            scene.objects.push(object);
+        }
+
+        assert_eq!(
+            scene.check_materials(),
+            Err("Unknown materials: 'bronze', 'silver'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_image_round_trip() {
+        let image = Image {
+            width: 1,
+            height: 2,
+            pixels: vec![0, 1, 2, 3, 252, 253, 254, 255],
+        };
+
+        let source = ron::to_string(&image).expect("Unable to serialize image");
+        assert_eq!(ron::from_str::<Image>(&source), Ok(image));
+    }
+
+    #[test]
+    fn test_transform_from_matrix() {
+        let matrix = glam::Mat4::from_scale_rotation_translation(
+            glam::vec3(1.0, 2.0, 3.0),
+            glam::Quat::from_euler(glam::EulerRot::XYZ, 0.5, -0.25, 1.0),
+            glam::vec3(4.0, 5.0, 6.0),
+        );
+
+        // Rotations are in degrees, as written in scene files
+        let transform = Transform::from(matrix);
+        assert!(transform
+            .rotation
+            .abs_diff_eq(glam::vec3(28.64789, -14.323945, 57.29578), 1E-4));
+
+        let rebuilt = glam::Mat4::from(glam::Affine3A::from(&transform));
+        assert!(rebuilt.abs_diff_eq(matrix, 1E-5));
     }
 }
@@ -16,9 +16,85 @@ fn vs_main(in: VertexInput) -> VertexOutput {
     return out;
 }
 
+// Tone mapping operators, matching `cli::ToneMapping`
+const TONE_MAPPING_CLAMP: u32 = 0u;
+const TONE_MAPPING_REINHARD: u32 = 1u;
+const TONE_MAPPING_ACES: u32 = 2u;
+const TONE_MAPPING_AGX: u32 = 3u;
+
+struct Display {
+    // In stops, so each step doubles or halves the brightness
+    exposure: f32,
+    tone_mapping: u32,
+}
+
 @group(0) @binding(0)
 var tex: texture_storage_2d<rgba32float, read>;
 
+@group(0) @binding(1)
+var<uniform> display: Display;
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn tone_map_reinhard(color: vec3<f32>) -> vec3<f32> {
+    // Scaling by luminance keeps bright colors from desaturating towards white
+    return color / (1.0 + luminance(color));
+}
+
+fn tone_map_aces(color: vec3<f32>) -> vec3<f32> {
+    // https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+    let x = color * 0.6;
+    return saturate((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14));
+}
+
+fn agx_contrast(x: vec3<f32>) -> vec3<f32> {
+    // Polynomial fit of AgX's default sigmoid
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+fn tone_map_agx(color: vec3<f32>) -> vec3<f32> {
+    // https://iolite-engine.com/blog_posts/minimal_agx_implementation
+    let inset = mat3x3<f32>(
+        vec3<f32>(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        vec3<f32>(0.0784335999999992, 0.878468636469772, 0.0784336),
+        vec3<f32>(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+    );
+
+    let outset = mat3x3<f32>(
+        vec3<f32>(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        vec3<f32>(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        vec3<f32>(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+    );
+
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    var x = inset * color;
+    x = clamp(log2(max(x, vec3<f32>(1E-10))), vec3<f32>(min_ev), vec3<f32>(max_ev));
+    x = agx_contrast((x - min_ev) / (max_ev - min_ev));
+    x = outset * x;
+
+    // AgX produces display encoded values, but the target applies its own sRGB encoding
+    return pow(max(x, vec3<f32>(0.0)), vec3<f32>(2.2));
+}
+
+fn tone_map(color: vec3<f32>) -> vec3<f32> {
+    let exposed = max(color, vec3<f32>(0.0)) * exp2(display.exposure);
+    if (display.tone_mapping == TONE_MAPPING_REINHARD) {
+        return tone_map_reinhard(exposed);
+    } else if (display.tone_mapping == TONE_MAPPING_ACES) {
+        return tone_map_aces(exposed);
+    } else if (display.tone_mapping == TONE_MAPPING_AGX) {
+        return tone_map_agx(exposed);
+    }
+
+    return saturate(exposed);
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let dimension = textureDimensions(tex);
@@ -27,6 +103,8 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         u32(in.uv.y * f32(dimension.y)),
     );
 
-    let color = textureLoad(tex, tex_coords);
-    return color;
+    // Pixels hold a linear, unbounded sum of samples in rgb and the sum of their weights in a
+    let sum = textureLoad(tex, tex_coords);
+    let color = sum.xyz / max(sum.w, 1E-6);
+    return vec4<f32>(tone_map(color), 1.0);
 }
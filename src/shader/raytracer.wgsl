@@ -3,6 +3,39 @@
 
 alias RandomState = ptr<function, u32>;
 
+// Must be at least as deep as the deepest tree built by the BVH builder
+const BVH_STACK_SIZE: u32 = 64u;
+
+// Marks an instance as a sphere, with the remaining bits of its root indexing the sphere buffer
+const SPHERE_INSTANCE_FLAG: u32 = 0x80000000u;
+
+const NO_INTERSECTION: f32 = 3.402823e+38;
+
+const BVH_NO_NODE: u32 = 0xffffffffu;
+
+// Reconstruction filters, matching `cli::Filter`
+const FILTER_BOX: u32 = 0u;
+const FILTER_TENT: u32 = 1u;
+const FILTER_GAUSSIAN: u32 = 2u;
+const FILTER_BLACKMAN_HARRIS: u32 = 3u;
+
+// Kinds of light in the light buffer, matching `LIGHT_*` in app.rs
+const LIGHT_SPHERE: u32 = 0u;
+const LIGHT_TRIANGLE: u32 = 1u;
+const LIGHT_POINT: u32 = 2u;
+const LIGHT_SPOT: u32 = 3u;
+const LIGHT_DIRECTIONAL: u32 = 4u;
+
+const NO_SPHERE: u32 = 0xffffffffu;
+
+// Distance secondary rays start off the surface they leave, to avoid hitting it again
+const RAY_EPSILON: f32 = 1E-4;
+
+const PI: f32 = 3.14159265;
+
+// Bounces every path makes before Russian roulette can end it
+const ROULETTE_MIN_BOUNCES: u32 = 3u;
+
 struct Globals {
     camera: Camera,
     frame: u32,
@@ -13,6 +46,17 @@ struct Globals {
     max_ray_bounces: u32,
     max_samples_per_pixel: u32,
     focal_blur_strength: f32,
+    reconstruction_filter: u32,
+    filter_radius: f32,
+    has_environment: u32,
+    environment_intensity: f32,
+    // Radians around the vertical axis
+    environment_rotation: f32,
+    // Samples per pixel to stop at, or zero to keep going
+    target_samples: u32,
+    // Only used by the display pass
+    exposure: f32,
+    tone_mapping: u32,
 }
 
 struct Camera {
@@ -25,9 +69,20 @@ struct Camera {
 
 struct Material {
     color: vec4<f32>,
-    luminosity: f32,
-    smoothness: f32,
-    _padding: array<u32, 2>,
+    emission: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    transmission: f32,
+    ior: f32,
+    // Beer's law attenuation coefficient per unit of distance
+    absorption: vec3<f32>,
+    // Where the base color texture is in the atlas, in texels. Zero sized without a texture.
+    texture_offset: vec2<u32>,
+    texture_size: vec2<u32>,
+    // Where the tangent space normal map is in the atlas, in texels. Zero sized without one.
+    normal_map_offset: vec2<u32>,
+    normal_map_size: vec2<u32>,
+    normal_scale: f32,
 }
 
 struct MaterialBuffer {
@@ -49,25 +104,85 @@ struct SphereBuffer {
     spheres: array<Sphere>,
 }
 
+struct MeshVertex {
+    position: vec3<f32>,
+    // Zero when the mesh is flat shaded
+    normal: vec3<f32>,
+    // Zero when the mesh has no tangents. The bitangent is cross(normal, tangent.xyz) * tangent.w
+    tangent: vec4<f32>,
+    uv: vec2<f32>,
+    // White when the mesh has no vertex colors
+    color: vec4<f32>,
+}
+
 struct VertexBuffer {
     count: u32,
-    vertices: array<vec3<f32>>,
+    vertices: array<MeshVertex>,
+}
+
+struct Triangle {
+    indices: vec3<u32>,
 }
 
-struct IndexBuffer {
+struct TriangleBuffer {
     count: u32,
-    indices: array<u32>,
+    triangles: array<Triangle>,
 }
 
-struct Mesh {
-    index_offset: u32,
-    triangle_count: u32,
+struct BvhNode {
+    aabb_min: vec3<f32>,
+    // Index of the left child for interior nodes, or of the first primitive for leaves
+    first: u32,
+    aabb_max: vec3<f32>,
+    // Zero for interior nodes
+    count: u32,
+}
+
+struct BvhBuffer {
+    count: u32,
+    nodes: array<BvhNode>,
+}
+
+struct Instance {
+    world_to_object: mat4x4<f32>,
+    // Index of the instance's bottom-level root node, unless it's a sphere
+    blas_root: u32,
     material_id: u32,
 }
 
-struct MeshBuffer {
+struct InstanceBuffer {
+    count: u32,
+    instances: array<Instance>,
+}
+
+struct Light {
+    // Sphere center, the first vertex of a triangle, or a point or spot light's position
+    position: vec3<f32>,
+    kind: u32,
+    edge_ab: vec3<f32>,
+    radius: f32,
+    edge_ac: vec3<f32>,
+    // Where point and spot lights fade out, or zero if they reach everywhere
+    range: f32,
+    // Radiance of area lights, or the intensity of point, spot and directional lights
+    emission: vec3<f32>,
+    cos_outer_cone: f32,
+    // The way spot and directional lights shine
+    direction: vec3<f32>,
+    cos_inner_cone: f32,
+}
+
+struct LightBuffer {
     count: u32,
-    meshes: array<Mesh>,
+    lights: array<Light>,
+}
+
+struct Accumulation {
+    // Filter weighted sum of every sample's color
+    color: vec3<f32>,
+    // Sum of the filter weights, which the color is divided by for display
+    weight: f32,
+    samples: u32,
 }
 
 struct Ray {
@@ -79,8 +194,31 @@ struct HitInfo {
     hit: bool,
     distance: f32,
     position: vec3<f32>,
+    // The true surface normal, which secondary rays are offset along to avoid self intersection
     normal: vec3<f32>,
+    // The interpolated normal that lighting is calculated with, on the same side as `normal`
+    shading_normal: vec3<f32>,
+    // Zero unless the surface has tangents for normal mapping
+    tangent: vec4<f32>,
+    uv: vec2<f32>,
+    // The vertex color, multiplying the material's color
+    color: vec4<f32>,
     material_id: u32,
+
+    // Used to find the light sampling pdf of emissive surfaces hit by chance
+    sphere_id: u32,
+    area: f32,
+}
+
+struct LightSample {
+    direction: vec3<f32>,
+    distance: f32,
+    // Solid angle pdf, or zero if the light can't be seen from the sampled point.
+    // Point, spot and directional lights have a pdf of one, as they're only
+    // ever reached by sampling them.
+    pdf: f32,
+    // Light arriving from the sampled point
+    emission: vec3<f32>,
 }
 
 // ============================= Ray Tracing Logic ============================ */
@@ -89,8 +227,8 @@ fn get_environment_lighting() -> vec3<f32> {
     return globals.ambient_lighting_color * globals.ambient_lighting_strength;
 }
 
-fn ray_sphere_intersection(ray: Ray, sphere: Sphere) -> HitInfo {
-
+fn ray_sphere_intersection(ray: Ray, sphere_id: u32) -> HitInfo {
+    let sphere = sphere_buffer.spheres[sphere_id];
     let center = sphere.position;
     let radius = sphere.radius;
 
@@ -108,24 +246,46 @@ fn ray_sphere_intersection(ray: Ray, sphere: Sphere) -> HitInfo {
     if (discriminant >= 0.0) {
         var temp = (-b - sqrt(discriminant)) / (2.0 * a);
 
+        // Rays starting inside the sphere hit its far side
+        if (temp < 0.0) {
+            temp = (-b + sqrt(discriminant)) / (2.0 * a);
+        }
+
         if (temp >= 0.0) {
             hit.hit = true;
             hit.distance = temp;
             hit.position = ray.origin + ray.direction * temp;
             hit.normal = normalize(hit.position - center);
+            hit.shading_normal = hit.normal;
+            hit.uv = sphere_uv(hit.normal);
+            hit.color = vec4<f32>(1.0);
+
+            // Along increasing longitude, with the bitangent pointing up the image towards +y
+            hit.tangent = vec4<f32>(normalize(vec3<f32>(-hit.normal.z, 0.0, hit.normal.x)), -1.0);
             hit.material_id = sphere.material_id;
+            hit.sphere_id = sphere_id;
         }
     }
 
     return hit;
 }
 
-fn ray_triangle_intersection(ray: Ray, a_idx: u32, b_idx: u32, c_idx: u32) -> HitInfo {
+fn sphere_uv(normal: vec3<f32>) -> vec2<f32> {
+    // Longitude and latitude, with the poles along the y axis
+    let u = atan2(normal.z, normal.x) / (2.0 * PI) + 0.5;
+    let v = acos(clamp(normal.y, -1.0, 1.0)) / PI;
+    return vec2<f32>(u, v);
+}
+
+fn ray_triangle_intersection(ray: Ray, triangle: Triangle) -> HitInfo {
     // https://stackoverflow.com/a/42752998
 
-    let a = vertex_buffer.vertices[a_idx];
-    let b = vertex_buffer.vertices[b_idx];
-    let c = vertex_buffer.vertices[c_idx];
+    let vertex_a = vertex_buffer.vertices[triangle.indices.x];
+    let vertex_b = vertex_buffer.vertices[triangle.indices.y];
+    let vertex_c = vertex_buffer.vertices[triangle.indices.z];
+    let a = vertex_a.position;
+    let b = vertex_b.position;
+    let c = vertex_c.position;
 
     var hit: HitInfo;
     hit.hit = false;
@@ -144,63 +304,663 @@ fn ray_triangle_intersection(ray: Ray, a_idx: u32, b_idx: u32, c_idx: u32) -> Hi
     let v = -dot(edge_ab, dao) * inv_det;
     let w = 1.0 - u - v;
 
-    if (determinant >= 1E-6 && dst >= 0.0 && u >= 0.0 && v >= 0.0 && w >= 0.0) {
+    // Both faces are hit so rays can leave closed transmissive meshes
+    if (abs(determinant) >= 1E-6 && dst >= 0.0 && u >= 0.0 && v >= 0.0 && w >= 0.0) {
         hit.hit = true;
         hit.distance = dst;
         hit.position = ray.origin + ray.direction * dst;
         hit.normal = normalize(normal_vector);
+        hit.uv = vertex_a.uv * w + vertex_b.uv * u + vertex_c.uv * v;
+        hit.color = vertex_a.color * w + vertex_b.color * u + vertex_c.color * v;
+
+        let shading_normal = vertex_a.normal * w + vertex_b.normal * u + vertex_c.normal * v;
+        if (dot(shading_normal, shading_normal) > 0.0) {
+            hit.shading_normal = normalize(shading_normal) * sign(dot(shading_normal, hit.normal));
+        } else {
+            hit.shading_normal = hit.normal;
+        }
+
+        let tangent = vertex_a.tangent.xyz * w + vertex_b.tangent.xyz * u + vertex_c.tangent.xyz * v;
+        hit.tangent = vec4<f32>(tangent, vertex_a.tangent.w);
+        hit.sphere_id = NO_SPHERE;
+        hit.area = 0.5 * length(normal_vector);
     }
 
     return hit;
 }
 
-fn ray_world_collision(ray: Ray) -> HitInfo {
+fn ray_aabb_distance(ray: Ray, inv_direction: vec3<f32>, aabb_min: vec3<f32>, aabb_max: vec3<f32>) -> f32 {
+    // Slab test, returning the distance to the box or NO_INTERSECTION on a miss
+    let t0 = (aabb_min - ray.origin) * inv_direction;
+    let t1 = (aabb_max - ray.origin) * inv_direction;
+    let t_min = min(t0, t1);
+    let t_max = max(t0, t1);
+
+    let t_near = max(max(t_min.x, t_min.y), t_min.z);
+    let t_far = min(min(t_max.x, t_max.y), t_max.z);
+
+    if (t_near <= t_far && t_far >= 0.0) {
+        return max(t_near, 0.0);
+    }
+
+    return NO_INTERSECTION;
+}
+
+fn bvh_visit_children(ray: Ray, inv_direction: vec3<f32>, node: BvhNode, max_distance: f32) -> vec2<u32> {
+    // Returns the children the ray enters, nearest first, so later hits can cull the farther one
+    let left = bvh_buffer.nodes[node.first];
+    let right = bvh_buffer.nodes[node.first + 1u];
+    let left_distance = ray_aabb_distance(ray, inv_direction, left.aabb_min, left.aabb_max);
+    let right_distance = ray_aabb_distance(ray, inv_direction, right.aabb_min, right.aabb_max);
+
+    var near = vec2<u32>(node.first, node.first + 1u);
+    var distances = vec2<f32>(left_distance, right_distance);
+    if (right_distance < left_distance) {
+        near = near.yx;
+        distances = distances.yx;
+    }
+
+    return select(vec2<u32>(BVH_NO_NODE), near, distances < vec2<f32>(max_distance));
+}
+
+fn ray_mesh_collision(ray: Ray, root_index: u32, max_distance: f32) -> HitInfo {
+    // Walks a bottom-level BVH, whose leaves reference triangles
     var hit: HitInfo;
     hit.hit = false;
-    hit.distance = globals.camera.far_clip;
+    hit.distance = max_distance;
+
+    let inv_direction = 1.0 / ray.direction;
+    let nodes = &bvh_buffer.nodes;
 
-    let spheres = &sphere_buffer.spheres;
-    for (var i: u32 = 0u; i < sphere_buffer.count; i++) {
-        let hit_info = ray_sphere_intersection(ray, (*spheres)[i]);
-        if (hit_info.hit && hit_info.distance < hit.distance) {
-            hit = hit_info;
+    let root = (*nodes)[root_index];
+    if (ray_aabb_distance(ray, inv_direction, root.aabb_min, root.aabb_max) >= hit.distance) {
+        return hit;
+    }
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size: u32 = 0u;
+    var node_index: u32 = root_index;
+
+    loop {
+        let node = (*nodes)[node_index];
+        if (node.count > 0u) {
+            for (var i: u32 = 0u; i < node.count; i++) {
+                let hit_info = ray_triangle_intersection(ray, triangle_buffer.triangles[node.first + i]);
+                if (hit_info.hit && hit_info.distance < hit.distance) {
+                    hit = hit_info;
+                }
+            }
+        } else {
+            let next = bvh_visit_children(ray, inv_direction, node, hit.distance);
+            if (next.x != BVH_NO_NODE) {
+                if (next.y != BVH_NO_NODE && stack_size < BVH_STACK_SIZE) {
+                    stack[stack_size] = next.y;
+                    stack_size++;
+                }
+
+                node_index = next.x;
+                continue;
+            }
         }
+
+        if (stack_size == 0u) {
+            break;
+        }
+
+        stack_size--;
+        node_index = stack[stack_size];
     }
 
-    let meshes = &mesh_buffer.meshes;
-    for (var i: u32 = 0u; i < min(mesh_buffer.count, 32u); i++) {
-        let mesh = (*meshes)[i];
-        let mesh_index_offset = mesh.index_offset;
-        let mesh_triangle_count = mesh.triangle_count;
-        let mesh_material_id = mesh.material_id;
+    return hit;
+}
 
-        for (var j: u32 = 0u; j < min(mesh_triangle_count, 256u); j++) {
-            let index_offset = mesh_index_offset + j * 3u;
-            let a_idx = index_buffer.indices[index_offset + 0u];
-            let b_idx = index_buffer.indices[index_offset + 1u];
-            let c_idx = index_buffer.indices[index_offset + 2u];
+fn ray_instance_intersection(ray: Ray, instance: Instance, max_distance: f32) -> HitInfo {
+    if ((instance.blas_root & SPHERE_INSTANCE_FLAG) != 0u) {
+        return ray_sphere_intersection(ray, instance.blas_root & ~SPHERE_INSTANCE_FLAG);
+    }
+
+    // The direction is left unnormalized so hit distances stay in world space
+    var object_ray: Ray;
+    object_ray.origin = (instance.world_to_object * vec4<f32>(ray.origin, 1.0)).xyz;
+    object_ray.direction = (instance.world_to_object * vec4<f32>(ray.direction, 0.0)).xyz;
+
+    var hit = ray_mesh_collision(object_ray, instance.blas_root, max_distance);
+    if (hit.hit) {
+        let world_to_object = mat3x3<f32>(
+            instance.world_to_object[0].xyz,
+            instance.world_to_object[1].xyz,
+            instance.world_to_object[2].xyz,
+        );
+
+        // Normals transform by the inverse transpose, and areas by its length over the determinant
+        let normal = transpose(world_to_object) * hit.normal;
+
+        hit.position = ray.origin + ray.direction * hit.distance;
+        hit.normal = normalize(normal);
+        hit.shading_normal = normalize(transpose(world_to_object) * hit.shading_normal);
+
+        // Tangents should transform by the inverse of world_to_object instead, but this
+        // only differs under non-uniform scale and normal mapping makes them perpendicular again
+        hit.tangent = vec4<f32>(transpose(world_to_object) * hit.tangent.xyz, hit.tangent.w);
+        hit.area *= length(normal) / abs(determinant(world_to_object));
+        hit.material_id = instance.material_id;
+    }
 
-            let hit_info = ray_triangle_intersection(ray, a_idx, b_idx, c_idx);
-            if (hit_info.hit && hit_info.distance < hit.distance) {
-                hit = hit_info;
-                hit.material_id = mesh_material_id;
+    return hit;
+}
+
+fn ray_world_collision(ray: Ray, max_distance: f32) -> HitInfo {
+    // Walks the top-level BVH, whose leaves reference instances
+    var hit: HitInfo;
+    hit.hit = false;
+    hit.distance = max_distance;
+
+    if (instance_buffer.count == 0u) {
+        return hit;
+    }
+
+    let inv_direction = 1.0 / ray.direction;
+    let nodes = &bvh_buffer.nodes;
+
+    let root = (*nodes)[0];
+    if (ray_aabb_distance(ray, inv_direction, root.aabb_min, root.aabb_max) >= hit.distance) {
+        return hit;
+    }
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size: u32 = 0u;
+    var node_index: u32 = 0u;
+
+    loop {
+        let node = (*nodes)[node_index];
+        if (node.count > 0u) {
+            for (var i: u32 = 0u; i < node.count; i++) {
+                let hit_info = ray_instance_intersection(ray, instance_buffer.instances[node.first + i], hit.distance);
+                if (hit_info.hit && hit_info.distance < hit.distance) {
+                    hit = hit_info;
+                }
+            }
+        } else {
+            let next = bvh_visit_children(ray, inv_direction, node, hit.distance);
+            if (next.x != BVH_NO_NODE) {
+                if (next.y != BVH_NO_NODE && stack_size < BVH_STACK_SIZE) {
+                    stack[stack_size] = next.y;
+                    stack_size++;
+                }
+
+                node_index = next.x;
+                continue;
             }
         }
+
+        if (stack_size == 0u) {
+            break;
+        }
+
+        stack_size--;
+        node_index = stack[stack_size];
     }
 
     return hit;
 }
 
+// ============================= Light Sampling ============================ */
+
+fn orthonormal_basis(n: vec3<f32>) -> mat3x3<f32> {
+    // https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+    let s = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    let tangent = vec3<f32>(1.0 + s * n.x * n.x * a, s * b, -s * n.x);
+    let bitangent = vec3<f32>(b, s + n.y * n.y * a, -n.y);
+    return mat3x3<f32>(tangent, bitangent, n);
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    return select(0.0, a / (a + b), a > 0.0);
+}
+
+fn sphere_light_cos_max(center: vec3<f32>, radius: f32, position: vec3<f32>) -> f32 {
+    // Cosine of the half angle of the cone the sphere subtends, or -1 from inside it
+    let distance_squared = dot(center - position, center - position);
+    let sin_squared = radius * radius / distance_squared;
+    return select(-1.0, sqrt(1.0 - sin_squared), sin_squared < 1.0);
+}
+
+fn sample_light(light: Light, position: vec3<f32>, rs: RandomState) -> LightSample {
+    var sample: LightSample;
+    sample.pdf = 0.0;
+
+    if (light.kind == LIGHT_SPHERE) {
+        // Sample the cone of directions the sphere covers rather than its whole surface
+        let cos_max = sphere_light_cos_max(light.position, light.radius, position);
+        if (cos_max < 0.0) {
+            return sample;
+        }
+
+        let cos_theta = 1.0 - random_value(rs) * (1.0 - cos_max);
+        let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+        let phi = 2.0 * PI * random_value(rs);
+
+        let to_center = light.position - position;
+        let basis = orthonormal_basis(normalize(to_center));
+        let direction = basis * vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+        let b = dot(to_center, direction);
+        let discriminant = b * b - dot(to_center, to_center) + light.radius * light.radius;
+
+        sample.direction = direction;
+        sample.distance = b - sqrt(max(discriminant, 0.0));
+        sample.pdf = 1.0 / (2.0 * PI * (1.0 - cos_max));
+        sample.emission = light.emission;
+    } else if (light.kind == LIGHT_TRIANGLE) {
+        var u = random_value(rs);
+        var v = random_value(rs);
+        if (u + v > 1.0) {
+            u = 1.0 - u;
+            v = 1.0 - v;
+        }
+
+        let point = light.position + light.edge_ab * u + light.edge_ac * v;
+        let normal_vector = cross(light.edge_ab, light.edge_ac);
+        let area = 0.5 * length(normal_vector);
+
+        let offset = point - position;
+        let distance = length(offset);
+        let direction = offset / distance;
+
+        // Triangles only emit from their front face
+        let cos_light = -dot(direction, normalize(normal_vector));
+        if (cos_light <= 0.0 || area <= 0.0) {
+            return sample;
+        }
+
+        sample.direction = direction;
+        sample.distance = distance;
+        sample.pdf = distance * distance / (area * cos_light);
+        sample.emission = light.emission;
+    } else if (light.kind == LIGHT_DIRECTIONAL) {
+        sample.direction = -light.direction;
+        sample.distance = NO_INTERSECTION;
+        sample.pdf = 1.0;
+        sample.emission = light.emission;
+    } else {
+        let offset = light.position - position;
+        let distance_squared = dot(offset, offset);
+        let distance = sqrt(distance_squared);
+        let direction = offset / distance;
+
+        var attenuation = 1.0 / distance_squared;
+        if (light.range > 0.0) {
+            // The falloff glTF recommends, reaching zero at the range
+            let ratio_squared = distance_squared / (light.range * light.range);
+            let falloff = saturate(1.0 - ratio_squared * ratio_squared);
+            attenuation *= falloff * falloff;
+        }
+
+        if (light.kind == LIGHT_SPOT) {
+            let cos_angle = dot(-direction, light.direction);
+            let cone_width = max(light.cos_inner_cone - light.cos_outer_cone, 1E-4);
+            let falloff = saturate((cos_angle - light.cos_outer_cone) / cone_width);
+            attenuation *= falloff * falloff;
+        }
+
+        if (attenuation <= 0.0 || distance_squared <= 0.0) {
+            return sample;
+        }
+
+        sample.direction = direction;
+        sample.distance = distance;
+        sample.pdf = 1.0;
+        sample.emission = light.emission * attenuation;
+    }
+
+    return sample;
+}
+
+fn light_pdf(origin: vec3<f32>, direction: vec3<f32>, hit_info: HitInfo) -> f32 {
+    // The pdf light sampling would have had of choosing this hit, including picking the light
+    let light_count = f32(light_buffer.count);
+    if (hit_info.sphere_id != NO_SPHERE) {
+        let sphere = sphere_buffer.spheres[hit_info.sphere_id];
+        let cos_max = sphere_light_cos_max(sphere.position, sphere.radius, origin);
+        if (cos_max < 0.0) {
+            return 0.0;
+        }
+
+        return 1.0 / (2.0 * PI * (1.0 - cos_max) * light_count);
+    }
+
+    let cos_light = abs(dot(direction, hit_info.normal));
+    return hit_info.distance * hit_info.distance / (hit_info.area * cos_light * light_count);
+}
+
+fn sample_direct_lighting(hit_info: HitInfo, mat: Material, view: vec3<f32>, rs: RandomState) -> vec3<f32> {
+    // Estimates light reflected from a randomly chosen emitter towards the viewer,
+    // weighted against the chance of the bounce ray finding the same light
+    let light_count = light_buffer.count;
+    if (light_count == 0u) {
+        return vec3<f32>(0.0);
+    }
+
+    let light_index = min(u32(random_value(rs) * f32(light_count)), light_count - 1u);
+    let light = light_buffer.lights[light_index];
+    let sample = sample_light(light, hit_info.position, rs);
+
+    let bsdf = evaluate_bsdf(mat, hit_info.shading_normal, view, sample.direction);
+    if (sample.pdf <= 0.0 || bsdf.pdf <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+
+    var shadow_ray: Ray;
+    shadow_ray.origin = hit_info.position + hit_info.normal * RAY_EPSILON;
+    shadow_ray.direction = sample.direction;
+
+    // Stop just short of the light so it doesn't shadow itself
+    let occluder = ray_world_collision(shadow_ray, sample.distance * (1.0 - 1E-3));
+    if (occluder.hit) {
+        return vec3<f32>(0.0);
+    }
+
+    let pdf = sample.pdf / f32(light_count);
+
+    // Bounce rays can't hit point, spot or directional lights, so nothing else counts their light
+    if (light.kind >= LIGHT_POINT) {
+        return sample.emission * bsdf.value / pdf;
+    }
+
+    let weight = power_heuristic(pdf, bsdf.pdf);
+    return sample.emission * bsdf.value * weight / pdf;
+}
+
+// ============================= Materials ============================ */
+
+struct BsdfEvaluation {
+    // Reflected light per unit of incoming light, including the cosine term
+    value: vec3<f32>,
+    // Solid angle pdf of `sample_bsdf` choosing the same direction
+    pdf: f32,
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn roughness_alpha(mat: Material) -> f32 {
+    // Perceptually linear roughness, kept off zero so perfect mirrors stay finite
+    return max(mat.roughness * mat.roughness, 1E-3);
+}
+
+fn specular_color(mat: Material) -> vec3<f32> {
+    // Dielectrics reflect according to their IOR, metals tint reflections with their base color
+    let r = (mat.ior - 1.0) / (mat.ior + 1.0);
+    return mix(vec3<f32>(r * r), mat.color.xyz, mat.metallic);
+}
+
+fn fresnel_schlick(f0: vec3<f32>, cos_theta: f32) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(1.0 - saturate(cos_theta), 5.0);
+}
+
+fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let alpha_squared = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (alpha_squared - 1.0) + 1.0;
+    return alpha_squared / (PI * d * d);
+}
+
+fn smith_g1(n_dot_x: f32, alpha: f32) -> f32 {
+    let alpha_squared = alpha * alpha;
+    return 2.0 * n_dot_x / (n_dot_x + sqrt(alpha_squared + (1.0 - alpha_squared) * n_dot_x * n_dot_x));
+}
+
+fn specular_probability(mat: Material, n_dot_v: f32) -> f32 {
+    // Split samples between the lobes by roughly how much light each reflects
+    let specular = luminance(fresnel_schlick(specular_color(mat), n_dot_v));
+    let diffuse = luminance(mat.color.xyz) * (1.0 - mat.metallic);
+    return specular / max(specular + diffuse, 1E-6);
+}
+
+fn evaluate_bsdf(mat: Material, normal: vec3<f32>, view: vec3<f32>, direction: vec3<f32>) -> BsdfEvaluation {
+    // Lambertian diffuse under a GGX microfacet specular lobe, as in glTF's metallic/roughness model
+    var result: BsdfEvaluation;
+    result.value = vec3<f32>(0.0);
+    result.pdf = 0.0;
+
+    let n_dot_l = dot(normal, direction);
+    let n_dot_v = dot(normal, view);
+    if (n_dot_l <= 0.0 || n_dot_v <= 0.0) {
+        return result;
+    }
+
+    let half_vector = normalize(view + direction);
+    let n_dot_h = saturate(dot(normal, half_vector));
+    let v_dot_h = saturate(dot(view, half_vector));
+    let alpha = roughness_alpha(mat);
+
+    let fresnel = fresnel_schlick(specular_color(mat), v_dot_h);
+    let distribution = ggx_distribution(n_dot_h, alpha);
+    let geometry = smith_g1(n_dot_v, alpha) * smith_g1(n_dot_l, alpha);
+
+    let specular = fresnel * distribution * geometry / (4.0 * n_dot_v * n_dot_l);
+    let diffuse = (1.0 - fresnel) * (1.0 - mat.metallic) * mat.color.xyz / PI;
+
+    let specular_chance = specular_probability(mat, n_dot_v);
+    let specular_pdf = distribution * n_dot_h / (4.0 * max(v_dot_h, 1E-6));
+    let diffuse_pdf = n_dot_l / PI;
+
+    result.value = (diffuse + specular) * n_dot_l;
+    result.pdf = mix(diffuse_pdf, specular_pdf, specular_chance);
+    return result;
+}
+
+fn sample_bsdf(mat: Material, normal: vec3<f32>, view: vec3<f32>, rs: RandomState) -> vec3<f32> {
+    if (random_value(rs) < specular_probability(mat, dot(normal, view))) {
+        // Sample a microfacet normal from the GGX distribution and reflect off it
+        let alpha = roughness_alpha(mat);
+        let u = random_value(rs);
+        let cos_theta = sqrt((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u));
+        let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+        let phi = 2.0 * PI * random_value(rs);
+
+        let half_vector = orthonormal_basis(normal) * vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+        return reflect(-view, half_vector);
+    }
+
+    return normalize(normal + random_unit_vector(rs));
+}
+
+fn fresnel_dielectric(cos_incident: f32, eta: f32) -> f32 {
+    // Unpolarized reflectance of a smooth dielectric, or 1 under total internal reflection
+    let sin_squared_transmitted = eta * eta * (1.0 - cos_incident * cos_incident);
+    if (sin_squared_transmitted >= 1.0) {
+        return 1.0;
+    }
+
+    let cos_transmitted = sqrt(1.0 - sin_squared_transmitted);
+    let r_s = (eta * cos_incident - cos_transmitted) / (eta * cos_incident + cos_transmitted);
+    let r_p = (cos_incident - eta * cos_transmitted) / (cos_incident + eta * cos_transmitted);
+
+    return 0.5 * (r_s * r_s + r_p * r_p);
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, color <= vec3<f32>(0.04045));
+}
+
+fn atlas_texel(offset: vec2<u32>, size: vec2<u32>, texel: vec2<i32>) -> vec4<f32> {
+    // Wraps around the texture's own region of the atlas, like a repeating sampler
+    let wrapped = ((texel % vec2<i32>(size)) + vec2<i32>(size)) % vec2<i32>(size);
+    return textureLoad(texture_atlas, vec2<i32>(offset) + wrapped, 0);
+}
+
+fn atlas_sample(offset: vec2<u32>, size: vec2<u32>, uv: vec2<f32>, srgb: bool) -> vec4<f32> {
+    // Bilinear filtering by hand, since the atlas can't be sampled across texture edges
+    let position = uv * vec2<f32>(size) - 0.5;
+    let texel = vec2<i32>(floor(position));
+    let t = fract(position);
+
+    var texels = array<vec4<f32>, 4>(
+        atlas_texel(offset, size, texel),
+        atlas_texel(offset, size, texel + vec2<i32>(1, 0)),
+        atlas_texel(offset, size, texel + vec2<i32>(0, 1)),
+        atlas_texel(offset, size, texel + vec2<i32>(1, 1)),
+    );
+
+    // Color is decoded before filtering, but data like normals is stored linearly
+    if (srgb) {
+        for (var i = 0; i < 4; i++) {
+            texels[i] = vec4<f32>(srgb_to_linear(texels[i].xyz), texels[i].w);
+        }
+    }
+
+    return mix(mix(texels[0], texels[1], t.x), mix(texels[2], texels[3], t.x), t.y);
+}
+
+fn texture_color(mat: Material, uv: vec2<f32>) -> vec4<f32> {
+    if (any(mat.texture_size == vec2<u32>(0u))) {
+        return vec4<f32>(1.0);
+    }
+
+    return atlas_sample(mat.texture_offset, mat.texture_size, uv, true);
+}
+
+fn apply_normal_map(mat: Material, hit_info: HitInfo) -> vec3<f32> {
+    // Tilts the shading normal by the normal map, in the tangent frame around it
+    let normal = hit_info.shading_normal;
+    let tangent = hit_info.tangent.xyz - normal * dot(normal, hit_info.tangent.xyz);
+    if (any(mat.normal_map_size == vec2<u32>(0u)) || dot(tangent, tangent) <= 0.0) {
+        return normal;
+    }
+
+    let t = normalize(tangent);
+    let b = cross(normal, t) * select(1.0, -1.0, hit_info.tangent.w < 0.0);
+
+    let sampled = atlas_sample(mat.normal_map_offset, mat.normal_map_size, hit_info.uv, false).xyz * 2.0 - 1.0;
+    let mapped = vec3<f32>(sampled.xy * mat.normal_scale, sampled.z);
+    let perturbed = t * mapped.x + b * mapped.y + normal * mapped.z;
+    if (dot(perturbed, perturbed) <= 0.0) {
+        return normal;
+    }
+
+    return normalize(perturbed);
+}
+
+// ============================= Environment ============================ */
+
+fn environment_uv(direction: vec3<f32>) -> vec2<f32> {
+    // Equirectangular mapping with the poles along the y axis
+    let phi = atan2(direction.z, direction.x) - globals.environment_rotation;
+    let theta = acos(clamp(direction.y, -1.0, 1.0));
+    return vec2<f32>(fract(phi / (2.0 * PI)), theta / PI);
+}
+
+fn environment_direction(uv: vec2<f32>) -> vec3<f32> {
+    let phi = uv.x * 2.0 * PI + globals.environment_rotation;
+    let theta = uv.y * PI;
+    return vec3<f32>(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+}
+
+fn environment_texel(uv: vec2<f32>) -> vec2<u32> {
+    let size = textureDimensions(environment_texture);
+    return min(vec2<u32>(uv * vec2<f32>(size)), size - 1u);
+}
+
+fn environment_radiance(direction: vec3<f32>) -> vec3<f32> {
+    let texel = environment_texel(environment_uv(direction));
+    return textureLoad(environment_texture, texel, 0).xyz * globals.environment_intensity;
+}
+
+fn environment_pdf(direction: vec3<f32>) -> f32 {
+    // The solid angle pdf of `sample_environment` choosing the direction
+    let uv = environment_uv(direction);
+    let texel = environment_texel(uv);
+    let size = textureDimensions(environment_texture);
+
+    let row = textureLoad(environment_marginal_cdf, vec2<u32>(texel.y + 1u, 0u), 0).x
+        - textureLoad(environment_marginal_cdf, vec2<u32>(texel.y, 0u), 0).x;
+    let column = textureLoad(environment_conditional_cdf, vec2<u32>(texel.x + 1u, texel.y), 0).x
+        - textureLoad(environment_conditional_cdf, texel, 0).x;
+
+    let sin_theta = sin(uv.y * PI);
+    if (sin_theta <= 0.0) {
+        return 0.0;
+    }
+
+    return row * column * f32(size.x * size.y) / (2.0 * PI * PI * sin_theta);
+}
+
+fn search_cdf(cdf: texture_2d<f32>, row: u32, count: u32, value: f32) -> u32 {
+    // Finds the last of `count` entries in the row whose cumulative value is at most `value`
+    var low = 0u;
+    var high = count;
+    while (low + 1u < high) {
+        let middle = (low + high) / 2u;
+        if (textureLoad(cdf, vec2<u32>(middle, row), 0).x <= value) {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    return low;
+}
+
+fn sample_environment(rs: RandomState) -> vec3<f32> {
+    let size = textureDimensions(environment_texture);
+    let y = search_cdf(environment_marginal_cdf, 0u, size.y, random_value(rs));
+    let x = search_cdf(environment_conditional_cdf, y, size.x, random_value(rs));
+
+    let uv = (vec2<f32>(f32(x), f32(y)) + vec2<f32>(random_value(rs), random_value(rs))) / vec2<f32>(size);
+    return environment_direction(uv);
+}
+
+fn sample_environment_lighting(hit_info: HitInfo, mat: Material, view: vec3<f32>, rs: RandomState) -> vec3<f32> {
+    // Estimates light reflected from the environment, favouring its brightest parts
+    let direction = sample_environment(rs);
+    let pdf = environment_pdf(direction);
+    let bsdf = evaluate_bsdf(mat, hit_info.shading_normal, view, direction);
+    if (pdf <= 0.0 || bsdf.pdf <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+
+    var shadow_ray: Ray;
+    shadow_ray.origin = hit_info.position + hit_info.normal * RAY_EPSILON;
+    shadow_ray.direction = direction;
+
+    let occluder = ray_world_collision(shadow_ray, NO_INTERSECTION);
+    if (occluder.hit) {
+        return vec3<f32>(0.0);
+    }
+
+    let weight = power_heuristic(pdf, bsdf.pdf);
+    return environment_radiance(direction) * bsdf.value * weight / pdf;
+}
+
 fn trace(ray: Ray, rs: RandomState) -> vec3<f32> {
     var light = vec3<f32>(0.0);
     var ray_color = vec3<f32>(1.0);
 
     var ray = ray;
     var bounces: u32 = 0u;
+
+    // How the current ray was chosen, to weigh emission it hits against light sampling
+    var bsdf_pdf = 0.0;
+    var specular_bounce = true;
+
     while (bounces <= globals.max_ray_bounces) {
-        let hit_info = ray_world_collision(ray);
+        var hit_info = ray_world_collision(ray, globals.camera.far_clip);
         if (!hit_info.hit) {
-            if (bounces > 0u) {
+            if (globals.has_environment != 0u) {
+                var weight = 1.0;
+                if (!specular_bounce) {
+                    weight = power_heuristic(bsdf_pdf, environment_pdf(ray.direction));
+                }
+
+                light += environment_radiance(ray.direction) * ray_color * weight;
+            } else if (bounces > 0u) {
                 let env_light = get_environment_lighting();
                 light += vec3<f32>(
                     env_light.x * ray_color.x,
@@ -216,30 +976,109 @@ fn trace(ray: Ray, rs: RandomState) -> vec3<f32> {
 
         bounces++;
 
-        let mat = mat_buffer.materials[hit_info.material_id];
+        var mat = mat_buffer.materials[hit_info.material_id];
+        mat.color *= texture_color(mat, hit_info.uv) * hit_info.color;
+        hit_info.shading_normal = apply_normal_map(mat, hit_info);
+
+        // Surfaces are shaded from whichever side the ray arrived on
+        let front_face = dot(ray.direction, hit_info.normal) < 0.0;
+        hit_info.normal = select(-hit_info.normal, hit_info.normal, front_face);
+        hit_info.shading_normal = select(-hit_info.shading_normal, hit_info.shading_normal, front_face);
 
-        let diffuse_reflection = normalize(hit_info.normal + random_unit_vector(rs));
-        let specular_reflection = reflect(ray.direction, hit_info.normal);
+        // Light fades with the distance it travelled through an absorbing medium
+        if (!front_face) {
+            ray_color *= exp(-mat.absorption * hit_info.distance);
+        }
 
-        ray.origin = hit_info.position;
-        ray.direction = normalize(mix(diffuse_reflection, specular_reflection, mat.smoothness));
+        if (any(mat.emission > vec3<f32>(0.0)) && front_face) {
+            var weight = 1.0;
+            if (!specular_bounce) {
+                weight = power_heuristic(bsdf_pdf, light_pdf(ray.origin, ray.direction, hit_info));
+            }
 
-        let emission = mat.color.xyz * mat.luminosity;
-        light += emission * ray_color;
+            light += mat.emission * ray_color * weight;
+        }
 
-        ray_color *= mix(mat.color.xyz, mat.color.xyz, mat.smoothness);
+        // Pick between refracting through the surface and reflecting off it
+        ray.origin = hit_info.position + hit_info.normal * RAY_EPSILON;
+        if (random_value(rs) < mat.transmission) {
+            // Ratio of the refractive indices on the incoming and outgoing sides
+            let eta = select(mat.ior, 1.0 / mat.ior, front_face);
+            let cos_incident = saturate(-dot(ray.direction, hit_info.shading_normal));
 
-        let p = max(ray_color.x, max(ray_color.y, ray_color.z));
-        if (random_value(rs) >= p) {
-            break;
+            if (random_value(rs) < fresnel_dielectric(cos_incident, eta)) {
+                ray.direction = reflect(ray.direction, hit_info.shading_normal);
+            } else {
+                ray.origin = hit_info.position - hit_info.normal * RAY_EPSILON;
+                ray.direction = refract(ray.direction, hit_info.shading_normal, eta);
+                ray_color *= mat.color.xyz;
+            }
+
+            specular_bounce = true;
+        } else {
+            let view = -ray.direction;
+            light += sample_direct_lighting(hit_info, mat, view, rs) * ray_color;
+            if (globals.has_environment != 0u) {
+                light += sample_environment_lighting(hit_info, mat, view, rs) * ray_color;
+            }
+
+            ray.direction = sample_bsdf(mat, hit_info.shading_normal, view, rs);
+            let bsdf = evaluate_bsdf(mat, hit_info.shading_normal, view, ray.direction);
+            if (bsdf.pdf <= 0.0) {
+                break;
+            }
+
+            ray_color *= bsdf.value / bsdf.pdf;
+            bsdf_pdf = bsdf.pdf;
+            specular_bounce = false;
         }
 
-        ray_color /= p;
+        // Russian roulette, once paths have had a few bounces to pick up light.
+        // Sampled BSDF weights can take the throughput above 1, where the ray
+        // has to survive without being scaled down.
+        if (bounces > ROULETTE_MIN_BOUNCES) {
+            let p = min(max(ray_color.x, max(ray_color.y, ray_color.z)), 1.0);
+            if (random_value(rs) >= p) {
+                break;
+            }
+
+            ray_color /= p;
+        }
     }
 
     return light;
 }
 
+// ============================= Reconstruction Filters ============================ */
+
+fn filter_weight_1d(x: f32, radius: f32) -> f32 {
+    let t = abs(x) / radius;
+    if (t >= 1.0) {
+        return 0.0;
+    }
+
+    let filter_type = globals.reconstruction_filter;
+    if (filter_type == FILTER_TENT) {
+        return 1.0 - t;
+    } else if (filter_type == FILTER_GAUSSIAN) {
+        // Shifted down so the weight reaches zero at the filter's edge
+        let sigma = radius / 3.0;
+        let falloff = -1.0 / (2.0 * sigma * sigma);
+        return max(exp(falloff * x * x) - exp(falloff * radius * radius), 0.0);
+    } else if (filter_type == FILTER_BLACKMAN_HARRIS) {
+        let phase = 2.0 * PI * (0.5 + 0.5 * x / radius);
+        return 0.35875 - 0.48829 * cos(phase) + 0.14128 * cos(2.0 * phase) - 0.01168 * cos(3.0 * phase);
+    }
+
+    // FILTER_BOX
+    return 1.0;
+}
+
+fn filter_weight(offset: vec2<f32>) -> f32 {
+    let radius = max(globals.filter_radius, 1E-3);
+    return filter_weight_1d(offset.x, radius) * filter_weight_1d(offset.y, radius);
+}
+
 // ============================= Randomness ============================ */
 
 fn random_next(rs: RandomState) -> u32 {
@@ -286,8 +1125,9 @@ fn random_point_in_unit_circle(rs: RandomState) -> vec2<f32>
 @group(0) @binding(0)
 var<uniform> globals: Globals;
 
+// A copy of each pixel's accumulated sum for the display pass to normalize
 @group(0) @binding(1)
-var tex: texture_storage_2d<rgba32float, read_write>;
+var tex: texture_storage_2d<rgba32float, write>;
 
 @group(0) @binding(2)
 var<storage, read> mat_buffer: MaterialBuffer;
@@ -299,10 +1139,35 @@ var<storage, read> sphere_buffer: SphereBuffer;
 var<storage, read> vertex_buffer: VertexBuffer;
 
 @group(0) @binding(5)
-var<storage, read> index_buffer: IndexBuffer;
+var<storage, read> triangle_buffer: TriangleBuffer;
 
 @group(0) @binding(6)
-var<storage, read> mesh_buffer: MeshBuffer;
+var<storage, read> bvh_buffer: BvhBuffer;
+
+// Running sums of each pixel. Kept in a buffer rather than reading back the
+// output texture because read-write float storage textures aren't portable.
+@group(0) @binding(7)
+var<storage, read_write> accumulation_buffer: array<Accumulation>;
+
+@group(0) @binding(8)
+var<storage, read> instance_buffer: InstanceBuffer;
+
+@group(0) @binding(9)
+var<storage, read> light_buffer: LightBuffer;
+
+@group(0) @binding(10)
+var environment_texture: texture_2d<f32>;
+
+// Cumulative distributions of the environment's luminance, along each row and over the rows
+@group(0) @binding(11)
+var environment_conditional_cdf: texture_2d<f32>;
+
+@group(0) @binding(12)
+var environment_marginal_cdf: texture_2d<f32>;
+
+// Every material's texture, packed into one image
+@group(0) @binding(13)
+var texture_atlas: texture_2d<f32>;
 
 @compute
 @workgroup_size(1, 1, 1)
@@ -316,36 +1181,50 @@ fn main(
 
     var rs: u32 = pixel_index + globals.frame * 719393u + globals.random_seed;
 
-    let uv = vec2<f32>(
-        f32(pixel_coords.x) / f32(dimensions.x),
-        f32(pixel_coords.y) / f32(dimensions.y)
-    );
+    let pixel_size = globals.camera.focal_view.xy / vec2<f32>(dimensions);
+    let pixel_center = (vec2<f32>(pixel_coords) + 0.5) / vec2<f32>(dimensions);
 
-    let focus_point_local = vec3<f32>(uv - 0.5, 1.0) * globals.camera.focal_view;
+    let focus_point_local = vec3<f32>(pixel_center - 0.5, 1.0) * globals.camera.focal_view;
     let focus_point = globals.camera.local_to_world_matrix * vec4<f32>(focus_point_local, 1.0);
     let cam_right = globals.camera.local_to_world_matrix[0].xyz;
     let cam_up = globals.camera.local_to_world_matrix[1].xyz;
 
-    let num_samples = min(globals.max_samples_per_pixel, 100u);
+    var previous = accumulation_buffer[pixel_index];
+    if (globals.frame == 0u) {
+        previous = Accumulation(vec3<f32>(0.0), 0.0, 0u);
+    }
+
+    // Never trace past the target, even if the last frame would overshoot it
+    var num_samples = min(globals.max_samples_per_pixel, 100u);
+    if (globals.target_samples > 0u) {
+        num_samples = min(num_samples, globals.target_samples - min(previous.samples, globals.target_samples));
+    }
 
     var ray: Ray;
     var color = vec3<f32>(0.0);
+    var total_weight = 0.0;
     for (var i: u32 = 0u; i < num_samples; i++) {
         let ray_origin_jitter_offset = random_point_in_unit_circle(&rs) * globals.focal_blur_strength / f32(dimensions.x);
         ray.origin =  globals.camera.world_space_position + cam_right * ray_origin_jitter_offset.x + cam_up * ray_origin_jitter_offset.y;
-        
-        let ray_target_jitter_offset = vec2<f32>(0.0); // This could be used for anti-aliasing
+
+        // Jitter uniformly over the filter's footprint (in pixels) and weight the sample by the filter
+        let filter_offset = (vec2<f32>(random_value(&rs), random_value(&rs)) * 2.0 - 1.0) * globals.filter_radius;
+        let weight = filter_weight(filter_offset);
+
+        let ray_target_jitter_offset = filter_offset * pixel_size;
         let ray_focal_point = focus_point.xyz + cam_right * ray_target_jitter_offset.x + cam_up * ray_target_jitter_offset.y;
         ray.direction = normalize(ray_focal_point - ray.origin);
 
-        color += trace(ray, &rs);
+        color += trace(ray, &rs) * weight;
+        total_weight += weight;
     }
 
-    color /= max(f32(num_samples), 1.0);
-
-    let weight = 1.0 / (f32(globals.frame) / 4.0 + 1.0);
-    let previous_color = textureLoad(tex, g_invocation_id.xy).xyz;
-    let color_average = saturate(previous_color * (1.0 - weight) + color * weight);
+    let accumulated = Accumulation(
+        previous.color + color,
+        previous.weight + total_weight,
+        previous.samples + num_samples,
+    );
 
-    textureStore(tex, g_invocation_id.xy, vec4<f32>(color_average, 1.0));
+    accumulation_buffer[pixel_index] = accumulated;
+    textureStore(tex, g_invocation_id.xy, vec4<f32>(accumulated.color, accumulated.weight));
 }
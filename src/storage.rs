@@ -1,10 +1,8 @@
-use encase::{
-    private::WriteInto, ArrayLength, ShaderSize, ShaderType, StorageBuffer, UniformBuffer,
-};
+use encase::{private::WriteInto, ShaderSize, ShaderType, StorageBuffer, UniformBuffer};
 use glam::f32;
 
 pub trait Storable {
-    fn into_bytes(&self) -> Vec<u8>;
+    fn to_bytes(&self) -> Vec<u8>;
 }
 
 pub struct Uniform<'a, T>(pub &'a T)
@@ -15,7 +13,7 @@ impl<T> Storable for Uniform<'_, T>
 where
     T: ShaderType + ShaderSize + WriteInto,
 {
-    fn into_bytes(&self) -> Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
         let mut buffer = UniformBuffer::new(Vec::new());
         buffer.write(self.0).expect("Unable to write uniform");
         buffer.into_inner()
@@ -26,31 +24,11 @@ pub struct Buffer<'a, T>(pub &'a [T])
 where
     T: ShaderSize;
 
-#[derive(ShaderType)]
-struct SizedBuffer<'a, T: ShaderSize + 'a> {
-    length: ArrayLength,
-
-    #[size(runtime)]
-    buffer: &'a [T],
-}
-
-impl<'a, T> SizedBuffer<'a, T>
-where
-    T: ShaderSize + 'a,
-{
-    fn new(buffer: &'a [T]) -> Self {
-        Self {
-            length: ArrayLength,
-            buffer,
-        }
-    }
-}
-
 impl<T> Storable for Buffer<'_, T>
 where
     T: ShaderSize + WriteInto,
 {
-    fn into_bytes(&self) -> Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
         let data = SizedBuffer::new(self.0);
         let mut buffer = StorageBuffer::new(Vec::new());
         buffer.write(&data).expect("Unable to write buffer");
@@ -63,47 +41,152 @@ where
     Types
 */
 
-#[derive(ShaderType)]
-pub struct Globals {
-    pub camera: Camera,
-    pub frame: u32,
-    pub random_seed: u32,
-    pub skybox_color: f32::Vec3,
-    pub ambient_lighting_color: f32::Vec3,
-    pub ambient_lighting_strength: f32,
-    pub max_ray_bounces: u32,
-    pub max_samples_per_pixel: u32,
-    pub focal_blur_strength: f32,
-}
+pub use types::*;
 
-#[derive(ShaderType)]
-pub struct Camera {
-    pub focal_view: f32::Vec3,
-    pub world_space_position: f32::Vec3,
-    pub local_to_world_matrix: f32::Mat4,
-    pub near_clip: f32,
-    pub far_clip: f32,
-}
+// `ShaderType` derives emit compile-time checks that are never called, beside
+// the types rather than inside them, so only the module around them can allow it
+#[allow(dead_code)]
+mod types {
+    use encase::{ArrayLength, ShaderSize, ShaderType};
+    use glam::{f32, u32};
 
-#[derive(ShaderType)]
-pub struct Material {
-    pub color: f32::Vec4,
-    pub luminosity: f32,
-    pub smoothness: f32,
-}
+    #[derive(ShaderType)]
+    pub(super) struct SizedBuffer<'a, T: ShaderSize + 'a> {
+        length: ArrayLength,
 
-#[derive(ShaderType)]
-pub struct Sphere {
-    pub position: f32::Vec3,
-    pub radius: f32,
-    pub material_id: u32,
-}
+        #[size(runtime)]
+        buffer: &'a [T],
+    }
+
+    impl<'a, T> SizedBuffer<'a, T>
+    where
+        T: ShaderSize + 'a,
+    {
+        pub(super) fn new(buffer: &'a [T]) -> Self {
+            Self {
+                length: ArrayLength,
+                buffer,
+            }
+        }
+    }
+
+    #[derive(ShaderType, Clone, PartialEq)]
+    pub struct Globals {
+        pub camera: Camera,
+        pub frame: u32,
+        pub random_seed: u32,
+        pub skybox_color: f32::Vec3,
+        pub ambient_lighting_color: f32::Vec3,
+        pub ambient_lighting_strength: f32,
+        pub max_ray_bounces: u32,
+        pub max_samples_per_pixel: u32,
+        pub focal_blur_strength: f32,
+        pub reconstruction_filter: u32,
+        pub filter_radius: f32,
+        pub has_environment: u32,
+        pub environment_intensity: f32,
+        pub environment_rotation: f32,
+        pub target_samples: u32,
+        pub exposure: f32,
+        pub tone_mapping: u32,
+    }
 
-#[derive(ShaderType)]
-pub struct Mesh {
-    pub index_offset: u32,
-    pub triangle_count: u32,
-    pub material_id: u32,
+    /// A pixel's running sums, which the shader keeps in the accumulation buffer
+    #[derive(ShaderType)]
+    pub struct Accumulation {
+        pub color: f32::Vec3,
+        pub weight: f32,
+        pub samples: u32,
+    }
+
+    /// Settings for the display pass, which tone maps the accumulated image
+    #[derive(ShaderType)]
+    pub struct Display {
+        pub exposure: f32,
+        pub tone_mapping: u32,
+    }
+
+    #[derive(ShaderType, Clone, PartialEq)]
+    pub struct Camera {
+        pub focal_view: f32::Vec3,
+        pub world_space_position: f32::Vec3,
+        pub local_to_world_matrix: f32::Mat4,
+        pub near_clip: f32,
+        pub far_clip: f32,
+    }
+
+    #[derive(ShaderType)]
+    pub struct Material {
+        pub color: f32::Vec4,
+        pub emission: f32::Vec3,
+        pub metallic: f32,
+        pub roughness: f32,
+        pub transmission: f32,
+        pub ior: f32,
+        pub absorption: f32::Vec3,
+        pub texture_offset: u32::UVec2,
+        /// Zero when the material isn't textured
+        pub texture_size: u32::UVec2,
+        pub normal_map_offset: u32::UVec2,
+        /// Zero when the material isn't normal mapped
+        pub normal_map_size: u32::UVec2,
+        pub normal_scale: f32,
+    }
+
+    #[derive(ShaderType)]
+    pub struct Sphere {
+        pub position: f32::Vec3,
+        pub radius: f32,
+        pub material_id: u32,
+    }
+
+    #[derive(ShaderType, Clone, Copy)]
+    pub struct MeshVertex {
+        pub position: f32::Vec3,
+        /// Zero when the mesh is flat shaded
+        pub normal: f32::Vec3,
+        /// Zero when the mesh has no tangents
+        pub tangent: f32::Vec4,
+        pub uv: f32::Vec2,
+        /// White when the mesh has no vertex colors
+        pub color: f32::Vec4,
+    }
+
+    #[derive(ShaderType)]
+    pub struct Triangle {
+        pub indices: u32::UVec3,
+    }
+
+    #[derive(ShaderType)]
+    pub struct Instance {
+        pub world_to_object: f32::Mat4,
+        pub blas_root: u32,
+        pub material_id: u32,
+    }
+
+    /// An emissive sphere or triangle, or a point, spot or directional light, in
+    /// world space, sampled directly for lighting
+    #[derive(ShaderType)]
+    pub struct Light {
+        pub position: f32::Vec3,
+        pub kind: u32,
+        pub edge_ab: f32::Vec3,
+        pub radius: f32,
+        pub edge_ac: f32::Vec3,
+        pub range: f32,
+        pub emission: f32::Vec3,
+        pub cos_outer_cone: f32,
+        pub direction: f32::Vec3,
+        pub cos_inner_cone: f32,
+    }
+
+    #[derive(ShaderType)]
+    pub struct BvhNode {
+        pub aabb_min: f32::Vec3,
+        pub first: u32,
+        pub aabb_max: f32::Vec3,
+        pub count: u32,
+    }
 }
 
 #[repr(C)]
@@ -151,7 +234,7 @@ mod test {
             },
         ];
 
-        let bytes = Buffer(spheres).into_bytes();
+        let bytes = Buffer(spheres).to_bytes();
         assert_eq!(bytes[0], 2);
         assert_eq!(bytes.len() % 16, 0);
     }
@@ -1 +1,20 @@
+use std::path::Path;
 
+use crate::{
+    loader::{ImportOptions, Warning},
+    scene::Scene,
+};
+
+/// Reads scenes from files in one format
+pub trait SceneLoader {
+    /// The extensions files in the format usually have, in lowercase
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// Whether a file looks like it's in the format, from its first bytes and
+    /// its total size, for files without a known extension
+    fn matches(&self, start: &[u8], size: u64) -> bool;
+
+    /// Loads the file at `path`, along with anything in it that couldn't be
+    /// imported as it was
+    fn load(&self, path: &Path, options: &ImportOptions) -> Result<(Scene, Vec<Warning>), String>;
+}
@@ -0,0 +1,564 @@
+use std::fmt;
+
+use crate::scene;
+
+/// How much a problem matters. Scenes with errors can't be rendered, while
+/// warnings are likely mistakes that still render.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Something wrong with the data in a scene
+#[derive(Clone, PartialEq, Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Where the problem is in the scene, like `objects[3].surface.radius`
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "Warning",
+            Severity::Error => "Error",
+        };
+
+        write!(f, "{}: {}: {}", severity, self.location, self.message)
+    }
+}
+
+/// Checks a scene for data that would render wrongly or not at all, like
+/// negative radii or triangles indexing past the end of their mesh, and
+/// returns every problem found in scene order
+pub fn validate(scene: &scene::Scene) -> Vec<Problem> {
+    let mut problems = Problems::default();
+    problems.camera(&scene.camera);
+
+    for (name, mesh) in &scene.meshes {
+        let location = format!("meshes[{:?}]", name);
+        problems.mesh(&location, MeshView::from(mesh));
+        if let Some(bvh) = scene.bvhs.get(name) {
+            if let Err(e) = bvh.validate(mesh.indices.len()) {
+                problems.error(&location, format!("Invalid prebuilt BVH: {}", e));
+            }
+        }
+    }
+
+    for (i, image) in scene.images.iter().enumerate() {
+        problems.image(&format!("images[{}]", i), image);
+    }
+
+    for (name, material) in &scene.materials {
+        problems.material(&format!("materials[{:?}]", name), material, scene);
+    }
+
+    for (i, object) in scene.objects.iter().enumerate() {
+        problems.object(&format!("objects[{}]", i), object, scene);
+    }
+
+    for (i, light) in scene.lights.iter().enumerate() {
+        problems.light(&format!("lights[{}]", i), light);
+    }
+
+    problems.0
+}
+
+/// The attributes of a mesh, whether it's named or given inline by an object
+struct MeshView<'a> {
+    vertices: &'a [glam::Vec3],
+    indices: &'a [glam::UVec3],
+    uvs: &'a [glam::Vec2],
+    normals: &'a [glam::Vec3],
+    tangents: &'a [glam::Vec4],
+    colors: &'a [glam::Vec4],
+}
+
+impl<'a> From<&'a scene::Mesh> for MeshView<'a> {
+    fn from(mesh: &'a scene::Mesh) -> Self {
+        MeshView {
+            vertices: &mesh.vertices,
+            indices: &mesh.indices,
+            uvs: &mesh.uvs,
+            normals: &mesh.normals,
+            tangents: &mesh.tangents,
+            colors: &mesh.colors,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn push(&mut self, severity: Severity, location: &str, message: String) {
+        self.0.push(Problem {
+            severity,
+            location: location.to_string(),
+            message,
+        });
+    }
+
+    fn error(&mut self, location: &str, message: String) {
+        self.push(Severity::Error, location, message);
+    }
+
+    fn warning(&mut self, location: &str, message: String) {
+        self.push(Severity::Warning, location, message);
+    }
+
+    /// Reports a value that isn't a number or is infinite
+    fn finite(&mut self, location: &str, value: impl fmt::Debug, is_finite: bool) -> bool {
+        if !is_finite {
+            self.error(location, format!("{:?} isn't finite", value));
+        }
+
+        is_finite
+    }
+
+    /// Reports a value that should be a fraction between 0 and 1
+    fn fraction(&mut self, location: &str, value: f32) {
+        if self.finite(location, value, value.is_finite()) && !(0.0..=1.0).contains(&value) {
+            self.warning(location, format!("{} is outside 0 to 1", value));
+        }
+    }
+
+    fn transform(&mut self, location: &str, transform: &scene::Transform) -> bool {
+        let scene::Transform {
+            position,
+            rotation,
+            scale,
+        } = transform;
+
+        let position = self.finite(
+            &format!("{}.position", location),
+            position,
+            position.is_finite(),
+        );
+        let rotation = self.finite(
+            &format!("{}.rotation", location),
+            rotation,
+            rotation.is_finite(),
+        );
+        let scale = self.finite(&format!("{}.scale", location), scale, scale.is_finite());
+        position && rotation && scale
+    }
+
+    fn camera(&mut self, camera: &scene::Camera) {
+        self.transform("camera.transform", &camera.transform);
+
+        let scene::Lens::Perspective {
+            fov,
+            focal_distance,
+        } = camera.lens;
+
+        if self.finite("camera.lens.fov", fov, fov.is_finite()) && (fov <= 0.0 || fov >= 180.0) {
+            self.error(
+                "camera.lens.fov",
+                format!("{}° is outside 0° to 180°, exclusive", fov),
+            );
+        }
+
+        if self.finite(
+            "camera.lens.focal_distance",
+            focal_distance,
+            focal_distance.is_finite(),
+        ) && focal_distance <= 0.0
+        {
+            self.error(
+                "camera.lens.focal_distance",
+                format!("{} isn't positive", focal_distance),
+            );
+        }
+
+        let scene::Clipping { near, far } = camera.clipping;
+        let near_finite = self.finite("camera.clipping.near", near, near.is_finite());
+        let far_finite = self.finite("camera.clipping.far", far, far.is_finite());
+        if near_finite && near < 0.0 {
+            self.error("camera.clipping.near", format!("{} is negative", near));
+        }
+
+        if near_finite && far_finite && far <= near {
+            self.error(
+                "camera.clipping",
+                format!(
+                    "The far plane ({}) isn't beyond the near plane ({})",
+                    far, near
+                ),
+            );
+        }
+    }
+
+    fn object(&mut self, location: &str, object: &scene::Object, scene: &scene::Scene) {
+        let transform = format!("{}.transform", location);
+        let finite = self.transform(&transform, &object.transform);
+        let scale = object.transform.scale;
+
+        match &object.surface {
+            scene::Surface::Sphere { radius } => {
+                let location = format!("{}.surface.radius", location);
+                if self.finite(&location, radius, radius.is_finite()) {
+                    if *radius < 0.0 {
+                        self.error(&location, format!("{} is negative", radius));
+                    } else if *radius == 0.0 {
+                        self.warning(&location, "The sphere has no size".to_string());
+                    }
+                }
+
+                // Spheres are scaled by the larger of their X and Y scale
+                let sphere_scale = f32::max(scale.x, scale.y);
+                if finite && sphere_scale <= 0.0 {
+                    self.error(
+                        &format!("{}.scale", transform),
+                        format!("{:?} gives the sphere a radius of zero or less", scale),
+                    );
+                }
+            }
+            surface => {
+                // Meshes are drawn through the inverse of their transform
+                if finite && scale.cmpeq(glam::Vec3::ZERO).any() {
+                    self.error(
+                        &format!("{}.scale", transform),
+                        format!("{:?} flattens the mesh to nothing", scale),
+                    );
+                }
+
+                let location = format!("{}.surface", location);
+                match surface {
+                    scene::Surface::MeshData {
+                        vertices,
+                        indices,
+                        uvs,
+                        normals,
+                        tangents,
+                        colors,
+                    } => self.mesh(
+                        &location,
+                        MeshView {
+                            vertices,
+                            indices,
+                            uvs,
+                            normals,
+                            tangents,
+                            colors,
+                        },
+                    ),
+                    scene::Surface::Mesh { name } => {
+                        if !scene.meshes.contains_key(name) {
+                            self.error(&location, format!("Unknown mesh: '{}'", name));
+                        }
+                    }
+                    scene::Surface::MeshFile { path, .. } => self.error(
+                        &location,
+                        format!("Mesh file wasn't loaded: {}", path.display()),
+                    ),
+                    scene::Surface::Sphere { .. } => unreachable!(),
+                }
+            }
+        }
+
+        let location = format!("{}.material", location);
+        match &object.material {
+            scene::MaterialRef::Inline(material) => self.material(&location, material, scene),
+            scene::MaterialRef::Named(name) => {
+                if !scene.materials.contains_key(name) {
+                    self.error(&location, format!("Unknown material: '{}'", name));
+                }
+            }
+        }
+    }
+
+    fn mesh(&mut self, location: &str, mesh: MeshView) {
+        let count = mesh.vertices.len();
+        if mesh.indices.is_empty() {
+            self.warning(location, "The mesh has no triangles".to_string());
+        }
+
+        fn first<T: Copy>(values: &[T], test: impl Fn(T) -> bool) -> Option<(usize, T)> {
+            values.iter().copied().enumerate().find(|(_, v)| test(*v))
+        }
+
+        fn non_finite<T: Copy + fmt::Debug>(
+            values: &[T],
+            is_finite: fn(T) -> bool,
+        ) -> Option<(usize, String)> {
+            first(values, |v| !is_finite(v)).map(|(i, v)| (i, format!("{:?}", v)))
+        }
+
+        if let Some((i, vertex)) = first(mesh.vertices, |v| !v.is_finite()) {
+            self.finite(&format!("{}.vertices[{}]", location, i), vertex, false);
+        }
+
+        let out_of_range = |i: glam::UVec3| i.max_element() as usize >= count;
+        if let Some((i, triangle)) = first(mesh.indices, out_of_range) {
+            let others = mesh.indices.iter().filter(|i| out_of_range(**i)).count() - 1;
+            let message = match others {
+                0 => format!("{:?} is out of range of {} vertices", triangle, count),
+                _ => format!(
+                    "{:?} is out of range of {} vertices, and so are {} more triangles",
+                    triangle, count, others
+                ),
+            };
+
+            self.error(&format!("{}.indices[{}]", location, i), message);
+        }
+
+        // Optional attributes are either missing or given for every vertex
+        let mut attribute = |name: &str, len: usize, non_finite: Option<(usize, String)>| {
+            let location = format!("{}.{}", location, name);
+            if len != 0 && len != count {
+                self.error(
+                    &location,
+                    format!("The mesh has {} {} but {} vertices", len, name, count),
+                );
+            }
+
+            if let Some((i, value)) = non_finite {
+                self.error(
+                    &format!("{}[{}]", location, i),
+                    format!("{} isn't finite", value),
+                );
+            }
+        };
+
+        attribute(
+            "uvs",
+            mesh.uvs.len(),
+            non_finite(mesh.uvs, |v| v.is_finite()),
+        );
+        attribute(
+            "normals",
+            mesh.normals.len(),
+            non_finite(mesh.normals, |v| v.is_finite()),
+        );
+        attribute(
+            "tangents",
+            mesh.tangents.len(),
+            non_finite(mesh.tangents, |v| v.is_finite()),
+        );
+        attribute(
+            "colors",
+            mesh.colors.len(),
+            non_finite(mesh.colors, |v| v.is_finite()),
+        );
+    }
+
+    fn material(&mut self, location: &str, material: &scene::Material, scene: &scene::Scene) {
+        let field = |name: &str| format!("{}.{}", location, name);
+
+        self.finite(&field("color"), material.color, material.color.is_finite());
+
+        let luminosity = material.luminosity;
+        if self.finite(&field("luminosity"), luminosity, luminosity.is_finite()) && luminosity < 0.0
+        {
+            self.error(&field("luminosity"), format!("{} is negative", luminosity));
+        }
+
+        if let Some(emission) = material.emission_color {
+            self.finite(&field("emission_color"), emission, emission.is_finite());
+        }
+
+        self.fraction(&field("metallic"), material.metallic);
+        self.fraction(&field("roughness"), material.roughness);
+        self.fraction(&field("transmission"), material.transmission);
+
+        let ior = material.ior;
+        if self.finite(&field("ior"), ior, ior.is_finite()) && ior <= 0.0 {
+            self.error(&field("ior"), format!("{} isn't positive", ior));
+        }
+
+        if let Some(absorption) = material.absorption {
+            let location = field("absorption");
+            if self.finite(&location, absorption, absorption.is_finite())
+                && absorption.cmplt(glam::Vec3::ZERO).any()
+            {
+                self.error(&location, format!("{:?} is negative", absorption));
+            }
+        }
+
+        let textures = [
+            ("texture", &material.texture),
+            ("normal_texture", &material.normal_texture),
+        ];
+
+        for (name, texture) in textures {
+            match texture {
+                Some(scene::Texture::Image(index)) if *index >= scene.images.len() => {
+                    self.error(
+                        &field(name),
+                        format!(
+                            "Image {} is out of range of {} images",
+                            index,
+                            scene.images.len()
+                        ),
+                    );
+                }
+                Some(scene::Texture::File(path)) if !path.is_file() => {
+                    self.error(
+                        &field(name),
+                        format!("Image file not found: {}", path.display()),
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn image(&mut self, location: &str, image: &scene::Image) {
+        if image.width == 0 || image.height == 0 {
+            self.error(
+                location,
+                format!("The image is empty ({}x{})", image.width, image.height),
+            );
+        }
+
+        let expected = image.width as usize * image.height as usize * 4;
+        if image.pixels.len() != expected {
+            self.error(
+                &format!("{}.pixels", location),
+                format!(
+                    "{} bytes don't match a {}x{} RGBA image ({} bytes)",
+                    image.pixels.len(),
+                    image.width,
+                    image.height,
+                    expected
+                ),
+            );
+        }
+    }
+
+    fn light(&mut self, location: &str, light: &scene::Light) {
+        let field = |name: &str| format!("{}.{}", location, name);
+        self.transform(&field("transform"), &light.transform);
+
+        if self.finite(&field("color"), light.color, light.color.is_finite())
+            && light.color.cmplt(glam::Vec3::ZERO).any()
+        {
+            self.error(&field("color"), format!("{:?} is negative", light.color));
+        }
+
+        let intensity = light.intensity;
+        if self.finite(&field("intensity"), intensity, intensity.is_finite()) {
+            if intensity < 0.0 {
+                self.error(&field("intensity"), format!("{} is negative", intensity));
+            } else if intensity == 0.0 {
+                self.warning(&field("intensity"), "The light is off".to_string());
+            }
+        }
+
+        if let Some(range) = light.range {
+            if self.finite(&field("range"), range, !range.is_nan()) && range <= 0.0 {
+                self.error(&field("range"), format!("{} isn't positive", range));
+            }
+        }
+
+        if let scene::LightKind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } = light.kind
+        {
+            let location = field("kind");
+            let angles = glam::vec2(inner_cone_angle, outer_cone_angle);
+            if self.finite(&location, angles, angles.is_finite()) {
+                if inner_cone_angle < 0.0 || outer_cone_angle <= 0.0 || outer_cone_angle > 180.0 {
+                    self.error(
+                        &location,
+                        format!(
+                            "Cone angles {}° and {}° are outside 0° to 180°",
+                            inner_cone_angle, outer_cone_angle
+                        ),
+                    );
+                } else if inner_cone_angle > outer_cone_angle {
+                    self.warning(
+                        &location,
+                        format!(
+                            "The inner cone angle ({}°) is wider than the outer one ({}°)",
+                            inner_cone_angle, outer_cone_angle
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scene() -> scene::Scene {
+        let source = include_str!("../examples/01-spheres.ron");
+        ron::from_str(source).expect("Unable to parse scene")
+    }
+
+    #[test]
+    fn test_example_is_valid() {
+        assert_eq!(validate(&scene()), []);
+    }
+
+    #[test]
+    fn test_problems() {
+        let mut scene = scene();
+        scene.camera.lens = scene::Lens::Perspective {
+            fov: 0.0,
+            focal_distance: 1.0,
+        };
+
+        scene.objects[0].surface = scene::Surface::Sphere { radius: -1.0 };
+        scene.objects[1].transform.position.y = f32::NAN;
+        scene.objects[2].surface = scene::Surface::MeshData {
+            vertices: vec![glam::Vec3::ZERO, glam::Vec3::X, glam::Vec3::Y],
+            indices: vec![glam::uvec3(0, 1, 2), glam::uvec3(0, 1, 3)],
+            uvs: vec![glam::Vec2::ZERO],
+            normals: Vec::new(),
+            tangents: Vec::new(),
+            colors: Vec::new(),
+        };
+        scene.objects[2].transform.scale = glam::vec3(1.0, 0.0, 1.0);
+        scene.objects[3].material = scene::MaterialRef::Named("missing".to_string());
+
+        let mut light = scene::Light {
+            kind: scene::LightKind::Spot {
+                inner_cone_angle: 30.0,
+                outer_cone_angle: 20.0,
+            },
+            transform: scene.objects[0].transform.clone(),
+            color: glam::Vec3::ONE,
+            intensity: 1.0,
+            range: None,
+        };
+        scene.lights.push(light.clone());
+        light.intensity = -1.0;
+        scene.lights.push(light);
+
+        let problems = validate(&scene);
+        assert_eq!(
+            problems[0].to_string(),
+            "Error: camera.lens.fov: 0° is outside 0° to 180°, exclusive"
+        );
+
+        let locations: Vec<(Severity, &str)> = problems
+            .iter()
+            .map(|problem| (problem.severity, problem.location.as_str()))
+            .collect();
+
+        use Severity::*;
+        assert_eq!(
+            locations,
+            [
+                (Error, "camera.lens.fov"),
+                (Error, "objects[0].surface.radius"),
+                (Error, "objects[1].transform.position"),
+                (Error, "objects[2].transform.scale"),
+                (Error, "objects[2].surface.indices[1]"),
+                (Error, "objects[2].surface.uvs"),
+                (Error, "objects[3].material"),
+                (Warning, "lights[0].kind"),
+                (Error, "lights[1].intensity"),
+                (Warning, "lights[1].kind"),
+            ]
+        );
+    }
+}